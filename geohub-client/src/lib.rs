@@ -0,0 +1,384 @@
+//! Blocking client for the GeoHub HTTP API.
+//!
+//! ```no_run
+//! let client = geohub_client::Client::new("https://example.com/geo", "alice", Some("abc"));
+//! for update in client.live(None, 30) {
+//!     println!("{:?}", update.unwrap().geo);
+//! }
+//! ```
+
+pub use geohub::types;
+
+use std::fmt;
+use std::time;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The server returned an error status, with the response body.
+    Status(u16, String),
+    /// The server couldn't wait for live updates, e.g. for lack of a database connection.
+    Live(String),
+    /// The server couldn't be reached.
+    Transport(String),
+    Io(std::io::Error),
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Status(code, body) => write!(f, "HTTP status {}: {}", code, body),
+            Error::Live(e) => write!(f, "live updates failed: {}", e),
+            Error::Transport(e) => write!(f, "transport error: {}", e),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Json(e) => write!(f, "JSON error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<ureq::Error> for Error {
+    fn from(e: ureq::Error) -> Error {
+        match e {
+            ureq::Error::Status(code, response) => {
+                Error::Status(code, response.into_string().unwrap_or_default())
+            }
+            e => Error::Transport(e.to_string()),
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Error {
+        Error::Json(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Selects points for `retrieve_json` and `retrieve_gpx`. All fields are optional.
+#[derive(Debug, Clone, Default)]
+pub struct Query {
+    pub from: Option<chrono::DateTime<chrono::Utc>>,
+    pub to: Option<chrono::DateTime<chrono::Utc>>,
+    pub limit: Option<i64>,
+    /// Only return points with an ID greater than this.
+    pub last: Option<i32>,
+}
+
+fn timestamp(t: chrono::DateTime<chrono::Utc>) -> String {
+    t.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+}
+
+/// A client of a GeoHub instance, acting as one GeoHub client (name and secret).
+#[derive(Clone)]
+pub struct Client {
+    /// Base URL, e.g. `https://example.com/geo`.
+    base: String,
+    client: String,
+    secret: Option<String>,
+    agent: ureq::Agent,
+}
+
+impl Client {
+    pub fn new(base: &str, client: &str, secret: Option<&str>) -> Client {
+        Client {
+            base: base.trim_end_matches('/').to_string(),
+            client: client.to_string(),
+            secret: secret.map(|s| s.to_string()),
+            agent: ureq::Agent::new(),
+        }
+    }
+
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        let req = self
+            .agent
+            .request(method, &format!("{}/{}/{}", self.base, self.client, path));
+        match self.secret {
+            Some(ref secret) => req.query("secret", secret),
+            None => req,
+        }
+    }
+
+    /// Log a single point.
+    pub fn log(&self, point: &types::GeoPoint) -> Result<()> {
+        let mut req = self
+            .request("POST", "log")
+            .query("lat", &point.lat.to_string())
+            .query("longitude", &point.long.to_string())
+            .query("time", &timestamp(point.time));
+        let optional = [
+            ("s", point.spd),
+            ("ele", point.ele),
+            ("accuracy", point.accuracy),
+        ];
+        for (name, value) in optional.iter() {
+            if let Some(value) = value {
+                req = req.query(name, &value.to_string());
+            }
+        }
+        req.send_string(point.note.as_ref().map(|n| n.as_str()).unwrap_or(""))?;
+        Ok(())
+    }
+
+    /// Log several points at once.
+    pub fn log_json(&self, points: &[types::GeoPoint]) -> Result<()> {
+        let body = types::LogLocations {
+            locations: points
+                .iter()
+                .cloned()
+                .map(types::geofeature_from_point)
+                .collect(),
+        };
+        self.request("POST", "logjson")
+            .set("Content-Type", "application/json")
+            .send_string(&serde_json::to_string(&body)?)?;
+        Ok(())
+    }
+
+    fn retrieve(&self, path: &str, query: &Query) -> Result<String> {
+        let mut req = self.request("GET", path);
+        if let Some(from) = query.from {
+            req = req.query("from", &timestamp(from));
+        }
+        if let Some(to) = query.to {
+            req = req.query("to", &timestamp(to));
+        }
+        if let Some(limit) = query.limit {
+            req = req.query("limit", &limit.to_string());
+        }
+        if let Some(last) = query.last {
+            req = req.query("last", &last.to_string());
+        }
+        Ok(req.call()?.into_string()?)
+    }
+
+    /// Fetch points as GeoJSON.
+    pub fn retrieve_json(&self, query: &Query) -> Result<types::GeoJSON> {
+        Ok(serde_json::from_str(
+            &self.retrieve("retrieve/json", query)?,
+        )?)
+    }
+
+    /// Fetch points as GPX document.
+    pub fn retrieve_gpx(&self, query: &Query) -> Result<String> {
+        self.retrieve("retrieve/gpx", query)
+    }
+
+    /// Fetch the most recent points (newest first), or those newer than `last`.
+    pub fn retrieve_last(
+        &self,
+        last: Option<i32>,
+        limit: Option<i64>,
+    ) -> Result<types::LiveUpdate> {
+        let query = Query {
+            last: last,
+            limit: limit,
+            ..Query::default()
+        };
+        Ok(serde_json::from_str(
+            &self.retrieve("retrieve/last", &query)?,
+        )?)
+    }
+
+    /// Wait up to `timeout` seconds for new points.
+    fn wait(&self, timeout: u64) -> Result<types::LiveUpdate> {
+        let response = self
+            .request("GET", "retrieve/live")
+            .query("timeout", &timeout.to_string())
+            .call()?;
+        Ok(serde_json::from_str(&response.into_string()?)?)
+    }
+
+    /// Iterate over live updates. Every update contains the points logged since the previous
+    /// one; timeouts are retried transparently. Start with `last` to also receive the points
+    /// logged after that ID.
+    pub fn live(&self, last: Option<i32>, timeout: u64) -> LiveUpdates {
+        LiveUpdates {
+            client: self.clone(),
+            last: last,
+            timeout: timeout,
+            backoff: None,
+        }
+    }
+}
+
+/// Iterator returned by `Client::live`. It only ends if the caller stops iterating; errors are
+/// returned as items. After an error, the next item is only requested after waiting (1 second,
+/// doubled on every further error up to 1 minute), so that iterating on doesn't flood the server.
+pub struct LiveUpdates {
+    client: Client,
+    last: Option<i32>,
+    timeout: u64,
+    /// Time to wait before the next request, after an error.
+    backoff: Option<time::Duration>,
+}
+
+impl LiveUpdates {
+    const MIN_BACKOFF: time::Duration = time::Duration::from_secs(1);
+    const MAX_BACKOFF: time::Duration = time::Duration::from_secs(60);
+
+    /// Wait for the next update with points.
+    fn poll(&mut self) -> Result<types::LiveUpdate> {
+        loop {
+            // Points logged while we weren't waiting are fetched explicitly, which also covers
+            // those that arrived while a live update was being delivered.
+            if let Some(last) = self.last {
+                let update = self.client.retrieve_last(Some(last), None)?;
+                if update.geo.is_some() {
+                    self.last = update.last.or(self.last);
+                    return Ok(update);
+                }
+            }
+
+            let update = self.client.wait(self.timeout)?;
+            match (update.geo.is_some(), update.last) {
+                (false, _) => match update.error {
+                    Some(e) if e != types::LIVE_TIMEOUT => return Err(Error::Live(e)),
+                    // Timeout.
+                    _ => continue,
+                },
+                // Let the next iteration fetch all points since the cursor.
+                (true, Some(_)) if self.last.is_some() => continue,
+                (true, last) => {
+                    self.last = last.or(self.last);
+                    return Ok(update);
+                }
+            }
+        }
+    }
+}
+
+impl Iterator for LiveUpdates {
+    type Item = Result<types::LiveUpdate>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(backoff) = self.backoff {
+            std::thread::sleep(backoff);
+        }
+        let result = self.poll();
+        self.backoff = match (&result, self.backoff) {
+            (Ok(_), _) => None,
+            (Err(_), None) => Some(Self::MIN_BACKOFF),
+            (Err(_), Some(backoff)) => Some(std::cmp::min(2 * backoff, Self::MAX_BACKOFF)),
+        };
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Write};
+    use std::sync::mpsc;
+
+    /// Answer one request per connection with the given updates. Returns the base URL and the
+    /// request lines received.
+    fn serve(updates: Vec<types::LiveUpdate>) -> (String, mpsc::Receiver<String>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}/geo", listener.local_addr().unwrap());
+        let (send, recv) = mpsc::channel();
+        std::thread::spawn(move || {
+            for update in updates {
+                let (mut conn, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 4096];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let n = conn.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let request = String::from_utf8_lossy(&request);
+                send.send(request.lines().next().unwrap().to_string()).ok();
+                let body = serde_json::to_string(&update).unwrap();
+                write!(
+                    conn,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        });
+        (base, recv)
+    }
+
+    fn update(last: Option<i32>, ids: &[i32], error: Option<&str>) -> types::LiveUpdate {
+        let geo = if ids.is_empty() {
+            None
+        } else {
+            let points = ids
+                .iter()
+                .map(|id| types::GeoPoint {
+                    id: Some(*id),
+                    lat: 52.5,
+                    long: 13.4,
+                    spd: None,
+                    ele: None,
+                    accuracy: None,
+                    time: chrono::Utc::now(),
+                    note: None,
+                })
+                .collect();
+            Some(types::geojson_from_points(points))
+        };
+        types::LiveUpdate::new("alice".into(), last, geo, error.map(String::from))
+    }
+
+    #[test]
+    fn test_live_updates() {
+        let (base, requests) = serve(vec![
+            update(None, &[], Some(types::LIVE_TIMEOUT)),
+            update(Some(5), &[5], None),
+            update(Some(7), &[6, 7], None),
+        ]);
+        let client = Client::new(&base, "alice", Some("abc"));
+        let mut live = client.live(None, 1);
+
+        // Timeouts are retried.
+        let first = live.next().unwrap().unwrap();
+        assert_eq!(first.last, Some(5));
+        assert_eq!(first.geo.unwrap().features.len(), 1);
+        assert!(requests
+            .recv()
+            .unwrap()
+            .contains("/geo/alice/retrieve/live?"));
+        assert!(requests
+            .recv()
+            .unwrap()
+            .contains("/geo/alice/retrieve/live?"));
+
+        // Points since the cursor are fetched first.
+        let second = live.next().unwrap().unwrap();
+        assert_eq!(second.last, Some(7));
+        assert_eq!(second.geo.unwrap().features.len(), 2);
+        let request = requests.recv().unwrap();
+        assert!(request.contains("/geo/alice/retrieve/last?"));
+        assert!(request.contains("last=5"));
+        assert_eq!(live.backoff, None);
+    }
+
+    #[test]
+    fn test_live_updates_error() {
+        let unavailable = "live updates unavailable (no database connection), try again later";
+        let (base, _requests) = serve(vec![
+            update(None, &[], Some(types::LIVE_TIMEOUT)),
+            update(None, &[], Some(unavailable)),
+        ]);
+        let client = Client::new(&base, "alice", None);
+        let mut live = client.live(None, 1);
+        match live.next() {
+            Some(Err(Error::Live(e))) => assert_eq!(e, unavailable),
+            r => panic!("expected an error, got {:?}", r),
+        }
+        // The next request waits.
+        assert_eq!(live.backoff, Some(LiveUpdates::MIN_BACKOFF));
+    }
+}
@@ -0,0 +1,24 @@
+--
+-- Optional PostGIS support for GeoHub.
+--
+-- Apply after pgsql_schema.sql to a database with the PostGIS extension
+-- (`CREATE EXTENSION postgis;`), then set `postgis = true` in Rocket.toml.
+-- This enables the `near` and `within` filters of retrieve/json and
+-- retrieve/gpx, and lets `bbox` use the spatial index.
+--
+
+--
+-- Name: geodata geog; Type: COLUMN; Schema: geohub; Owner: -
+--
+
+ALTER TABLE geohub.geodata
+    ADD COLUMN geog public.geography(Point,4326)
+    GENERATED ALWAYS AS (public.st_setsrid(public.st_makepoint(long, lat), 4326)::public.geography) STORED;
+
+
+--
+-- Name: geodata_geog_idx; Type: INDEX; Schema: geohub; Owner: -
+--
+
+CREATE INDEX geodata_geog_idx ON geohub.geodata USING gist (geog);
+
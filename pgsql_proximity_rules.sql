@@ -0,0 +1,77 @@
+--
+-- Upgrade for GeoHub databases set up before proximity rules were introduced.
+--
+-- Apply once to a database created from an older pgsql_schema.sql. Without
+-- the table, logging points, replays and `geohub-admin rehash` fail. The
+-- statements are the same as in pgsql_schema.sql, and do nothing if the table
+-- exists already.
+--
+
+--
+-- Name: proximity_rules; Type: TABLE; Schema: geohub; Owner: -
+--
+
+CREATE TABLE IF NOT EXISTS geohub.proximity_rules (
+    id integer NOT NULL,
+    client text NOT NULL,
+    secret bytea,
+    other text NOT NULL,
+    other_secret bytea,
+    radius double precision NOT NULL,
+    inside boolean DEFAULT false NOT NULL
+);
+
+
+--
+-- Name: proximity_rules_id_seq; Type: SEQUENCE; Schema: geohub; Owner: -
+--
+
+CREATE SEQUENCE IF NOT EXISTS geohub.proximity_rules_id_seq
+    AS integer
+    START WITH 1
+    INCREMENT BY 1
+    NO MINVALUE
+    NO MAXVALUE
+    CACHE 1;
+
+
+--
+-- Name: proximity_rules_id_seq; Type: SEQUENCE OWNED BY; Schema: geohub; Owner: -
+--
+
+ALTER SEQUENCE geohub.proximity_rules_id_seq OWNED BY geohub.proximity_rules.id;
+
+
+--
+-- Name: proximity_rules id; Type: DEFAULT; Schema: geohub; Owner: -
+--
+
+ALTER TABLE ONLY geohub.proximity_rules ALTER COLUMN id SET DEFAULT nextval('geohub.proximity_rules_id_seq'::regclass);
+
+
+--
+-- Name: proximity_rules proximity_rules_pkey; Type: CONSTRAINT; Schema: geohub; Owner: -
+--
+
+DO $$
+BEGIN
+    ALTER TABLE ONLY geohub.proximity_rules
+        ADD CONSTRAINT proximity_rules_pkey PRIMARY KEY (id);
+EXCEPTION
+    WHEN invalid_table_definition THEN NULL;
+END
+$$;
+
+
+--
+-- Name: proximity_rules_client_idx; Type: INDEX; Schema: geohub; Owner: -
+--
+
+CREATE INDEX IF NOT EXISTS proximity_rules_client_idx ON geohub.proximity_rules USING btree (client);
+
+
+--
+-- Name: proximity_rules_other_idx; Type: INDEX; Schema: geohub; Owner: -
+--
+
+CREATE INDEX IF NOT EXISTS proximity_rules_other_idx ON geohub.proximity_rules USING btree (other);
@@ -43,10 +43,46 @@ CREATE TABLE geohub.geodata (
     secret bytea,
     id integer NOT NULL,
     accuracy double precision,
-    note text
+    note text,
+    point_key text
 );
 
 
+--
+-- Name: proximity_rules; Type: TABLE; Schema: geohub; Owner: -
+--
+
+CREATE TABLE geohub.proximity_rules (
+    id integer NOT NULL,
+    client text NOT NULL,
+    secret bytea,
+    other text NOT NULL,
+    other_secret bytea,
+    radius double precision NOT NULL,
+    inside boolean DEFAULT false NOT NULL
+);
+
+
+--
+-- Name: proximity_rules_id_seq; Type: SEQUENCE; Schema: geohub; Owner: -
+--
+
+CREATE SEQUENCE geohub.proximity_rules_id_seq
+    AS integer
+    START WITH 1
+    INCREMENT BY 1
+    NO MINVALUE
+    NO MAXVALUE
+    CACHE 1;
+
+
+--
+-- Name: proximity_rules_id_seq; Type: SEQUENCE OWNED BY; Schema: geohub; Owner: -
+--
+
+ALTER SEQUENCE geohub.proximity_rules_id_seq OWNED BY geohub.proximity_rules.id;
+
+
 --
 -- Name: geodata_id_seq; Type: SEQUENCE; Schema: geohub; Owner: -
 --
@@ -74,6 +110,13 @@ ALTER SEQUENCE geohub.geodata_id_seq OWNED BY geohub.geodata.id;
 ALTER TABLE ONLY geohub.geodata ALTER COLUMN id SET DEFAULT nextval('geohub.geodata_id_seq'::regclass);
 
 
+--
+-- Name: proximity_rules id; Type: DEFAULT; Schema: geohub; Owner: -
+--
+
+ALTER TABLE ONLY geohub.proximity_rules ALTER COLUMN id SET DEFAULT nextval('geohub.proximity_rules_id_seq'::regclass);
+
+
 --
 -- Name: geodata geodata_pkey; Type: CONSTRAINT; Schema: geohub; Owner: -
 --
@@ -82,6 +125,14 @@ ALTER TABLE ONLY geohub.geodata
     ADD CONSTRAINT geodata_pkey PRIMARY KEY (id);
 
 
+--
+-- Name: proximity_rules proximity_rules_pkey; Type: CONSTRAINT; Schema: geohub; Owner: -
+--
+
+ALTER TABLE ONLY geohub.proximity_rules
+    ADD CONSTRAINT proximity_rules_pkey PRIMARY KEY (id);
+
+
 --
 -- Name: geodata_client_secret_idx; Type: INDEX; Schema: geohub; Owner: -
 --
@@ -89,6 +140,13 @@ ALTER TABLE ONLY geohub.geodata
 CREATE INDEX geodata_client_secret_idx ON geohub.geodata USING btree (client, secret);
 
 
+--
+-- Name: geodata_client_secret_point_key_idx; Type: INDEX; Schema: geohub; Owner: -
+--
+
+CREATE UNIQUE INDEX geodata_client_secret_point_key_idx ON geohub.geodata USING btree (client, COALESCE(secret, ''::bytea), point_key) WHERE (point_key IS NOT NULL);
+
+
 --
 -- Name: geodata_t_idx; Type: INDEX; Schema: geohub; Owner: -
 --
@@ -96,6 +154,20 @@ CREATE INDEX geodata_client_secret_idx ON geohub.geodata USING btree (client, se
 CREATE INDEX geodata_t_idx ON geohub.geodata USING btree (t);
 
 
+--
+-- Name: proximity_rules_client_idx; Type: INDEX; Schema: geohub; Owner: -
+--
+
+CREATE INDEX proximity_rules_client_idx ON geohub.proximity_rules USING btree (client);
+
+
+--
+-- Name: proximity_rules_other_idx; Type: INDEX; Schema: geohub; Owner: -
+--
+
+CREATE INDEX proximity_rules_other_idx ON geohub.proximity_rules USING btree (other);
+
+
 --
 -- PostgreSQL database dump complete
 --
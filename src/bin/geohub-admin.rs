@@ -0,0 +1,491 @@
+//! Administration of a GeoHub database. Uses the database and secret key configured in
+//! Rocket.toml (for the environment selected by ROCKET_ENV), unless `--database` and
+//! `--secret-key` are given.
+
+use geohub::{db, notifier, types};
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+
+use std::io::Write;
+
+/// Database URL and secret hasher, from the command line or Rocket.toml.
+fn settings(matches: &ArgMatches) -> Result<(String, db::SecretHasher), String> {
+    let rocket_config = || {
+        rocket::config::RocketConfig::read()
+            .map(|c| c.active().clone())
+            .map_err(|e| format!("Couldn't read Rocket.toml: {}", e))
+    };
+    let url = match matches.value_of("database") {
+        Some(url) => url.to_string(),
+        None => rocket_contrib::databases::database_config("geohub", &rocket_config()?)
+            .map(|c| c.url.to_string())
+            .map_err(|e| format!("No geohub database in Rocket.toml: {:?}", e))?,
+    };
+    let key = match matches.value_of("secret-key") {
+        Some(key) => Some(key.to_string()),
+        None => rocket_config()?
+            .get_str("secret_key")
+            .ok()
+            .map(|k| k.to_string()),
+    };
+    let key = key.filter(|k| !k.is_empty());
+    Ok((
+        url,
+        db::SecretHasher::new(key.as_ref().map(|k| k.as_bytes())),
+    ))
+}
+
+fn parse_time(s: &str) -> Result<chrono::DateTime<chrono::Utc>, String> {
+    if let Ok(t) = chrono::DateTime::parse_from_rfc3339(s) {
+        return Ok(t.with_timezone(&chrono::Utc));
+    }
+    for fs in &["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f"] {
+        if let Ok(t) = chrono::NaiveDateTime::parse_from_str(s, fs) {
+            return Ok(chrono::DateTime::from_utc(t, chrono::Utc));
+        }
+    }
+    if let Ok(d) = chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return Ok(chrono::DateTime::from_utc(d.and_hms(0, 0, 0), chrono::Utc));
+    }
+    Err(format!("Invalid timestamp: {}", s))
+}
+
+/// The `--from` and `--to` arguments, defaulting to everything.
+fn time_range(
+    matches: &ArgMatches,
+) -> Result<(chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>), String> {
+    let from = match matches.value_of("from") {
+        Some(s) => parse_time(s)?,
+        None => {
+            chrono::DateTime::from_utc(chrono::NaiveDateTime::from_timestamp(0, 0), chrono::Utc)
+        }
+    };
+    let to = match matches.value_of("to") {
+        Some(s) => parse_time(s)?,
+        None => chrono::Utc::now() + chrono::Duration::days(365 * 100),
+    };
+    Ok((from, to))
+}
+
+fn secret(matches: &ArgMatches) -> Option<String> {
+    matches
+        .value_of("secret")
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_string())
+}
+
+fn fmt_time(t: Option<chrono::DateTime<chrono::Utc>>) -> String {
+    t.map(|t| t.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+        .unwrap_or("-".into())
+}
+
+fn list_clients(db: &db::DBQuery) -> Result<(), String> {
+    let clients = db.clients().map_err(|e| e.to_string())?;
+    println!(
+        "{:<24} {:>10} {:>9} {:<21} {:<21}",
+        "client", "points", "sessions", "first", "last"
+    );
+    for c in clients {
+        println!(
+            "{:<24} {:>10} {:>9} {:<21} {:<21}",
+            c.client,
+            c.points,
+            c.sessions,
+            fmt_time(c.first),
+            fmt_time(c.last)
+        );
+    }
+    Ok(())
+}
+
+fn list_sessions(db: &db::DBQuery, client: &str) -> Result<(), String> {
+    let sessions = db.sessions(client).map_err(|e| e.to_string())?;
+    println!(
+        "{:<18} {:>10} {:<21} {:<21}",
+        "secret hash", "points", "first", "last"
+    );
+    for s in sessions {
+        // Secrets are only stored hashed; the hash prefix tells sessions apart.
+        let hash = match s.secret {
+            Some(h) => h.iter().take(8).map(|b| format!("{:02x}", b)).collect(),
+            None => "(public)".to_string(),
+        };
+        println!(
+            "{:<18} {:>10} {:<21} {:<21}",
+            hash,
+            s.points,
+            fmt_time(s.first),
+            fmt_time(s.last)
+        );
+    }
+    Ok(())
+}
+
+fn export(db: &db::DBQuery, matches: &ArgMatches) -> Result<(), String> {
+    let client = matches.value_of("client").unwrap();
+    let (from, to) = time_range(matches)?;
+    let points = db
+        .retrieve(
+            client,
+            from,
+            to,
+            &secret(matches),
+            std::i64::MAX,
+            None,
+            &None,
+            false,
+        )
+        .map_err(|e| e.to_string())?;
+    let mut out: Box<dyn Write> = match matches.value_of("output") {
+        Some(path) => Box::new(std::fs::File::create(path).map_err(|e| e.to_string())?),
+        None => Box::new(std::io::stdout()),
+    };
+    let n = points.len();
+    match matches.value_of("format").unwrap() {
+        "gpx" => gpx::write(&types::gpx_track_from_points(points), &mut out)
+            .map_err(|e| e.to_string())?,
+        _ => serde_json::to_writer(&mut out, &types::geojson_from_points(points))
+            .map_err(|e| e.to_string())?,
+    }
+    out.flush().map_err(|e| e.to_string())?;
+    eprintln!("Exported {} points", n);
+    Ok(())
+}
+
+/// Read points from a GPX or GeoJSON file (a FeatureCollection, or a `logjson` body).
+fn read_points(path: &str) -> Result<Vec<types::GeoPoint>, String> {
+    let data = std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    if path.ends_with(".gpx") {
+        let gx = gpx::read(&data[..]).map_err(|e| format!("{}: {}", path, e))?;
+        let mut points = vec![];
+        let waypoints = gx
+            .tracks
+            .iter()
+            .flat_map(|t| t.segments.iter())
+            .flat_map(|s| s.points.iter())
+            .chain(gx.routes.iter().flat_map(|r| r.points.iter()))
+            .chain(gx.waypoints.iter());
+        let mut skipped = 0;
+        for wp in waypoints {
+            let time = match wp.time {
+                Some(time) => time,
+                None => {
+                    skipped += 1;
+                    continue;
+                }
+            };
+            let p = wp.point();
+            points.push(types::GeoPoint {
+                id: None,
+                lat: p.y(),
+                long: p.x(),
+                spd: wp.speed.map(|s| s * 3.6),
+                ele: wp.elevation,
+                accuracy: wp.hdop,
+                time: time,
+                note: wp.comment.clone(),
+            });
+        }
+        if skipped > 0 {
+            eprintln!("{}: skipped {} points without time", path, skipped);
+        }
+        return Ok(points);
+    }
+    let features = match serde_json::from_slice::<types::GeoJSON>(&data) {
+        Ok(gj) => gj.features,
+        Err(_) => {
+            serde_json::from_slice::<types::LogLocations>(&data)
+                .map_err(|e| format!("{}: neither GeoJSON nor logjson body: {}", path, e))?
+                .locations
+        }
+    };
+    Ok(features
+        .into_iter()
+        .map(types::geopoint_from_feature)
+        .collect())
+}
+
+fn import(db: &db::DBQuery, matches: &ArgMatches) -> Result<(), String> {
+    let client = matches.value_of("client").unwrap();
+    let secret = secret(matches);
+    let mut points = vec![];
+    for path in matches.values_of("files").unwrap() {
+        points.extend(read_points(path)?);
+    }
+    // All files or nothing.
+    let trans = db.0.transaction().map_err(|e| e.to_string())?;
+    for p in points.iter() {
+        db.log_geopoint(client, &secret, p, &None, false)
+            .map_err(|e| e.to_string())?;
+    }
+    trans.commit().map_err(|e| e.to_string())?;
+    eprintln!("Imported {} points into {}", points.len(), client);
+    Ok(())
+}
+
+fn delete(db: &db::DBQuery, matches: &ArgMatches) -> Result<(), String> {
+    let client = matches.value_of("client").unwrap();
+    let secret = secret(matches);
+    let (from, to) = time_range(matches)?;
+    if !matches.is_present("yes") {
+        let n = db
+            .count_points(client, &secret, from, to)
+            .map_err(|e| e.to_string())?;
+        println!(
+            "Would delete {} points. Repeat with --yes to delete them.",
+            n
+        );
+        return Ok(());
+    }
+    let n = db
+        .delete_points(client, &secret, from, to)
+        .map_err(|e| e.to_string())?;
+    println!("Deleted {} points", n);
+    Ok(())
+}
+
+/// Move a whole client, or with `--secret` one of its sessions, to another client. Like the
+/// `rename` endpoint, live waiters of the target receive the latest moved points.
+fn move_client(db: &db::DBQuery, matches: &ArgMatches, merge: bool) -> Result<(), String> {
+    let from = matches.value_of("from").unwrap();
+    let to = matches.value_of("to").unwrap();
+    // With --secret, only that session is moved (--secret "" selects the public points).
+    let session = matches.value_of("secret").map(|_| secret(matches));
+    let to_secret = match matches.value_of("to-secret") {
+        Some(s) => Some(s.to_string()).filter(|s| !s.is_empty()),
+        None => session.clone().unwrap_or(None),
+    };
+
+    let trans = db.0.transaction().map_err(|e| e.to_string())?;
+    let exists = match session {
+        Some(_) => db.session_exists(to, &to_secret),
+        None => db.client_exists(to),
+    }
+    .map_err(|e| e.to_string())?;
+    if exists && !merge {
+        return Err(format!("{} already has points; use merge", to));
+    }
+    // Point keys are unique per session.
+    db.lock_client(to).map_err(|e| e.to_string())?;
+    let conflicts = db
+        .count_key_conflicts(from, session.as_ref().map(|s| (s, &to_secret)), to)
+        .map_err(|e| e.to_string())?;
+    if conflicts > 0 {
+        return Err(format!(
+            "{} points have a key that the same session of {} uses already; delete them from either client first",
+            conflicts, to
+        ));
+    }
+    let (n, latest) = match session {
+        Some(ref secret) => {
+            db.move_session(from, secret, to, &to_secret, notifier::MAX_NOTIFY_ROWS)
+        }
+        None => db.move_client(from, to, notifier::MAX_NOTIFY_ROWS),
+    }
+    .map_err(|e| e.to_string())?;
+    let notify_secret = if session.is_some() { to_secret } else { None };
+    notifier::notify_points(db, to, &notify_secret, &latest).map_err(|e| e.to_string())?;
+    trans.commit().map_err(|e| e.to_string())?;
+    println!("Moved {} points from {} to {}", n, from, to);
+    Ok(())
+}
+
+/// Change the secret of a session, keeping its points and proximity rules. Without a client,
+/// replace all legacy secret hashes with keyed ones.
+fn rehash(db: &db::DBQuery, matches: &ArgMatches) -> Result<(), String> {
+    if let Some(client) = matches.value_of("client") {
+        let new_secret = matches
+            .value_of("new-secret")
+            .filter(|s| !s.is_empty())
+            .map(|s| s.to_string());
+        let trans = db.0.transaction().map_err(|e| e.to_string())?;
+        let n = db
+            .rehash_session(client, &secret(matches), &new_secret)
+            .map_err(|e| e.to_string())?;
+        trans.commit().map_err(|e| e.to_string())?;
+        println!("Rehashed {} rows of {}", n, client);
+        return Ok(());
+    }
+    if !db.1.has_key() {
+        return Err("No secret key: set secret_key in Rocket.toml, or use --secret-key".into());
+    }
+    let trans = db.0.transaction().map_err(|e| e.to_string())?;
+    let n = db.rehash_legacy().map_err(|e| e.to_string())?;
+    trans.commit().map_err(|e| e.to_string())?;
+    println!("Rehashed secrets of {} rows", n);
+    Ok(())
+}
+
+fn statistics(db: &db::DBQuery) -> Result<(), String> {
+    let s = db.statistics().map_err(|e| e.to_string())?;
+    println!("points:          {}", s.points);
+    println!("points last 24h: {}", s.points_last_day);
+    println!("clients:         {}", s.clients);
+    println!("sessions:        {}", s.sessions);
+    println!("first point:     {}", fmt_time(s.first));
+    println!("last point:      {}", fmt_time(s.last));
+    println!(
+        "table size:      {:.1} MB",
+        s.table_size as f64 / (1 << 20) as f64
+    );
+    Ok(())
+}
+
+fn main() {
+    let client = || Arg::with_name("client").required(true).help("Client name");
+    let secret = || {
+        Arg::with_name("secret")
+            .long("secret")
+            .takes_value(true)
+            .help("Secret of the session. Without it, only public points are affected")
+    };
+    let range = || {
+        vec![
+            Arg::with_name("from")
+                .long("from")
+                .takes_value(true)
+                .help("Start of the time range"),
+            Arg::with_name("to")
+                .long("to")
+                .takes_value(true)
+                .help("End of the time range"),
+        ]
+    };
+    let session = || {
+        vec![
+            Arg::with_name("secret")
+                .long("secret")
+                .takes_value(true)
+                .help("Only move the session with this secret (\"\" for public points)"),
+            Arg::with_name("to-secret")
+                .long("to-secret")
+                .takes_value(true)
+                .requires("secret")
+                .help("Secret of the target session; by default the secret is kept"),
+        ]
+    };
+    let matches = App::new("geohub-admin")
+        .about("Administer a GeoHub database")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("database")
+                .long("database")
+                .takes_value(true)
+                .global(true)
+                .help("Database URL. Defaults to the geohub database in Rocket.toml"),
+        )
+        .arg(
+            Arg::with_name("secret-key")
+                .long("secret-key")
+                .takes_value(true)
+                .global(true)
+                .help("Key for hashing secrets. Defaults to secret_key in Rocket.toml"),
+        )
+        .subcommand(SubCommand::with_name("clients").about("List clients"))
+        .subcommand(
+            SubCommand::with_name("sessions")
+                .about("List the sessions (secrets) of a client")
+                .arg(client()),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Export points of a session")
+                .arg(client())
+                .arg(secret())
+                .args(&range())
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&["gpx", "geojson"])
+                        .default_value("geojson"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .takes_value(true)
+                        .help("Output file; default is stdout"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Import GPX (*.gpx) or GeoJSON files into a session")
+                .arg(client())
+                .arg(secret())
+                .arg(Arg::with_name("files").required(true).multiple(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("delete")
+                .about("Delete the points of a session in a time range")
+                .arg(client())
+                .arg(secret())
+                .args(&range())
+                .arg(
+                    Arg::with_name("yes")
+                        .long("yes")
+                        .help("Really delete; otherwise only count"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rename")
+                .about("Rename a client or session. Fails if the target already has points")
+                .arg(Arg::with_name("from").required(true))
+                .arg(Arg::with_name("to").required(true))
+                .args(&session()),
+        )
+        .subcommand(
+            SubCommand::with_name("merge")
+                .about("Move all points of a client or session to another, existing one")
+                .arg(Arg::with_name("from").required(true))
+                .arg(Arg::with_name("to").required(true))
+                .args(&session()),
+        )
+        .subcommand(
+            SubCommand::with_name("rehash")
+                .about(
+                    "Change the secret of a session, rehashing its points and proximity rules. \
+                     Without a client, replace unkeyed secret hashes with keyed ones (requires a \
+                     secret key)",
+                )
+                .arg(
+                    Arg::with_name("client")
+                        .requires("new-secret")
+                        .help("Client name"),
+                )
+                .arg(secret())
+                .arg(
+                    Arg::with_name("new-secret")
+                        .long("new-secret")
+                        .takes_value(true)
+                        .requires("client")
+                        .help("The new secret. An empty one makes the points public"),
+                ),
+        )
+        .subcommand(SubCommand::with_name("stats").about("Print statistics"))
+        .get_matches();
+
+    let (cmd, sub) = matches.subcommand();
+    let sub = sub.unwrap();
+    let result = settings(sub).and_then(|(url, secrets)| {
+        let conn = postgres::Connection::connect(url, postgres::TlsMode::None)
+            .map_err(|e| e.to_string())?;
+        let db = db::DBQuery(&conn, &secrets);
+        match cmd {
+            "clients" => list_clients(&db),
+            "sessions" => list_sessions(&db, sub.value_of("client").unwrap()),
+            "export" => export(&db, sub),
+            "import" => import(&db, sub),
+            "delete" => delete(&db, sub),
+            "rename" => move_client(&db, sub, false),
+            "merge" => move_client(&db, sub, true),
+            "rehash" => rehash(&db, sub),
+            "stats" => statistics(&db),
+            _ => unreachable!(),
+        }
+    });
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
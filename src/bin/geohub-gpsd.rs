@@ -0,0 +1,551 @@
+//! Forward positions from gpsd to GeoHub. Points are buffered on disk until the server has
+//! accepted them, so that nothing is lost while the network is down.
+
+use geohub::types;
+
+use clap::{value_t, App, Arg};
+
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::sync::mpsc;
+use std::time;
+
+/// The parts of a gpsd TPV report that we use. See gpsd_json(5).
+#[derive(serde::Deserialize, Debug)]
+struct Tpv {
+    class: String,
+    /// 2: 2D fix, 3: 3D fix.
+    mode: Option<i32>,
+    time: Option<String>,
+    lat: Option<f64>,
+    lon: Option<f64>,
+    /// Altitude in meters; newer gpsd versions report `altMSL` and `altHAE` instead.
+    alt: Option<f64>,
+    #[serde(rename = "altMSL")]
+    alt_msl: Option<f64>,
+    /// Speed over ground in m/s.
+    speed: Option<f64>,
+    /// Estimated longitude and latitude error in meters.
+    epx: Option<f64>,
+    epy: Option<f64>,
+}
+
+/// Convert a line received from gpsd into a point, if it is a TPV report with a fix.
+fn point_from_report(line: &str) -> Option<types::GeoPoint> {
+    let tpv = serde_json::from_str::<Tpv>(line).ok()?;
+    if tpv.class != "TPV" || tpv.mode.unwrap_or(0) < 2 {
+        return None;
+    }
+    let time = chrono::DateTime::parse_from_rfc3339(tpv.time.as_ref()?).ok()?;
+    let accuracy = match (tpv.epx, tpv.epy) {
+        (Some(x), Some(y)) => Some((x * x + y * y).sqrt()),
+        _ => None,
+    };
+    Some(types::GeoPoint {
+        id: None,
+        lat: tpv.lat?,
+        long: tpv.lon?,
+        spd: tpv.speed.map(|s| s * 3.6),
+        ele: tpv.alt_msl.or(tpv.alt),
+        accuracy: accuracy,
+        time: time.with_timezone(&chrono::Utc),
+        note: None,
+    })
+}
+
+/// Read gpsd reports from `reader` until it is closed, and send points to `points`. Points less
+/// than `interval` apart are skipped.
+fn read_reports<R: BufRead>(
+    reader: R,
+    interval: chrono::Duration,
+    last: &mut Option<chrono::DateTime<chrono::Utc>>,
+    points: &mpsc::Sender<types::GeoPoint>,
+) -> std::io::Result<()> {
+    for line in reader.lines() {
+        let point = match point_from_report(&line?) {
+            Some(point) => point,
+            None => continue,
+        };
+        // gpsd repeats reports, and may send more than we want.
+        if let Some(last) = *last {
+            if point.time <= last || point.time - last < interval {
+                continue;
+            }
+        }
+        *last = Some(point.time);
+        if points.send(point).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Connect to gpsd at `addr` and forward points, reconnecting whenever the connection is lost.
+fn gpsd_thread(addr: String, interval: chrono::Duration, points: mpsc::Sender<types::GeoPoint>) {
+    const WATCH: &[u8] = b"?WATCH={\"enable\":true,\"json\":true};\n";
+    let mut last = None;
+    loop {
+        let result = TcpStream::connect(&addr).and_then(|mut conn| {
+            conn.write_all(WATCH)?;
+            eprintln!("Connected to gpsd at {}", addr);
+            read_reports(BufReader::new(conn), interval, &mut last, &points)
+        });
+        if let Err(e) = result {
+            eprintln!("gpsd connection to {}: {}", addr, e);
+        }
+        std::thread::sleep(time::Duration::from_secs(5));
+    }
+}
+
+/// Points not yet accepted by the server, mirrored to a file with one GeoJSON feature per line.
+struct Buffer {
+    path: std::path::PathBuf,
+    points: VecDeque<types::GeoFeature>,
+}
+
+impl Buffer {
+    /// Open the buffer at `path`, with the points left over from the last run.
+    fn open(path: std::path::PathBuf) -> std::io::Result<Buffer> {
+        let mut points = VecDeque::new();
+        match std::fs::File::open(&path) {
+            Ok(f) => {
+                for line in BufReader::new(f).lines() {
+                    match serde_json::from_str(&line?) {
+                        Ok(feature) => points.push_back(feature),
+                        // Probably an incomplete line written during a crash.
+                        Err(e) => eprintln!("Skipping invalid point in buffer: {}", e),
+                    }
+                }
+            }
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        Ok(Buffer {
+            path: path,
+            points: points,
+        })
+    }
+
+    fn push(&mut self, feature: types::GeoFeature) -> std::io::Result<()> {
+        let mut f = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(f, "{}", serde_json::to_string(&feature).unwrap())?;
+        f.sync_data()?;
+        self.points.push_back(feature);
+        Ok(())
+    }
+
+    /// Remove the first `n` points. The file is replaced atomically.
+    fn remove(&mut self, n: usize) -> std::io::Result<()> {
+        self.points.drain(..n);
+        let tmp = self.path.with_extension("tmp");
+        {
+            let mut f = std::fs::File::create(&tmp)?;
+            for feature in self.points.iter() {
+                writeln!(f, "{}", serde_json::to_string(feature).unwrap())?;
+            }
+            f.sync_data()?;
+        }
+        std::fs::rename(&tmp, &self.path)
+    }
+}
+
+/// Longest wait requested by the server that is honoured, in seconds.
+const MAX_RETRY_AFTER: u64 = 3600;
+
+/// Why buffered points weren't sent.
+#[derive(Debug)]
+enum FlushError {
+    /// The server rejected the batch as invalid or too large (400, 413). Smaller batches may be
+    /// accepted; a single rejected point never will be.
+    Rejected(u16, String),
+    /// Try again later: after the given time if the server asked for it (429 with
+    /// `Retry-After`), otherwise after backing off.
+    Retry(Option<time::Duration>, String),
+}
+
+/// Send up to `batch` of the buffered points. Returns the number of points sent.
+fn flush(
+    agent: &ureq::Agent,
+    url: &str,
+    secret: &str,
+    buffer: &mut Buffer,
+    batch: usize,
+) -> Result<usize, FlushError> {
+    let n = std::cmp::min(batch, buffer.points.len());
+    if n == 0 {
+        return Ok(0);
+    }
+    let body = types::LogLocations {
+        locations: buffer.points.iter().take(n).cloned().collect(),
+    };
+    let result = agent
+        .post(url)
+        .query("secret", secret)
+        .set("Content-Type", "application/json")
+        .send_string(&serde_json::to_string(&body).unwrap());
+    match result {
+        Ok(_) => {}
+        Err(ureq::Error::Status(code, response)) if code == 400 || code == 413 => {
+            let message = response.into_string().unwrap_or_default();
+            return Err(FlushError::Rejected(code, message));
+        }
+        Err(ureq::Error::Status(429, response)) => {
+            // Only the delay in seconds is understood, not an HTTP date.
+            let wait = response
+                .header("Retry-After")
+                .and_then(|s| s.trim().parse::<u64>().ok())
+                .map(|s| time::Duration::from_secs(std::cmp::min(s, MAX_RETRY_AFTER)));
+            return Err(FlushError::Retry(wait, "rate limit exceeded".into()));
+        }
+        Err(e) => return Err(FlushError::Retry(None, e.to_string())),
+    }
+    buffer
+        .remove(n)
+        .map_err(|e| FlushError::Retry(None, e.to_string()))?;
+    Ok(n)
+}
+
+fn main() {
+    let matches = App::new("geohub-gpsd")
+        .about("Forward positions from gpsd to GeoHub")
+        .arg(
+            Arg::with_name("gpsd")
+                .long("gpsd")
+                .takes_value(true)
+                .default_value("localhost:2947")
+                .help("Address of gpsd"),
+        )
+        .arg(
+            Arg::with_name("server")
+                .long("server")
+                .takes_value(true)
+                .required(true)
+                .help("Base URL of the GeoHub instance, e.g. https://example.com/geo"),
+        )
+        .arg(
+            Arg::with_name("client")
+                .long("client")
+                .takes_value(true)
+                .default_value("gpsd")
+                .help("Client name"),
+        )
+        .arg(
+            Arg::with_name("secret")
+                .long("secret")
+                .takes_value(true)
+                .default_value("")
+                .help("Secret. By default, points are public on your GeoHub instance"),
+        )
+        .arg(
+            Arg::with_name("interval")
+                .long("interval")
+                .takes_value(true)
+                .default_value("5")
+                .help("Minimum time between points in seconds. 0 forwards every point"),
+        )
+        .arg(
+            Arg::with_name("buffer")
+                .long("buffer")
+                .takes_value(true)
+                .default_value("geohub-gpsd.buffer")
+                .help("File in which points are kept until the server has accepted them"),
+        )
+        .arg(
+            Arg::with_name("batch")
+                .long("batch")
+                .takes_value(true)
+                .default_value("100")
+                .help("Maximum number of points per request"),
+        )
+        .get_matches();
+
+    let client = matches.value_of("client").unwrap();
+    let secret = matches.value_of("secret").unwrap();
+    let interval = value_t!(matches, "interval", i64).unwrap_or_else(|e| e.exit());
+    let batch = value_t!(matches, "batch", usize).unwrap_or_else(|e| e.exit());
+    if batch == 0 {
+        clap::Error::value_validation_auto("--batch must be at least 1".into()).exit();
+    }
+    let url = format!(
+        "{}/{}/logjson",
+        matches.value_of("server").unwrap().trim_end_matches('/'),
+        client
+    );
+    let mut buffer = match Buffer::open(matches.value_of("buffer").unwrap().into()) {
+        Ok(buffer) => buffer,
+        Err(e) => {
+            eprintln!("Couldn't open buffer: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if !buffer.points.is_empty() {
+        eprintln!("{} points left in buffer", buffer.points.len());
+    }
+
+    let (send, recv) = mpsc::channel();
+    let gpsd = matches.value_of("gpsd").unwrap().to_string();
+    std::thread::spawn(move || gpsd_thread(gpsd, chrono::Duration::seconds(interval), send));
+
+    let agent = ureq::AgentBuilder::new()
+        .timeout(time::Duration::from_secs(30))
+        .build();
+    // Time to wait before retrying after the server couldn't be reached; doubled on every
+    // failure.
+    const MIN_BACKOFF: time::Duration = time::Duration::from_secs(2);
+    const MAX_BACKOFF: time::Duration = time::Duration::from_secs(300);
+    let mut backoff = MIN_BACKOFF;
+    let mut retry_at = time::Instant::now();
+
+    loop {
+        let wait = if buffer.points.is_empty() {
+            time::Duration::from_secs(3600)
+        } else {
+            retry_at.saturating_duration_since(time::Instant::now())
+        };
+        match recv.recv_timeout(wait) {
+            Ok(point) => {
+                if let Err(e) = buffer.push(types::geofeature_from_point(point)) {
+                    eprintln!("Couldn't write buffer: {}", e);
+                }
+                // Pick up everything that arrived in the meantime.
+                while let Ok(point) = recv.try_recv() {
+                    if let Err(e) = buffer.push(types::geofeature_from_point(point)) {
+                        eprintln!("Couldn't write buffer: {}", e);
+                    }
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+        if time::Instant::now() < retry_at {
+            continue;
+        }
+        // Batches rejected by the server are halved until they are accepted, or until the
+        // offending point is found and dropped.
+        let mut size = batch;
+        while !buffer.points.is_empty() {
+            size = std::cmp::min(size, buffer.points.len());
+            match flush(&agent, &url, secret, &mut buffer, size) {
+                Ok(n) => {
+                    eprintln!("Sent {} points, {} left", n, buffer.points.len());
+                    backoff = MIN_BACKOFF;
+                    size = batch;
+                }
+                Err(FlushError::Rejected(code, e)) if size > 1 => {
+                    eprintln!(
+                        "Server rejected {} points ({}), retrying in smaller batches: {}",
+                        size, code, e
+                    );
+                    size /= 2;
+                }
+                Err(FlushError::Rejected(code, e)) => {
+                    eprintln!(
+                        "Server rejected point ({}), dropping it: {}: {}",
+                        code,
+                        e,
+                        serde_json::to_string(&buffer.points[0]).unwrap()
+                    );
+                    if let Err(e) = buffer.remove(1) {
+                        eprintln!("Couldn't write buffer: {}", e);
+                    }
+                    size = batch;
+                }
+                Err(FlushError::Retry(wait, e)) => {
+                    let wait = wait.unwrap_or(backoff);
+                    eprintln!(
+                        "Couldn't send points ({} buffered), retrying in {:?}: {}",
+                        buffer.points.len(),
+                        wait,
+                        e
+                    );
+                    retry_at = time::Instant::now() + wait;
+                    backoff = std::cmp::min(2 * backoff, MAX_BACKOFF);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Read;
+
+    const SKY: &str = r#"{"class":"SKY","device":"/dev/ttyUSB0","satellites":[]}"#;
+
+    fn tpv(time: &str, mode: i32) -> String {
+        format!(
+            r#"{{"class":"TPV","mode":{},"time":"{}","lat":52.5,"lon":13.4,"alt":40.0,"altMSL":34.5,"speed":10.0,"epx":3.0,"epy":4.0}}"#,
+            mode, time
+        )
+    }
+
+    /// Features forwarded from the gpsd reports `lines`.
+    fn read(lines: &[String], interval: i64) -> Vec<serde_json::Value> {
+        let (send, recv) = mpsc::channel();
+        let input = std::io::Cursor::new(lines.join("\n"));
+        read_reports(input, chrono::Duration::seconds(interval), &mut None, &send).unwrap();
+        drop(send);
+        recv.iter()
+            .map(|p| serde_json::to_value(types::geofeature_from_point(p)).unwrap())
+            .collect()
+    }
+
+    fn feature(time: &str) -> types::GeoFeature {
+        let tpv = tpv(time, 3);
+        types::geofeature_from_point(point_from_report(&tpv).unwrap())
+    }
+
+    fn buffer(name: &str) -> Buffer {
+        let path = std::env::temp_dir().join(format!(
+            "geohub-gpsd-test-{}-{}.buffer",
+            name,
+            std::process::id()
+        ));
+        std::fs::remove_file(&path).ok();
+        Buffer::open(path).unwrap()
+    }
+
+    /// Answer requests with the given status lines and headers, one connection each. Returns
+    /// the URL to send them to.
+    fn serve(responses: Vec<&'static str>) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/geo/test/logjson", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for response in responses {
+                let (mut conn, _) = listener.accept().unwrap();
+                // Read the request, so that the client doesn't fail writing it.
+                let mut request = Vec::new();
+                let mut buf = [0; 4096];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let n = conn.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let head = String::from_utf8_lossy(&request).to_lowercase();
+                let length: usize = head
+                    .lines()
+                    .find_map(|l| l.strip_prefix("content-length:"))
+                    .map(|l| l.trim().parse().unwrap())
+                    .unwrap_or(0);
+                let body_start = request.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+                let mut missing = length - (request.len() - body_start);
+                while missing > 0 {
+                    missing -= conn.read(&mut buf[..std::cmp::min(missing, 4096)]).unwrap();
+                }
+                write!(
+                    conn,
+                    "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    response
+                )
+                .unwrap();
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn test_read_reports() {
+        let lines = vec![
+            SKY.to_string(),
+            tpv("2021-05-01T12:00:00.000Z", 3),
+            // Repeated report.
+            tpv("2021-05-01T12:00:00.000Z", 3),
+            tpv("2021-05-01T12:00:02.000Z", 3),
+            // No fix.
+            tpv("2021-05-01T12:00:05.000Z", 1),
+            tpv("2021-05-01T12:00:06.000Z", 2),
+            "garbage".to_string(),
+        ];
+        let features = read(&lines, 5);
+        assert_eq!(features.len(), 2);
+        let first = &features[0];
+        assert_eq!(first["type"], "Feature");
+        assert_eq!(first["geometry"]["type"], "Point");
+        assert_eq!(
+            first["geometry"]["coordinates"],
+            serde_json::json!([13.4, 52.5])
+        );
+        assert_eq!(first["properties"]["time"], "2021-05-01T12:00:00Z");
+        // altMSL is preferred over alt.
+        assert_eq!(first["properties"]["altitude"], 34.5);
+        assert!((first["properties"]["speed"].as_f64().unwrap() - 36.).abs() < 1e-9);
+        assert_eq!(first["properties"]["accuracy"], 5.);
+        assert_eq!(features[1]["properties"]["time"], "2021-05-01T12:00:06Z");
+
+        // Without an interval, only repeated reports are skipped.
+        assert_eq!(read(&lines, 0).len(), 3);
+    }
+
+    #[test]
+    fn test_buffer() {
+        let mut b = buffer("buffer");
+        for time in &[
+            "2021-05-01T12:00:00Z",
+            "2021-05-01T12:00:01Z",
+            "2021-05-01T12:00:02Z",
+        ] {
+            b.push(feature(time)).unwrap();
+        }
+        b.remove(2).unwrap();
+        assert_eq!(b.points.len(), 1);
+
+        // Points survive a restart, and incomplete lines are skipped.
+        writeln!(
+            std::fs::OpenOptions::new()
+                .append(true)
+                .open(&b.path)
+                .unwrap(),
+            "{{\"type\":\"Feat"
+        )
+        .unwrap();
+        let reopened = Buffer::open(b.path.clone()).unwrap();
+        assert_eq!(reopened.points.len(), 1);
+        let point = serde_json::to_value(&reopened.points[0]).unwrap();
+        assert_eq!(point["properties"]["time"], "2021-05-01T12:00:02Z");
+        std::fs::remove_file(&b.path).unwrap();
+    }
+
+    #[test]
+    fn test_flush() {
+        let url = serve(vec![
+            "429 Too Many Requests\r\nRetry-After: 7",
+            "413 Payload Too Large",
+            "500 Internal Server Error",
+            "200 OK",
+        ]);
+        let agent = ureq::AgentBuilder::new().build();
+        let mut b = buffer("flush");
+        for time in &[
+            "2021-05-01T12:00:00Z",
+            "2021-05-01T12:00:01Z",
+            "2021-05-01T12:00:02Z",
+        ] {
+            b.push(feature(time)).unwrap();
+        }
+
+        match flush(&agent, &url, "", &mut b, 2) {
+            Err(FlushError::Retry(Some(wait), _)) => assert_eq!(wait, time::Duration::from_secs(7)),
+            r => panic!("expected a retry after 7s, got {:?}", r),
+        }
+        match flush(&agent, &url, "", &mut b, 2) {
+            Err(FlushError::Rejected(413, _)) => {}
+            r => panic!("expected a rejection, got {:?}", r),
+        }
+        match flush(&agent, &url, "", &mut b, 2) {
+            Err(FlushError::Retry(None, _)) => {}
+            r => panic!("expected a retry, got {:?}", r),
+        }
+        // Nothing is removed from the buffer until the server accepted it.
+        assert_eq!(b.points.len(), 3);
+        assert_eq!(flush(&agent, &url, "", &mut b, 2).unwrap(), 2);
+        assert_eq!(b.points.len(), 1);
+        assert_eq!(Buffer::open(b.path.clone()).unwrap().points.len(), 1);
+        std::fs::remove_file(&b.path).unwrap();
+    }
+}
@@ -0,0 +1,473 @@
+//! Load generator: simulates clients moving along random walks or GPX routes, logging points to a
+//! GeoHub instance, while watchers wait for the updates on `retrieve/live`. Reports ingest
+//! latency and notification delay.
+
+use geohub::{geo, types};
+
+use clap::{value_t, App, Arg};
+use rand::Rng;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time;
+
+/// Measurements, in milliseconds.
+#[derive(Default)]
+struct Stats {
+    ingest: Mutex<Vec<f64>>,
+    delay: Mutex<Vec<f64>>,
+    points: AtomicUsize,
+    ingest_errors: AtomicUsize,
+    updates: AtomicUsize,
+    live_timeouts: AtomicUsize,
+    live_errors: AtomicUsize,
+}
+
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return std::f64::NAN;
+    }
+    let i = ((sorted.len() - 1) as f64 * p).round() as usize;
+    sorted[i]
+}
+
+fn report(name: &str, values: &Mutex<Vec<f64>>) {
+    let mut values = values.lock().unwrap().clone();
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    println!(
+        "{:<20} n = {:<8} p50 = {:>8.1} ms  p90 = {:>8.1} ms  p99 = {:>8.1} ms  max = {:>8.1} ms",
+        name,
+        values.len(),
+        percentile(&values, 0.5),
+        percentile(&values, 0.9),
+        percentile(&values, 0.99),
+        percentile(&values, 1.),
+    );
+}
+
+fn millis(d: chrono::Duration) -> f64 {
+    d.num_microseconds().unwrap_or(std::i64::MAX) as f64 / 1000.
+}
+
+/// How a simulated client moves.
+enum Movement {
+    /// Random walk with a speed in m/s; the heading changes a little at every step.
+    Walk {
+        lat: f64,
+        long: f64,
+        heading: f64,
+        speed: f64,
+    },
+    /// Follow a route at `speed` m/s, starting over at the end.
+    Route {
+        points: Arc<Vec<(f64, f64, Option<f64>)>>,
+        distance: f64,
+        speed: f64,
+    },
+}
+
+impl Movement {
+    /// Advance by `dt` seconds and return the new position.
+    fn advance(&mut self, dt: f64, now: chrono::DateTime<chrono::Utc>) -> types::GeoPoint {
+        let mut rng = rand::thread_rng();
+        let (lat, long, ele, spd) = match self {
+            Movement::Walk {
+                lat,
+                long,
+                heading,
+                speed,
+            } => {
+                *heading += rng.gen_range(-0.3..0.3);
+                let d = *speed * dt / geo::EARTH_RADIUS;
+                *lat = (*lat + (d * heading.cos()).to_degrees()).max(-85.).min(85.);
+                *long += (d * heading.sin() / lat.to_radians().cos()).to_degrees();
+                if *long > 180. {
+                    *long -= 360.;
+                } else if *long < -180. {
+                    *long += 360.;
+                }
+                (*lat, *long, None, *speed)
+            }
+            Movement::Route {
+                points,
+                distance,
+                speed,
+            } => {
+                *distance += *speed * dt;
+                let (lat, long, ele) = position_on_route(points, distance);
+                (lat, long, ele, *speed)
+            }
+        };
+        types::GeoPoint {
+            id: None,
+            lat: lat,
+            long: long,
+            spd: Some(spd * 3.6),
+            ele: ele,
+            accuracy: Some(rng.gen_range(3.0..15.0)),
+            time: now,
+            note: None,
+        }
+    }
+}
+
+/// Position after `distance` meters along `route`. `distance` wraps around at the end.
+fn position_on_route(
+    route: &[(f64, f64, Option<f64>)],
+    distance: &mut f64,
+) -> (f64, f64, Option<f64>) {
+    let total = route
+        .windows(2)
+        .map(|w| geo::distance(w[0].0, w[0].1, w[1].0, w[1].1))
+        .sum::<f64>();
+    if total <= 0. {
+        return route[0];
+    }
+    *distance %= total;
+    let mut left = *distance;
+    for w in route.windows(2) {
+        let d = geo::distance(w[0].0, w[0].1, w[1].0, w[1].1);
+        if left <= d && d > 0. {
+            let f = left / d;
+            let (lat, long) = geo::intermediate(w[0].0, w[0].1, w[1].0, w[1].1, f);
+            let ele = match (w[0].2, w[1].2) {
+                (Some(a), Some(b)) => Some(a + (b - a) * f),
+                _ => None,
+            };
+            return (lat, long, ele);
+        }
+        left -= d;
+    }
+    route[route.len() - 1]
+}
+
+fn read_route(path: &str) -> Result<Vec<(f64, f64, Option<f64>)>, String> {
+    let file = std::fs::File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+    let gx = gpx::read(std::io::BufReader::new(file)).map_err(|e| format!("{}: {}", path, e))?;
+    let mut points = vec![];
+    for track in gx.tracks.iter() {
+        for segment in track.segments.iter() {
+            for wp in segment.points.iter() {
+                let p = wp.point();
+                points.push((p.y(), p.x(), wp.elevation));
+            }
+        }
+    }
+    for wp in gx.routes.iter().flat_map(|r| r.points.iter()) {
+        let p = wp.point();
+        points.push((p.y(), p.x(), wp.elevation));
+    }
+    if points.is_empty() {
+        return Err(format!("{}: no track or route points", path));
+    }
+    Ok(points)
+}
+
+struct Options {
+    server: String,
+    prefix: String,
+    secret: String,
+    rate: f64,
+    batch: usize,
+    duration: time::Duration,
+    live_timeout: u64,
+}
+
+fn timestamp(t: chrono::DateTime<chrono::Utc>) -> String {
+    t.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+}
+
+/// Post points of one client, `batch` points at a time, until the end of the run.
+fn run_client(
+    i: usize,
+    mut movement: Movement,
+    opts: Arc<Options>,
+    stats: Arc<Stats>,
+    agent: ureq::Agent,
+) {
+    let client = format!("{}{}", opts.prefix, i);
+    let interval = 1. / opts.rate;
+    let start = time::Instant::now();
+    let mut next = start;
+    let mut pending = vec![];
+
+    while start.elapsed() < opts.duration {
+        let now = time::Instant::now();
+        if next > now {
+            std::thread::sleep(next - now);
+        }
+        next += time::Duration::from_secs_f64(interval);
+        pending.push(movement.advance(interval, chrono::Utc::now()));
+        if pending.len() < opts.batch {
+            continue;
+        }
+
+        let sent = chrono::Utc::now();
+        let result = if opts.batch == 1 {
+            let p = &pending[0];
+            let mut req = agent
+                .post(&format!("{}/{}/log", opts.server, client))
+                .query("secret", &opts.secret)
+                .query("lat", &p.lat.to_string())
+                .query("longitude", &p.long.to_string())
+                .query("time", &timestamp(p.time))
+                .query("s", &p.spd.unwrap_or(0.).to_string())
+                .query("accuracy", &p.accuracy.unwrap_or(0.).to_string());
+            if let Some(ele) = p.ele {
+                req = req.query("ele", &ele.to_string());
+            }
+            req.send_string("")
+        } else {
+            let body = types::LogLocations {
+                locations: pending
+                    .iter()
+                    .cloned()
+                    .map(types::geofeature_from_point)
+                    .collect(),
+            };
+            agent
+                .post(&format!("{}/{}/logjson", opts.server, client))
+                .query("secret", &opts.secret)
+                .set("Content-Type", "application/json")
+                .send_string(&serde_json::to_string(&body).unwrap())
+        };
+        match result {
+            Ok(_) => {
+                stats
+                    .ingest
+                    .lock()
+                    .unwrap()
+                    .push(millis(chrono::Utc::now() - sent));
+                stats.points.fetch_add(pending.len(), Ordering::Relaxed);
+            }
+            Err(e) => {
+                if stats.ingest_errors.fetch_add(1, Ordering::Relaxed) < 10 {
+                    eprintln!("{}: Couldn't log points: {}", client, e);
+                }
+            }
+        }
+        pending.clear();
+    }
+}
+
+/// Wait for updates of one client and record the time from the newest point to its arrival.
+fn run_watcher(i: usize, opts: Arc<Options>, stats: Arc<Stats>, agent: ureq::Agent) {
+    let client = format!("{}{}", opts.prefix, i);
+    loop {
+        let result = agent
+            .get(&format!("{}/{}/retrieve/live", opts.server, client))
+            .query("secret", &opts.secret)
+            .query("timeout", &opts.live_timeout.to_string())
+            .call()
+            .map_err(|e| e.to_string())
+            .and_then(|r| r.into_string().map_err(|e| e.to_string()))
+            .and_then(|s| serde_json::from_str::<types::LiveUpdate>(&s).map_err(|e| e.to_string()));
+        let received = chrono::Utc::now();
+        match result {
+            Ok(update) => match update.geo {
+                Some(geo) => {
+                    let newest = geo
+                        .features
+                        .into_iter()
+                        .map(|f| types::geopoint_from_feature(f).time)
+                        .max();
+                    if let Some(t) = newest {
+                        stats.delay.lock().unwrap().push(millis(received - t));
+                        stats.updates.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                None => {
+                    stats.live_timeouts.fetch_add(1, Ordering::Relaxed);
+                }
+            },
+            Err(e) => {
+                if stats.live_errors.fetch_add(1, Ordering::Relaxed) < 10 {
+                    eprintln!("{}: retrieve/live failed: {}", client, e);
+                }
+                std::thread::sleep(time::Duration::from_secs(1));
+            }
+        }
+    }
+}
+
+fn main() {
+    let matches = App::new("geohub-loadgen")
+        .about("Generate load on a GeoHub instance and measure latencies")
+        .arg(
+            Arg::with_name("server")
+                .long("server")
+                .takes_value(true)
+                .default_value("http://localhost:8000/geo")
+                .help("Base URL of the GeoHub instance"),
+        )
+        .arg(
+            Arg::with_name("clients")
+                .long("clients")
+                .short("n")
+                .takes_value(true)
+                .default_value("10")
+                .help("Number of simulated clients"),
+        )
+        .arg(
+            Arg::with_name("watchers")
+                .long("watchers")
+                .short("m")
+                .takes_value(true)
+                .default_value("10")
+                .help("Number of retrieve/live watchers, distributed over the clients"),
+        )
+        .arg(
+            Arg::with_name("rate")
+                .long("rate")
+                .takes_value(true)
+                .default_value("1")
+                .help("Points per second and client"),
+        )
+        .arg(
+            Arg::with_name("batch")
+                .long("batch")
+                .takes_value(true)
+                .default_value("1")
+                .help("Points per request. 1 uses log, more use logjson"),
+        )
+        .arg(
+            Arg::with_name("duration")
+                .long("duration")
+                .takes_value(true)
+                .default_value("60")
+                .help("Duration of the run in seconds"),
+        )
+        .arg(
+            Arg::with_name("speed")
+                .long("speed")
+                .takes_value(true)
+                .default_value("10")
+                .help("Speed of the simulated clients in m/s"),
+        )
+        .arg(
+            Arg::with_name("gpx")
+                .long("gpx")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("GPX file with a route to follow instead of a random walk. Can be repeated"),
+        )
+        .arg(
+            Arg::with_name("prefix")
+                .long("prefix")
+                .takes_value(true)
+                .default_value("loadgen")
+                .help("Client names are the prefix followed by a number"),
+        )
+        .arg(
+            Arg::with_name("secret")
+                .long("secret")
+                .takes_value(true)
+                .default_value("loadgen")
+                .help("Secret for all clients"),
+        )
+        .arg(
+            Arg::with_name("live-timeout")
+                .long("live-timeout")
+                .takes_value(true)
+                .default_value("30")
+                .help("Timeout of retrieve/live requests in seconds"),
+        )
+        .get_matches();
+
+    let clients = value_t!(matches, "clients", usize).unwrap_or_else(|e| e.exit());
+    let watchers = value_t!(matches, "watchers", usize).unwrap_or_else(|e| e.exit());
+    let speed = value_t!(matches, "speed", f64).unwrap_or_else(|e| e.exit());
+    let opts = Arc::new(Options {
+        server: matches
+            .value_of("server")
+            .unwrap()
+            .trim_end_matches('/')
+            .to_string(),
+        prefix: matches.value_of("prefix").unwrap().to_string(),
+        secret: matches.value_of("secret").unwrap().to_string(),
+        rate: value_t!(matches, "rate", f64).unwrap_or_else(|e| e.exit()),
+        batch: value_t!(matches, "batch", usize).unwrap_or_else(|e| e.exit()),
+        duration: time::Duration::from_secs(
+            value_t!(matches, "duration", u64).unwrap_or_else(|e| e.exit()),
+        ),
+        live_timeout: value_t!(matches, "live-timeout", u64).unwrap_or_else(|e| e.exit()),
+    });
+    if clients == 0 || !(opts.rate > 0.) || opts.batch == 0 {
+        eprintln!("clients, rate and batch must be positive");
+        std::process::exit(1);
+    }
+    let routes = match matches
+        .values_of("gpx")
+        .map(|v| v.map(read_route).collect::<Result<Vec<_>, String>>())
+        .unwrap_or(Ok(vec![]))
+    {
+        Ok(routes) => routes.into_iter().map(Arc::new).collect::<Vec<_>>(),
+        Err(e) => {
+            eprintln!("Couldn't read route: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let agent = ureq::AgentBuilder::new()
+        .timeout_read(time::Duration::from_secs(opts.live_timeout + 10))
+        .build();
+    let stats = Arc::new(Stats::default());
+    let mut rng = rand::thread_rng();
+
+    for i in 0..watchers {
+        let (opts, stats, agent) = (opts.clone(), stats.clone(), agent.clone());
+        std::thread::spawn(move || run_watcher(i % clients, opts, stats, agent));
+    }
+    // Give the watchers a moment to start waiting.
+    std::thread::sleep(time::Duration::from_millis(500));
+
+    let started = time::Instant::now();
+    let mut threads = vec![];
+    for i in 0..clients {
+        let movement = if routes.is_empty() {
+            Movement::Walk {
+                lat: rng.gen_range(-60.0..60.0),
+                long: rng.gen_range(-180.0..180.0),
+                heading: rng.gen_range(0.0..2. * std::f64::consts::PI),
+                speed: speed,
+            }
+        } else {
+            // Spread clients over the routes, and along them.
+            Movement::Route {
+                points: routes[i % routes.len()].clone(),
+                distance: rng.gen_range(0.0..1e6),
+                speed: speed,
+            }
+        };
+        let (opts, stats, agent) = (opts.clone(), stats.clone(), agent.clone());
+        threads.push(std::thread::spawn(move || {
+            run_client(i, movement, opts, stats, agent)
+        }));
+    }
+    for t in threads {
+        t.join().ok();
+    }
+    let elapsed = started.elapsed().as_secs_f64();
+    // Wait for the last notifications.
+    std::thread::sleep(time::Duration::from_secs(2));
+
+    let points = stats.points.load(Ordering::Relaxed);
+    println!(
+        "{} clients, {} watchers, {:.1} s: {} points logged ({:.1}/s), {} ingest errors",
+        clients,
+        watchers,
+        elapsed,
+        points,
+        points as f64 / elapsed,
+        stats.ingest_errors.load(Ordering::Relaxed)
+    );
+    println!(
+        "{} live updates, {} live timeouts, {} live errors",
+        stats.updates.load(Ordering::Relaxed),
+        stats.live_timeouts.load(Ordering::Relaxed),
+        stats.live_errors.load(Ordering::Relaxed)
+    );
+    report("ingest latency", &stats.ingest);
+    report("notification delay", &stats.delay);
+}
@@ -0,0 +1,89 @@
+use crate::db;
+use crate::geo;
+use crate::mvt;
+
+/// GeoHub-specific settings, read from the extras of the active Rocket.toml environment.
+///
+/// Managed by Rocket.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Whether the database has the PostGIS `geog` column (see `pgsql_postgis.sql`).
+    pub postgis: bool,
+    /// Positions are only interpolated between points at most this many seconds apart. At most
+    /// `geo::MAX_INTERPOLATION_GAP`.
+    pub max_interpolation_gap: i64,
+    /// URL of the `geohub` database, for threads that need their own connection.
+    pub database_url: String,
+    /// Token that authorizes administrative requests, like renaming other clients. Without it,
+    /// such requests are refused.
+    pub admin_token: Option<String>,
+    /// Hashes secrets with the `secret_key`, if one is configured.
+    pub secrets: db::SecretHasher,
+    /// At most this many clients are labelled individually in the metrics.
+    pub metrics_client_limit: usize,
+    /// Don't store points of a session at the same time and position as an existing one.
+    pub dedupe_points: bool,
+    /// Maximum number of points loaded for one vector tile. At most `mvt::MAX_TILE_POINTS`.
+    pub max_tile_points: i64,
+    /// Maximum number of replays running at the same time.
+    pub max_replays: usize,
+    /// Log JSON lines instead of text (`log_format = "json"`).
+    pub log_json: bool,
+    /// Most verbose level logged, following Rocket's `log` setting.
+    pub log_level: log::LevelFilter,
+}
+
+impl Config {
+    pub fn from_rocket(cfg: &rocket::Config) -> Config {
+        Config {
+            postgis: cfg.get_bool("postgis").unwrap_or(false),
+            max_interpolation_gap: cfg
+                .get_int("max_interpolation_gap")
+                .unwrap_or(600)
+                .max(0)
+                .min(geo::MAX_INTERPOLATION_GAP),
+            database_url: rocket_contrib::databases::database_config("geohub", cfg)
+                .map(|c| c.url.to_string())
+                .unwrap_or_default(),
+            admin_token: cfg
+                .get_str("admin_token")
+                .ok()
+                .filter(|t| !t.is_empty())
+                .map(|t| t.to_string()),
+            secrets: db::SecretHasher::new(
+                cfg.get_str("secret_key")
+                    .ok()
+                    .filter(|k| !k.is_empty())
+                    .map(|k| k.as_bytes()),
+            ),
+            metrics_client_limit: cfg.get_int("metrics_client_limit").unwrap_or(100).max(0)
+                as usize,
+            dedupe_points: cfg.get_bool("dedupe_points").unwrap_or(false),
+            max_tile_points: mvt::point_limit(cfg.get_int("max_tile_points").unwrap_or(10000)),
+            max_replays: cfg.get_int("max_replays").unwrap_or(16).max(0) as usize,
+            log_json: cfg.get_str("log_format") == Ok("json"),
+            log_level: match cfg.log_level {
+                rocket::config::LoggingLevel::Critical => log::LevelFilter::Warn,
+                rocket::config::LoggingLevel::Normal => log::LevelFilter::Info,
+                rocket::config::LoggingLevel::Debug => log::LevelFilter::Debug,
+                rocket::config::LoggingLevel::Off => log::LevelFilter::Off,
+            },
+        }
+    }
+
+    /// Whether `token` is the admin token. Always false if none is configured.
+    pub fn is_admin(&self, token: &Option<String>) -> bool {
+        match (&self.admin_token, token) {
+            // Compare in constant time; the token is a password.
+            (Some(expected), Some(token)) => {
+                expected.len() == token.len()
+                    && expected
+                        .bytes()
+                        .zip(token.bytes())
+                        .fold(0, |acc, (a, b)| acc | (a ^ b))
+                        == 0
+            }
+            _ => false,
+        }
+    }
+}
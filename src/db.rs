@@ -1,14 +1,271 @@
 use crate::types;
 
+use hmac::{Mac, NewMac};
+use postgres::types::ToSql;
+use sha2::Digest;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
 /// Managed by Rocket.
 #[rocket_contrib::database("geohub")]
 pub struct DBConn(postgres::Connection);
 
+/// Restricts retrieved points to an area.
+#[derive(Debug, Clone)]
+pub enum SpatialFilter {
+    /// Bounding box: minlon, minlat, maxlon, maxlat.
+    BBox(f64, f64, f64, f64),
+    /// Circle: lat, lon, radius in meters. Requires PostGIS.
+    Near(f64, f64, f64),
+    /// A GeoJSON Polygon or MultiPolygon geometry. Requires PostGIS.
+    Within(String),
+}
+
+impl SpatialFilter {
+    pub fn needs_postgis(&self) -> bool {
+        match self {
+            SpatialFilter::BBox(..) => false,
+            _ => true,
+        }
+    }
+
+    /// Returns an SQL condition with placeholders numbered from `$first`, and the values for
+    /// them. Without PostGIS, only bounding boxes are supported (on the plain lat/long columns).
+    fn to_sql(&self, first: usize, postgis: bool) -> (String, Vec<&dyn ToSql>) {
+        match self {
+            SpatialFilter::BBox(minlon, minlat, maxlon, maxlat) => {
+                let cond = if postgis {
+                    format!(
+                        "ST_Intersects(geog, ST_MakeEnvelope(${}, ${}, ${}, ${}, 4326)::geography)",
+                        first,
+                        first + 1,
+                        first + 2,
+                        first + 3
+                    )
+                } else {
+                    format!(
+                        "(long BETWEEN ${} AND ${}) AND (lat BETWEEN ${} AND ${})",
+                        first,
+                        first + 2,
+                        first + 1,
+                        first + 3
+                    )
+                };
+                (cond, vec![minlon as &dyn ToSql, minlat, maxlon, maxlat])
+            }
+            SpatialFilter::Near(lat, lon, radius) => (
+                format!(
+                    "ST_DWithin(geog, ST_MakePoint(${}, ${})::geography, ${})",
+                    first + 1,
+                    first,
+                    first + 2
+                ),
+                vec![lat as &dyn ToSql, lon, radius],
+            ),
+            SpatialFilter::Within(geojson) => (
+                format!(
+                    "ST_Covers(ST_GeomFromGeoJSON(${}::text)::geography, geog)",
+                    first
+                ),
+                vec![geojson as &dyn ToSql],
+            ),
+        }
+    }
+}
+
+/// Smallest grid cell size of a heatmap, in degrees (about 10 cm). Smaller cells would overflow
+/// the cell coordinates.
+pub const MIN_GRID_PRECISION: f64 = 1e-6;
+
+/// How to bin points for a heatmap.
+#[derive(Debug, Clone, Copy)]
+pub enum HeatmapBinning {
+    /// Square cells, with the size in degrees.
+    Grid(f64),
+    /// Geohash cells, with the number of characters. Requires PostGIS.
+    Geohash(i32),
+}
+
+/// Aggregated points in one heatmap cell.
+#[derive(Debug, Clone)]
+pub struct HeatmapCell {
+    pub cell: String,
+    /// minlon, minlat, maxlon, maxlat.
+    pub bounds: (f64, f64, f64, f64),
+    pub count: i64,
+    /// Time spent in this cell in seconds.
+    pub dwell: f64,
+}
+
+/// A proximity rule involving a client that just logged a point.
+pub struct ProximityCheck {
+    pub rule: types::ProximityRule,
+    /// The other client of the rule, and the hashed secret of its points visible to the rule.
+    pub counterpart: String,
+    pub counterpart_secret: Option<Vec<u8>>,
+}
+
+/// Overview of one client, for administration.
+#[derive(Debug, Clone)]
+pub struct ClientSummary {
+    pub client: String,
+    pub points: i64,
+    /// Number of distinct secrets, including "no secret".
+    pub sessions: i64,
+    pub first: Option<chrono::DateTime<chrono::Utc>>,
+    pub last: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// Overview of the points of a client logged with one secret.
+#[derive(Debug, Clone)]
+pub struct SessionSummary {
+    /// Hashed secret; `None` for public points.
+    pub secret: Option<Vec<u8>>,
+    pub points: i64,
+    pub first: Option<chrono::DateTime<chrono::Utc>>,
+    pub last: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// Database-wide statistics.
+#[derive(Debug, Clone)]
+pub struct Statistics {
+    pub points: i64,
+    pub clients: i64,
+    pub sessions: i64,
+    pub first: Option<chrono::DateTime<chrono::Utc>>,
+    pub last: Option<chrono::DateTime<chrono::Utc>>,
+    pub points_last_day: i64,
+    /// Size of the geodata table including indices, in bytes.
+    pub table_size: i64,
+}
+
+/// Hashes secrets before they are stored or compared.
+///
+/// Hashes used to be the unsalted SHA-256 of the secret, as computed by
+/// `public.digest(secret, 'sha256')`. With a server key, new hashes are `0x01 || HMAC-SHA256(key,
+/// SHA-256(secret))` instead: they can't be brute-forced from a dump without the key, and legacy
+/// hashes can be upgraded without knowing the secret (see `DBQuery::rehash_legacy`). Lookups
+/// match both kinds until all are upgraded. Without a key, legacy hashes are used throughout.
+#[derive(Clone)]
+pub struct SecretHasher {
+    key: Option<Vec<u8>>,
+    /// Sessions (client and keyed hash) whose legacy hashes have been upgraded by this process,
+    /// see `DBQuery::upgrade_secret` and `mark_upgraded`.
+    upgraded: Arc<Mutex<HashSet<Session>>>,
+}
+
+/// A client and the keyed hash of its secret.
+type Session = (String, Vec<u8>);
+
+/// First byte of keyed hashes, which are 33 bytes long; legacy hashes are 32 bytes long.
+const KEYED_HASH_VERSION: u8 = 1;
+
+/// The set of upgraded sessions is cleared once it has this many entries.
+const MAX_UPGRADED_SESSIONS: usize = 4096;
+
+impl SecretHasher {
+    pub fn new(key: Option<&[u8]>) -> SecretHasher {
+        SecretHasher {
+            key: key.map(|k| k.to_vec()),
+            upgraded: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    pub fn has_key(&self) -> bool {
+        self.key.is_some()
+    }
+
+    fn legacy(secret: &str) -> Vec<u8> {
+        sha2::Sha256::digest(secret.as_bytes()).to_vec()
+    }
+
+    /// Convert a legacy hash to a keyed one. `None` without a key, or if `hash` is not a legacy
+    /// hash.
+    pub fn upgrade(&self, hash: &[u8]) -> Option<Vec<u8>> {
+        let key = self.key.as_ref()?;
+        if hash.len() != 32 {
+            return None;
+        }
+        let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(key).unwrap();
+        mac.update(hash);
+        let mut keyed = vec![KEYED_HASH_VERSION];
+        keyed.extend_from_slice(&mac.finalize().into_bytes());
+        Some(keyed)
+    }
+
+    /// HMAC-SHA256 of `data` with the key, or its SHA-256 without one. For identifiers derived
+    /// from secrets that others may see, e.g. notification channels.
+    pub fn digest(&self, data: &[u8]) -> Vec<u8> {
+        match self.key.as_ref() {
+            Some(key) => {
+                let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(key).unwrap();
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+            None => sha2::Sha256::digest(data).to_vec(),
+        }
+    }
+
+    /// The hash to store for `secret`.
+    pub fn hash(&self, secret: &Option<String>) -> Option<Vec<u8>> {
+        let legacy = Self::legacy(secret.as_ref()?);
+        Some(self.upgrade(&legacy).unwrap_or(legacy))
+    }
+
+    /// The hashes that may be stored for `secret`; empty for public points.
+    pub fn candidates(&self, secret: &Option<String>) -> Vec<Vec<u8>> {
+        match secret {
+            Some(secret) => self.candidates_for_hash(&Self::legacy(secret)),
+            None => vec![],
+        }
+    }
+
+    /// The session of `name` and `secret` in the set of upgraded sessions, if its hashes can be
+    /// upgraded at all.
+    fn upgradable_session(&self, name: &str, secret: &Option<String>) -> Option<Session> {
+        match self.candidates(secret).as_slice() {
+            [keyed, _] => Some((name.to_string(), keyed.clone())),
+            _ => None,
+        }
+    }
+
+    /// Whether the legacy hashes of the session have been upgraded already, or there is nothing to
+    /// upgrade (no key, or a public session).
+    pub fn is_upgraded(&self, name: &str, secret: &Option<String>) -> bool {
+        match self.upgradable_session(name, secret) {
+            Some(session) => self.upgraded.lock().unwrap().contains(&session),
+            None => true,
+        }
+    }
+
+    /// Remember that the legacy hashes of the session have been upgraded. Only call this once the
+    /// transaction of `DBQuery::upgrade_secret` is committed.
+    pub fn mark_upgraded(&self, name: &str, secret: &Option<String>) {
+        if let Some(session) = self.upgradable_session(name, secret) {
+            let mut upgraded = self.upgraded.lock().unwrap();
+            if upgraded.len() >= MAX_UPGRADED_SESSIONS {
+                upgraded.clear();
+            }
+            upgraded.insert(session);
+        }
+    }
+
+    /// The hashes that may be stored for the same secret as `hash`.
+    fn candidates_for_hash(&self, hash: &[u8]) -> Vec<Vec<u8>> {
+        match self.upgrade(hash) {
+            Some(keyed) => vec![keyed, hash.to_vec()],
+            None => vec![hash.to_vec()],
+        }
+    }
+}
+
 /// For requests from in- or outside a request handler.
-pub struct DBQuery<'a>(pub &'a postgres::Connection);
+pub struct DBQuery<'a>(pub &'a postgres::Connection, pub &'a SecretHasher);
 
 impl<'a> DBQuery<'a> {
     /// Fetch records and format as JSON
+    ///
+    /// `postgis` states whether the `geog` column is available for `spatial`.
     pub fn retrieve(
         &self,
         name: &str,
@@ -17,61 +274,662 @@ impl<'a> DBQuery<'a> {
         secret: &Option<String>,
         limit: i64,
         last: Option<i32>,
+        spatial: &Option<SpatialFilter>,
+        postgis: bool,
     ) -> Result<Vec<types::GeoPoint>, postgres::Error> {
-        let stmt = self.0.prepare_cached(
+        let last = last.unwrap_or(0);
+        let secrets = self.1.candidates(secret);
+        let mut params: Vec<&dyn ToSql> = vec![&name, &from_ts, &to_ts, &secrets, &last, &limit];
+        let spatial_cond = if let Some(filter) = spatial {
+            let (cond, filter_params) = filter.to_sql(params.len() + 1, postgis);
+            params.extend(filter_params);
+            format!("AND ({})", cond)
+        } else {
+            "".into()
+        };
+        // There is a small, fixed number of variants of this query, so caching them is fine.
+        let stmt = self.0.prepare_cached(&format!(
             r"SELECT id, t, lat, long, spd, ele, note, accuracy FROM geohub.geodata
-        WHERE (client = $1) and (t between $2 and $3) AND (secret = public.digest($4, 'sha256') or secret is null) AND (id > $5)
+        WHERE (client = $1) and (t between $2 and $3) AND (secret = ANY($4) or secret is null) AND (id > $5) {}
         ORDER BY t ASC
-        LIMIT $6").unwrap(); // Must succeed.
-        let rows = stmt.query(&[&name, &from_ts, &to_ts, &secret, &last.unwrap_or(0), &limit])?;
-        let mut returnable = Vec::with_capacity(rows.len());
-        for row in rows.iter() {
-            let (id, ts, lat, long, spd, ele, note, acc) = (
-                row.get(0),
-                row.get(1),
-                row.get(2),
-                row.get(3),
-                row.get(4),
-                row.get(5),
-                row.get(6),
-                row.get(7),
-            );
-            let point = types::GeoPoint {
-                id: id,
-                lat: lat,
-                long: long,
-                spd: spd,
-                ele: ele,
-                accuracy: acc,
-                note: note,
-                time: ts,
-            };
-            returnable.push(point);
-        }
-        Ok(returnable)
+        LIMIT $6", spatial_cond))?;
+        let rows = stmt.query(&params)?;
+        Ok(rows.iter().map(|row| point_from_row(&row)).collect())
     }
 
+    /// Fetch the last point at or before `t` and the first point after `t`, in this order. Either
+    /// may be missing.
+    pub fn retrieve_around(
+        &self,
+        name: &str,
+        secret: &Option<String>,
+        t: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Vec<types::GeoPoint>, postgres::Error> {
+        let stmt = self
+            .0
+            .prepare_cached(
+                r"(SELECT id, t, lat, long, spd, ele, note, accuracy FROM geohub.geodata
+            WHERE (client = $1) AND (t <= $2) AND (secret = ANY($3) or secret is null)
+            ORDER BY t DESC
+            LIMIT 1)
+            UNION ALL
+            (SELECT id, t, lat, long, spd, ele, note, accuracy FROM geohub.geodata
+            WHERE (client = $1) AND (t > $2) AND (secret = ANY($3) or secret is null)
+            ORDER BY t ASC
+            LIMIT 1)
+            ORDER BY t ASC",
+            )
+            .unwrap(); // Must succeed.
+        let rows = stmt.query(&[&name, &t, &self.1.candidates(secret)])?;
+        Ok(rows.iter().map(|row| point_from_row(&row)).collect())
+    }
+
+    /// Keep other transactions from logging points of `name` until the current transaction ends.
+    /// Outside of a transaction, the lock is released right away. Taken for deduplicated or
+    /// keyed points, and for moving points to `name`.
+    pub fn lock_client(&self, name: &str) -> Result<(), postgres::Error> {
+        let stmt = self.0.prepare_cached(
+            r"SELECT pg_advisory_xact_lock(hashtext('geohub.geodata'), hashtext($1))",
+        )?;
+        stmt.execute(&[&name])?;
+        Ok(())
+    }
+
+    /// Store a point, returning its ID. Nothing is stored, and `None` returned, if the session of
+    /// `name` and `secret` already has a point with the same `key`, or if `dedupe` is set and it
+    /// already has a point at the same time and position. Other sessions don't matter, so that
+    /// they can't keep points from being stored.
+    ///
+    /// With `dedupe`, the caller must hold `lock_client` for `name`: otherwise, the same point
+    /// logged concurrently may be stored twice.
     pub fn log_geopoint(
         &self,
         name: &str,
         secret: &Option<String>,
         point: &types::GeoPoint,
-    ) -> Result<(), postgres::Error> {
+        key: &Option<String>,
+        dedupe: bool,
+    ) -> Result<Option<i32>, postgres::Error> {
         let stmt = self.0.prepare_cached(
-            r"INSERT INTO geohub.geodata (client, lat, long, spd, t, ele, secret, note, accuracy)
-            VALUES ($1, $2, $3, $4, $5, $6, public.digest($7, 'sha256'), $8, $9)").unwrap();
-        stmt.execute(&[
+            r"INSERT INTO geohub.geodata (client, lat, long, spd, t, ele, secret, note, accuracy, point_key)
+            SELECT $1::text, $2::float8, $3::float8, $4::float8, $5::timestamptz, $6::float8, $7::bytea, $8::text, $9::float8, $10::text
+            WHERE NOT $11 OR NOT EXISTS (
+                SELECT 1 FROM geohub.geodata
+                WHERE client = $1 AND t = $5 AND lat = $2 AND long = $3 AND secret IS NOT DISTINCT FROM $7)
+            ON CONFLICT (client, COALESCE(secret, ''::bytea), point_key) WHERE point_key IS NOT NULL DO NOTHING
+            RETURNING id").unwrap();
+        let rows = stmt.query(&[
             &name,
             &point.lat,
             &point.long,
             &point.spd,
             &point.time,
             &point.ele,
-            &secret,
+            &self.1.hash(secret),
             &point.note,
             &point.accuracy,
-        ])
-        .map(|_| ())
+            key,
+            &dedupe,
+        ])?;
+        Ok(rows.iter().next().map(|row| row.get(0)))
+    }
+
+    /// Bin the points of `name` (with `secret`) and the public points of `others` in the given
+    /// time range. The time until the next point of the same client, but at most `max_dwell`
+    /// seconds, counts as dwell time.
+    pub fn heatmap(
+        &self,
+        name: &str,
+        secret: &Option<String>,
+        others: &Vec<String>,
+        from_ts: chrono::DateTime<chrono::Utc>,
+        to_ts: chrono::DateTime<chrono::Utc>,
+        max_dwell: f64,
+        binning: HeatmapBinning,
+    ) -> Result<Vec<HeatmapCell>, postgres::Error> {
+        let secret = self.1.candidates(secret);
+        const POINTS: &str = r"WITH pts AS (
+            SELECT lat, long,
+                LEAST(EXTRACT(EPOCH FROM (LEAD(t) OVER (PARTITION BY client ORDER BY t) - t))::float8, $6) AS dwell
+            FROM geohub.geodata
+            WHERE ((client = $1 AND (secret = ANY($2) or secret is null))
+                OR (client = ANY($3) AND secret is null))
+                AND (t between $4 and $5) AND lat IS NOT NULL AND long IS NOT NULL)";
+        match binning {
+            HeatmapBinning::Grid(size) => {
+                let stmt = self.0.prepare_cached(&format!(
+                    r"{}
+                    SELECT floor(long / $7)::int8 AS x, floor(lat / $7)::int8 AS y, count(*), coalesce(sum(dwell), 0)::float8
+                    FROM pts
+                    GROUP BY x, y", POINTS))?;
+                let rows =
+                    stmt.query(&[&name, &secret, others, &from_ts, &to_ts, &max_dwell, &size])?;
+                Ok(rows
+                    .iter()
+                    .map(|row| {
+                        let (x, y): (i64, i64) = (row.get(0), row.get(1));
+                        HeatmapCell {
+                            cell: format!("{},{}", x, y),
+                            bounds: (
+                                x as f64 * size,
+                                y as f64 * size,
+                                (x + 1) as f64 * size,
+                                (y + 1) as f64 * size,
+                            ),
+                            count: row.get(2),
+                            dwell: row.get(3),
+                        }
+                    })
+                    .collect())
+            }
+            HeatmapBinning::Geohash(len) => {
+                let stmt = self.0.prepare_cached(&format!(
+                    r"{}, cells AS (
+                        SELECT ST_GeoHash(ST_SetSRID(ST_MakePoint(long, lat), 4326), $7::int4) AS gh,
+                            count(*) AS n, coalesce(sum(dwell), 0)::float8 AS dwell
+                        FROM pts
+                        GROUP BY gh)
+                    SELECT gh, n, dwell, ST_XMin(b)::float8, ST_YMin(b)::float8, ST_XMax(b)::float8, ST_YMax(b)::float8
+                    FROM (SELECT *, ST_Box2dFromGeoHash(gh) AS b FROM cells) c", POINTS))?;
+                let rows =
+                    stmt.query(&[&name, &secret, others, &from_ts, &to_ts, &max_dwell, &len])?;
+                Ok(rows
+                    .iter()
+                    .map(|row| HeatmapCell {
+                        cell: row.get(0),
+                        count: row.get(1),
+                        dwell: row.get(2),
+                        bounds: (row.get(3), row.get(4), row.get(5), row.get(6)),
+                    })
+                    .collect())
+            }
+        }
+    }
+
+    /// Fetch the most recent point of `name` that is public or protected by the hashed secret.
+    pub fn latest_point(
+        &self,
+        name: &str,
+        secret_hash: &Option<Vec<u8>>,
+    ) -> Result<Option<types::GeoPoint>, postgres::Error> {
+        let stmt = self
+            .0
+            .prepare_cached(
+                r"SELECT id, t, lat, long, spd, ele, note, accuracy FROM geohub.geodata
+            WHERE (client = $1) AND (secret = ANY($2) or secret is null)
+            ORDER BY id DESC
+            LIMIT 1",
+            )
+            .unwrap(); // Must succeed.
+
+        // The rule may have been created before the points were rehashed, or vice versa.
+        let secrets = secret_hash
+            .as_ref()
+            .map(|h| self.1.candidates_for_hash(h))
+            .unwrap_or(vec![]);
+        let rows = stmt.query(&[&name, &secrets])?;
+        Ok(rows.iter().next().map(|row| point_from_row(&row)))
+    }
+
+    /// Create a proximity rule owned by `name` and `secret`, returning its ID.
+    pub fn create_proximity_rule(
+        &self,
+        name: &str,
+        secret: &Option<String>,
+        other: &str,
+        other_secret: &Option<String>,
+        radius: f64,
+    ) -> Result<i32, postgres::Error> {
+        let stmt = self
+            .0
+            .prepare_cached(
+                r"INSERT INTO geohub.proximity_rules (client, secret, other, other_secret, radius)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING id",
+            )
+            .unwrap();
+        let rows = stmt.query(&[
+            &name,
+            &self.1.hash(secret),
+            &other,
+            &self.1.hash(other_secret),
+            &radius,
+        ])?;
+        Ok(rows.get(0).get(0))
+    }
+
+    /// List the proximity rules owned by `name` and `secret`.
+    pub fn proximity_rules(
+        &self,
+        name: &str,
+        secret: &Option<String>,
+    ) -> Result<Vec<types::ProximityRule>, postgres::Error> {
+        let stmt = self
+            .0
+            .prepare_cached(
+                r"SELECT id, client, other, radius, inside FROM geohub.proximity_rules
+            WHERE (client = $1) AND (secret = ANY($2) OR (secret IS NULL AND cardinality($2::bytea[]) = 0))
+            ORDER BY id ASC",
+            )
+            .unwrap();
+        let rows = stmt.query(&[&name, &self.1.candidates(secret)])?;
+        Ok(rows
+            .iter()
+            .map(|row| types::ProximityRule {
+                id: row.get(0),
+                client: row.get(1),
+                other: row.get(2),
+                radius: row.get(3),
+                inside: row.get(4),
+            })
+            .collect())
+    }
+
+    /// Delete a proximity rule owned by `name` and `secret`. Returns the number of deleted rules.
+    pub fn delete_proximity_rule(
+        &self,
+        name: &str,
+        secret: &Option<String>,
+        id: i32,
+    ) -> Result<u64, postgres::Error> {
+        let stmt = self
+            .0
+            .prepare_cached(
+                r"DELETE FROM geohub.proximity_rules
+            WHERE (id = $1) AND (client = $2)
+                AND (secret = ANY($3) OR (secret IS NULL AND cardinality($3::bytea[]) = 0))",
+            )
+            .unwrap();
+        stmt.execute(&[&id, &name, &self.1.candidates(secret)])
+    }
+
+    /// Find the proximity rules on either side of which a point of `name` logged with `secret`
+    /// is visible.
+    pub fn proximity_checks(
+        &self,
+        name: &str,
+        secret: &Option<String>,
+    ) -> Result<Vec<ProximityCheck>, postgres::Error> {
+        let stmt = self
+            .0
+            .prepare_cached(
+                r"SELECT id, client, other, radius, inside,
+                CASE WHEN client = $1 THEN other ELSE client END,
+                CASE WHEN client = $1 THEN other_secret ELSE secret END
+            FROM geohub.proximity_rules
+            WHERE (client = $1 AND (cardinality($2::bytea[]) = 0 OR secret = ANY($2)))
+                OR (other = $1 AND (cardinality($2::bytea[]) = 0 OR other_secret = ANY($2)))",
+            )
+            .unwrap();
+        let rows = stmt.query(&[&name, &self.1.candidates(secret)])?;
+        Ok(rows
+            .iter()
+            .map(|row| ProximityCheck {
+                rule: types::ProximityRule {
+                    id: row.get(0),
+                    client: row.get(1),
+                    other: row.get(2),
+                    radius: row.get(3),
+                    inside: row.get(4),
+                },
+                counterpart: row.get(5),
+                counterpart_secret: row.get(6),
+            })
+            .collect())
+    }
+
+    /// Update the state of a proximity rule. Returns 0 if it was already in this state.
+    pub fn set_proximity_state(&self, id: i32, inside: bool) -> Result<u64, postgres::Error> {
+        let stmt = self
+            .0
+            .prepare_cached(
+                r"UPDATE geohub.proximity_rules SET inside = $2 WHERE (id = $1) AND (inside <> $2)",
+            )
+            .unwrap();
+        stmt.execute(&[&id, &inside])
+    }
+
+    /// List all clients.
+    pub fn clients(&self) -> Result<Vec<ClientSummary>, postgres::Error> {
+        let stmt = self.0.prepare_cached(
+            r"SELECT client, COUNT(*), COUNT(DISTINCT COALESCE(secret, '')), MIN(t), MAX(t)
+            FROM geohub.geodata
+            GROUP BY client
+            ORDER BY client ASC",
+        )?;
+        let rows = stmt.query(&[])?;
+        Ok(rows
+            .iter()
+            .map(|row| ClientSummary {
+                client: row.get(0),
+                points: row.get(1),
+                sessions: row.get(2),
+                first: row.get(3),
+                last: row.get(4),
+            })
+            .collect())
+    }
+
+    /// List the sessions (distinct secrets) of a client.
+    pub fn sessions(&self, name: &str) -> Result<Vec<SessionSummary>, postgres::Error> {
+        let stmt = self.0.prepare_cached(
+            r"SELECT secret, COUNT(*), MIN(t), MAX(t)
+            FROM geohub.geodata
+            WHERE client = $1
+            GROUP BY secret
+            ORDER BY MIN(t) ASC",
+        )?;
+        let rows = stmt.query(&[&name])?;
+        Ok(rows
+            .iter()
+            .map(|row| SessionSummary {
+                secret: row.get(0),
+                points: row.get(1),
+                first: row.get(2),
+                last: row.get(3),
+            })
+            .collect())
+    }
+
+    /// Delete the points of `name` logged with exactly `secret` (or without secret, if `None`)
+    /// in the given time range. Returns the number of deleted points.
+    pub fn delete_points(
+        &self,
+        name: &str,
+        secret: &Option<String>,
+        from_ts: chrono::DateTime<chrono::Utc>,
+        to_ts: chrono::DateTime<chrono::Utc>,
+    ) -> Result<u64, postgres::Error> {
+        let stmt = self.0.prepare_cached(
+            r"DELETE FROM geohub.geodata
+            WHERE (client = $1) AND (secret = ANY($2) OR (secret IS NULL AND cardinality($2::bytea[]) = 0))
+                AND (t BETWEEN $3 AND $4)",
+        )?;
+        stmt.execute(&[&name, &self.1.candidates(secret), &from_ts, &to_ts])
+    }
+
+    /// Count the points that `delete_points` would delete.
+    pub fn count_points(
+        &self,
+        name: &str,
+        secret: &Option<String>,
+        from_ts: chrono::DateTime<chrono::Utc>,
+        to_ts: chrono::DateTime<chrono::Utc>,
+    ) -> Result<i64, postgres::Error> {
+        let stmt = self.0.prepare_cached(
+            r"SELECT COUNT(*) FROM geohub.geodata
+            WHERE (client = $1) AND (secret = ANY($2) OR (secret IS NULL AND cardinality($2::bytea[]) = 0))
+                AND (t BETWEEN $3 AND $4)",
+        )?;
+        let rows = stmt.query(&[&name, &self.1.candidates(secret), &from_ts, &to_ts])?;
+        Ok(rows.get(0).get(0))
+    }
+
+    /// Count the points that moving client `from` to client `to` would give the same key as a
+    /// point of the same session of `to`. With `session` (secret and new secret), only that
+    /// session is moved, as by `move_session`. Such a move fails on the unique index of point keys.
+    pub fn count_key_conflicts(
+        &self,
+        from: &str,
+        session: Option<(&Option<String>, &Option<String>)>,
+        to: &str,
+    ) -> Result<i64, postgres::Error> {
+        let stmt = self.0.prepare_cached(
+            r"SELECT COUNT(*) FROM geohub.geodata f
+            JOIN geohub.geodata t ON t.client = $3 AND t.point_key = f.point_key AND t.id <> f.id
+                AND COALESCE(t.secret, ''::bytea) = COALESCE(CASE WHEN $4 THEN f.secret ELSE $5 END, ''::bytea)
+            WHERE (f.client = $1) AND f.point_key IS NOT NULL
+                AND ($4 OR f.secret = ANY($2) OR (f.secret IS NULL AND cardinality($2::bytea[]) = 0))",
+        )?;
+        let (candidates, to_secret) = match session {
+            Some((secret, to_secret)) => (self.1.candidates(secret), self.1.hash(to_secret)),
+            None => (vec![], None),
+        };
+        let rows = stmt.query(&[&from, &candidates, &to, &session.is_none(), &to_secret])?;
+        Ok(rows.get(0).get(0))
+    }
+
+    /// Move all points of client `from` to client `to`, keeping their IDs and secrets, and the
+    /// proximity rules on either side with them. Returns the number of moved points, and the
+    /// `limit` latest public ones in chronological order.
+    pub fn move_client(
+        &self,
+        from: &str,
+        to: &str,
+        limit: i64,
+    ) -> Result<(u64, Vec<types::GeoPoint>), postgres::Error> {
+        // The count is returned even if no point is public, with NULL for the point.
+        let stmt = self.0.prepare_cached(
+            r"WITH moved AS (
+                UPDATE geohub.geodata SET client = $2 WHERE client = $1
+                RETURNING id, t, lat, long, spd, ele, note, accuracy, secret)
+            SELECT p.id, p.t, p.lat, p.long, p.spd, p.ele, p.note, p.accuracy, c.n
+            FROM (SELECT COUNT(*) AS n FROM moved) c
+            LEFT JOIN LATERAL (
+                SELECT * FROM moved WHERE secret IS NULL ORDER BY t DESC LIMIT $3) p ON true
+            ORDER BY p.t DESC",
+        )?;
+        let rows = stmt.query(&[&from, &to, &limit])?;
+        for query in &[
+            r"UPDATE geohub.proximity_rules SET client = $2 WHERE client = $1",
+            r"UPDATE geohub.proximity_rules SET other = $2 WHERE other = $1",
+        ] {
+            self.0.prepare_cached(query)?.execute(&[&from, &to])?;
+        }
+        self.drop_self_rules(to)?;
+        Ok(moved_points(&rows))
+    }
+
+    /// Move the points of client `from` with exactly secret `secret` to client `to` and secret
+    /// `to_secret`, keeping their IDs, and the proximity rules of the session on either side
+    /// with them. Returns the number of moved points, and the `limit` latest of them in
+    /// chronological order.
+    pub fn move_session(
+        &self,
+        from: &str,
+        secret: &Option<String>,
+        to: &str,
+        to_secret: &Option<String>,
+        limit: i64,
+    ) -> Result<(u64, Vec<types::GeoPoint>), postgres::Error> {
+        let stmt = self.0.prepare_cached(
+            r"WITH moved AS (
+                UPDATE geohub.geodata SET client = $3, secret = $4
+                WHERE (client = $1) AND (secret = ANY($2) OR (secret IS NULL AND cardinality($2::bytea[]) = 0))
+                RETURNING id, t, lat, long, spd, ele, note, accuracy)
+            SELECT id, t, lat, long, spd, ele, note, accuracy, COUNT(*) OVER () FROM moved
+            ORDER BY t DESC
+            LIMIT $5",
+        )?;
+        let rows = stmt.query(&[
+            &from,
+            &self.1.candidates(secret),
+            &to,
+            &self.1.hash(to_secret),
+            &limit,
+        ])?;
+        for query in &[
+            r"UPDATE geohub.proximity_rules SET client = $3, secret = $4
+            WHERE (client = $1) AND (secret = ANY($2) OR (secret IS NULL AND cardinality($2::bytea[]) = 0))",
+            r"UPDATE geohub.proximity_rules SET other = $3, other_secret = $4
+            WHERE (other = $1)
+                AND (other_secret = ANY($2) OR (other_secret IS NULL AND cardinality($2::bytea[]) = 0))",
+        ] {
+            self.0.prepare_cached(query)?.execute(&[
+                &from,
+                &self.1.candidates(secret),
+                &to,
+                &self.1.hash(to_secret),
+            ])?;
+        }
+        self.drop_self_rules(to)?;
+        Ok(moved_points(&rows))
+    }
+
+    /// Delete the proximity rules between `name` and itself, which moving points may leave
+    /// behind (they can't be created).
+    fn drop_self_rules(&self, name: &str) -> Result<u64, postgres::Error> {
+        let stmt = self.0.prepare_cached(
+            r"DELETE FROM geohub.proximity_rules WHERE (client = $1) AND (other = $1)",
+        )?;
+        stmt.execute(&[&name])
+    }
+
+    /// Change the secret of session `secret` of `name` to `new_secret`, in its points and in the
+    /// proximity rules on either side. Returns the number of updated rows.
+    pub fn rehash_session(
+        &self,
+        name: &str,
+        secret: &Option<String>,
+        new_secret: &Option<String>,
+    ) -> Result<u64, postgres::Error> {
+        let mut n = 0;
+        for query in &[
+            r"UPDATE geohub.geodata SET secret = $3
+            WHERE (client = $1) AND (secret = ANY($2) OR (secret IS NULL AND cardinality($2::bytea[]) = 0))",
+            r"UPDATE geohub.proximity_rules SET secret = $3
+            WHERE (client = $1) AND (secret = ANY($2) OR (secret IS NULL AND cardinality($2::bytea[]) = 0))",
+            r"UPDATE geohub.proximity_rules SET other_secret = $3
+            WHERE (other = $1)
+                AND (other_secret = ANY($2) OR (other_secret IS NULL AND cardinality($2::bytea[]) = 0))",
+        ] {
+            n += self.0.prepare_cached(query)?.execute(&[
+                &name,
+                &self.1.candidates(secret),
+                &self.1.hash(new_secret),
+            ])?;
+        }
+        Ok(n)
+    }
+
+    pub fn client_exists(&self, name: &str) -> Result<bool, postgres::Error> {
+        let stmt = self
+            .0
+            .prepare_cached(r"SELECT EXISTS (SELECT 1 FROM geohub.geodata WHERE client = $1)")?;
+        let rows = stmt.query(&[&name])?;
+        Ok(rows.get(0).get(0))
+    }
+
+    /// Whether `name` has points with exactly `secret` (or without secret, if `None`).
+    pub fn session_exists(
+        &self,
+        name: &str,
+        secret: &Option<String>,
+    ) -> Result<bool, postgres::Error> {
+        let stmt = self.0.prepare_cached(
+            r"SELECT EXISTS (SELECT 1 FROM geohub.geodata
+            WHERE (client = $1) AND (secret = ANY($2) OR (secret IS NULL AND cardinality($2::bytea[]) = 0)))",
+        )?;
+        let rows = stmt.query(&[&name, &self.1.candidates(secret)])?;
+        Ok(rows.get(0).get(0))
+    }
+
+    /// Replace the legacy hashes of `secret` in the points and proximity rules of `name` with the
+    /// keyed hash. Returns the number of updated rows; without key, nothing is updated.
+    ///
+    /// This is called for every logged point, so sessions marked with
+    /// `SecretHasher::mark_upgraded` are skipped, and rows are only updated if there are legacy
+    /// hashes at all. Legacy hashes written later (e.g. by an instance without key) are still
+    /// matched by lookups, and upgraded after a restart or by `rehash_legacy`. Proximity rules
+    /// are skipped on databases without the table (see `pgsql_proximity_rules.sql`).
+    pub fn upgrade_secret(
+        &self,
+        name: &str,
+        secret: &Option<String>,
+    ) -> Result<u64, postgres::Error> {
+        if self.1.is_upgraded(name, secret) {
+            return Ok(0);
+        }
+        let candidates = self.1.candidates(secret);
+        let (keyed, legacy) = (&candidates[0], &candidates[1]);
+        // Uses the index on client and secret; cheaper than updating nothing.
+        let stmt = self.0.prepare_cached(
+            r"SELECT EXISTS (SELECT 1 FROM geohub.geodata WHERE (client = $1) AND (secret = $2)),
+                to_regclass('geohub.proximity_rules') IS NOT NULL",
+        )?;
+        let rows = stmt.query(&[&name, legacy])?;
+        let (points, rules): (bool, bool) = (rows.get(0).get(0), rows.get(0).get(1));
+        let mut n = 0;
+        if points {
+            n += self
+                .0
+                .prepare_cached(
+                    r"UPDATE geohub.geodata SET secret = $3 WHERE (client = $1) AND (secret = $2)",
+                )?
+                .execute(&[&name, legacy, keyed])?;
+        }
+        if !rules {
+            return Ok(n);
+        }
+        let stmt = self.0.prepare_cached(
+            r"SELECT EXISTS (SELECT 1 FROM geohub.proximity_rules WHERE (client = $1) AND (secret = $2))
+            OR EXISTS (SELECT 1 FROM geohub.proximity_rules WHERE (other = $1) AND (other_secret = $2))",
+        )?;
+        let rows = stmt.query(&[&name, legacy])?;
+        if rows.get(0).get(0) {
+            for query in &[
+                r"UPDATE geohub.proximity_rules SET secret = $3 WHERE (client = $1) AND (secret = $2)",
+                r"UPDATE geohub.proximity_rules SET other_secret = $3
+                WHERE (other = $1) AND (other_secret = $2)",
+            ] {
+                n += self
+                    .0
+                    .prepare_cached(query)?
+                    .execute(&[&name, legacy, keyed])?;
+            }
+        }
+        Ok(n)
+    }
+
+    /// Replace all legacy hashes with keyed ones. This works without knowing the secrets, see
+    /// `SecretHasher`. Returns the number of updated rows. Requires a key.
+    pub fn rehash_legacy(&self) -> Result<u64, postgres::Error> {
+        assert!(self.1.has_key());
+        let mut n = 0;
+        for (table, column) in &[
+            ("geodata", "secret"),
+            ("proximity_rules", "secret"),
+            ("proximity_rules", "other_secret"),
+        ] {
+            let hashes = self.0.query(
+                &format!(
+                    "SELECT DISTINCT {c} FROM geohub.{t} WHERE length({c}) = 32",
+                    t = table,
+                    c = column
+                ),
+                &[],
+            )?;
+            let update = self.0.prepare(&format!(
+                "UPDATE geohub.{t} SET {c} = $2 WHERE {c} = $1",
+                t = table,
+                c = column
+            ))?;
+            for row in hashes.iter() {
+                let legacy: Vec<u8> = row.get(0);
+                n += update.execute(&[&legacy, &self.1.upgrade(&legacy)])?;
+            }
+        }
+        Ok(n)
+    }
+
+    pub fn statistics(&self) -> Result<Statistics, postgres::Error> {
+        let stmt = self.0.prepare_cached(
+            r"SELECT COUNT(*), COUNT(DISTINCT client), COUNT(DISTINCT (client, COALESCE(secret, ''))),
+                MIN(t), MAX(t), COUNT(*) FILTER (WHERE t > NOW() - INTERVAL '1 day'),
+                pg_total_relation_size('geohub.geodata')
+            FROM geohub.geodata",
+        )?;
+        let rows = stmt.query(&[])?;
+        let row = rows.get(0);
+        Ok(Statistics {
+            points: row.get(0),
+            clients: row.get(1),
+            sessions: row.get(2),
+            first: row.get(3),
+            last: row.get(4),
+            points_last_day: row.get(5),
+            table_size: row.get(6),
+        })
     }
 
     /// Queries for at most `limit` rows since entry ID `last`.
@@ -82,17 +940,21 @@ impl<'a> DBQuery<'a> {
         last: &Option<i32>,
         limit: &Option<i64>,
     ) -> Option<(Vec<types::GeoPoint>, i32)> {
-        let check_for_new = self.0.prepare_cached(
-            r"SELECT id, t, lat, long, spd, ele, note, accuracy FROM geohub.geodata
-            WHERE (client = $1) and (id > $2) AND (secret = public.digest($3, 'sha256') or secret is null)
+        let check_for_new = self
+            .0
+            .prepare_cached(
+                r"SELECT id, t, lat, long, spd, ele, note, accuracy FROM geohub.geodata
+            WHERE (client = $1) and (id > $2) AND (secret = ANY($3) or secret is null)
             ORDER BY id DESC
-            LIMIT $4").unwrap(); // Must succeed.
+            LIMIT $4",
+            )
+            .unwrap(); // Must succeed.
 
         let last = last.unwrap_or(0);
         let limit = limit.unwrap_or(256);
 
         let mut returnable = vec![];
-        let rows = check_for_new.query(&[&name, &last, &secret, &limit]);
+        let rows = check_for_new.query(&[&name, &last, &self.1.candidates(secret), &limit]);
         if let Ok(rows) = rows {
             // If there are unknown entries, return those.
             if rows.len() > 0 {
@@ -100,26 +962,8 @@ impl<'a> DBQuery<'a> {
                 let mut last = 0;
 
                 for row in rows.iter() {
-                    let (id, ts, lat, long, spd, ele, note, acc) = (
-                        row.get(0),
-                        row.get(1),
-                        row.get(2),
-                        row.get(3),
-                        row.get(4),
-                        row.get(5),
-                        row.get(6),
-                        row.get(7),
-                    );
-                    let point = types::GeoPoint {
-                        id: Some(id),
-                        time: ts,
-                        lat: lat,
-                        long: long,
-                        spd: spd,
-                        ele: ele,
-                        note: note,
-                        accuracy: acc,
-                    };
+                    let point = point_from_row(&row);
+                    let id = point.id.unwrap_or(0);
                     returnable.push(point);
                     if id > last {
                         last = id;
@@ -130,8 +974,134 @@ impl<'a> DBQuery<'a> {
             }
             return None;
         } else if let Err(e) = rows {
-            eprintln!("check_for_new_rows: Couldn't check new rows: {}", e);
+            log::warn!("check_for_new_rows: Couldn't check new rows: {}", e);
         }
         return None;
     }
 }
+
+/// Number of moved points and the returned ones, in chronological order, from the rows of
+/// `move_client` or `move_session`.
+fn moved_points(rows: &postgres::rows::Rows) -> (u64, Vec<types::GeoPoint>) {
+    let total: i64 = rows.iter().next().map(|row| row.get(8)).unwrap_or(0);
+    let mut points: Vec<types::GeoPoint> = rows
+        .iter()
+        .filter(|row| row.get::<_, Option<i32>>(0).is_some())
+        .map(|row| point_from_row(&row))
+        .collect();
+    points.reverse();
+    (total as u64, points)
+}
+
+/// Convert a row of `SELECT id, t, lat, long, spd, ele, note, accuracy` into a point.
+fn point_from_row(row: &postgres::rows::Row) -> types::GeoPoint {
+    types::GeoPoint {
+        id: row.get(0),
+        time: row.get(1),
+        lat: row.get(2),
+        long: row.get(3),
+        spd: row.get(4),
+        ele: row.get(5),
+        note: row.get(6),
+        accuracy: row.get(7),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn some(s: &str) -> Option<String> {
+        Some(s.to_string())
+    }
+
+    #[test]
+    fn test_secret_hasher_legacy() {
+        let hasher = SecretHasher::new(None);
+        let legacy = sha2::Sha256::digest(b"verysecret").to_vec();
+        assert_eq!(hasher.hash(&some("verysecret")), Some(legacy.clone()));
+        assert_eq!(hasher.candidates(&some("verysecret")), vec![legacy.clone()]);
+        assert_eq!(hasher.upgrade(&legacy), None);
+        // Public points have no hash.
+        assert_eq!(hasher.hash(&None), None);
+        assert!(hasher.candidates(&None).is_empty());
+    }
+
+    #[test]
+    fn test_secret_hasher_keyed() {
+        let hasher = SecretHasher::new(Some(b"server key"));
+        let legacy = sha2::Sha256::digest(b"verysecret").to_vec();
+        let keyed = hasher.hash(&some("verysecret")).unwrap();
+        assert_eq!(keyed.len(), 33);
+        assert_eq!(keyed[0], KEYED_HASH_VERSION);
+        assert_eq!(hasher.upgrade(&legacy), Some(keyed.clone()));
+        // Keyed hashes aren't upgraded again.
+        assert_eq!(hasher.upgrade(&keyed), None);
+        // Points are found by either hash until all are upgraded; keyed ones first.
+        assert_eq!(
+            hasher.candidates(&some("verysecret")),
+            vec![keyed.clone(), legacy]
+        );
+        assert!(hasher.candidates(&None).is_empty());
+
+        let other = SecretHasher::new(Some(b"other key"));
+        assert_ne!(other.hash(&some("verysecret")), Some(keyed.clone()));
+        assert_ne!(hasher.hash(&some("othersecret")), Some(keyed));
+    }
+
+    /// Needs a database set up with `pgsql_schema.sql`, given as `GEOHUB_TEST_DATABASE`. Nothing
+    /// is committed.
+    #[test]
+    #[ignore]
+    fn test_log_geopoint_sessions() {
+        let url = std::env::var("GEOHUB_TEST_DATABASE").expect("GEOHUB_TEST_DATABASE not set");
+        let conn = postgres::Connection::connect(url.as_str(), postgres::TlsMode::None).unwrap();
+        let _trans = conn.transaction().unwrap();
+        let hasher = SecretHasher::new(Some(b"server key"));
+        let db = DBQuery(&conn, &hasher);
+        let point = types::GeoPoint {
+            id: None,
+            lat: 48.1,
+            long: 11.5,
+            spd: None,
+            ele: None,
+            accuracy: None,
+            time: chrono::Utc::now(),
+            note: None,
+        };
+        let log = |secret: &str, key: Option<&str>, dedupe: bool| {
+            let secret = Some(secret.to_string()).filter(|s| !s.is_empty());
+            let key = key.map(String::from);
+            db.log_geopoint("geohubtest", &secret, &point, &key, dedupe)
+                .unwrap()
+        };
+
+        // Public points, or points of another session, with the same key don't keep a point
+        // from being stored, but points of the same session do.
+        let key = Some("phone:2021-04-05T12:00:00Z");
+        assert!(log("", key, false).is_some());
+        assert!(log("alice", key, false).is_some());
+        assert!(log("mallory", key, false).is_some());
+        assert!(log("alice", key, false).is_none());
+        assert!(log("", key, false).is_none());
+        // The same goes for points at the same time and position.
+        assert!(log("bob", None, true).is_some());
+        assert!(log("bob", None, true).is_none());
+        assert!(log("eve", None, true).is_some());
+    }
+
+    #[test]
+    fn test_secret_hasher_mark_upgraded() {
+        let hasher = SecretHasher::new(Some(b"server key"));
+        assert!(!hasher.is_upgraded("alice", &some("verysecret")));
+        hasher.mark_upgraded("alice", &some("verysecret"));
+        assert!(hasher.is_upgraded("alice", &some("verysecret")));
+        assert!(!hasher.is_upgraded("alice", &some("othersecret")));
+        assert!(!hasher.is_upgraded("bob", &some("verysecret")));
+        // Clones share the set, like the handlers of one process.
+        assert!(hasher.clone().is_upgraded("alice", &some("verysecret")));
+        // Nothing to upgrade without key, or for public points.
+        assert!(hasher.is_upgraded("alice", &None));
+        assert!(SecretHasher::new(None).is_upgraded("alice", &some("verysecret")));
+    }
+}
@@ -0,0 +1,342 @@
+use crate::types;
+
+/// Mean earth radius in meters.
+pub const EARTH_RADIUS: f64 = 6_371_008.8;
+
+/// Great-circle distance between two positions in meters (haversine formula).
+pub fn distance(lat1: f64, long1: f64, lat2: f64, long2: f64) -> f64 {
+    let (phi1, phi2) = (lat1.to_radians(), lat2.to_radians());
+    let dphi = (lat2 - lat1).to_radians();
+    let dlambda = (long2 - long1).to_radians();
+    let a = (dphi / 2.).sin().powi(2) + phi1.cos() * phi2.cos() * (dlambda / 2.).sin().powi(2);
+    2. * EARTH_RADIUS * a.sqrt().min(1.).asin()
+}
+
+/// Distance between two points in meters.
+pub fn point_distance(a: &types::GeoPoint, b: &types::GeoPoint) -> f64 {
+    distance(a.lat, a.long, b.lat, b.long)
+}
+
+/// Position at fraction `f` (0 to 1) along the great circle from (lat1, long1) to (lat2, long2).
+/// Returns (lat, long).
+pub fn intermediate(lat1: f64, long1: f64, lat2: f64, long2: f64, f: f64) -> (f64, f64) {
+    let delta = distance(lat1, long1, lat2, long2) / EARTH_RADIUS;
+    if delta < 1e-12 {
+        return (lat1, long1);
+    }
+    let (phi1, lambda1) = (lat1.to_radians(), long1.to_radians());
+    let (phi2, lambda2) = (lat2.to_radians(), long2.to_radians());
+    let a = ((1. - f) * delta).sin() / delta.sin();
+    let b = (f * delta).sin() / delta.sin();
+    let x = a * phi1.cos() * lambda1.cos() + b * phi2.cos() * lambda2.cos();
+    let y = a * phi1.cos() * lambda1.sin() + b * phi2.cos() * lambda2.sin();
+    let z = a * phi1.sin() + b * phi2.sin();
+    let phi = z.atan2((x * x + y * y).sqrt());
+    let lambda = y.atan2(x);
+    (phi.to_degrees(), lambda.to_degrees())
+}
+
+fn lerp(a: Option<f64>, b: Option<f64>, f: f64) -> Option<f64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a + (b - a) * f),
+        _ => None,
+    }
+}
+
+/// Interpolate a point at time `t` between `a` and `b` (which must be in chronological order).
+/// Position follows the great circle; elevation, speed, and accuracy are interpolated linearly.
+pub fn interpolate(
+    a: &types::GeoPoint,
+    b: &types::GeoPoint,
+    t: chrono::DateTime<chrono::Utc>,
+) -> types::GeoPoint {
+    let span = (b.time - a.time).num_milliseconds();
+    let f = if span > 0 {
+        ((t - a.time).num_milliseconds() as f64 / span as f64)
+            .max(0.)
+            .min(1.)
+    } else {
+        0.
+    };
+    let (lat, long) = intermediate(a.lat, a.long, b.lat, b.long, f);
+    types::GeoPoint {
+        id: None,
+        lat: lat,
+        long: long,
+        spd: lerp(a.spd, b.spd, f),
+        ele: lerp(a.ele, b.ele, f),
+        accuracy: lerp(a.accuracy, b.accuracy, f),
+        time: t,
+        note: None,
+    }
+}
+
+/// Upper limit for the gap between points that positions are interpolated over, in seconds.
+pub const MAX_INTERPOLATION_GAP: i64 = 7 * 24 * 3600;
+
+/// Result of `position_at`.
+pub struct Interpolated {
+    /// The interpolated position, or `None` if `t` is outside of the track or the gap between
+    /// the surrounding points is too large.
+    pub point: Option<types::GeoPoint>,
+    /// Time distance from `t` to the nearest real point.
+    pub nearest_fix: Option<chrono::Duration>,
+}
+
+/// Find the position at time `t` in a track sorted by time. Points are only interpolated if the
+/// surrounding fixes are at most `max_gap` apart.
+pub fn position_at(
+    track: &[types::GeoPoint],
+    t: chrono::DateTime<chrono::Utc>,
+    max_gap: chrono::Duration,
+) -> Interpolated {
+    // Index of the first point after t.
+    let after = match track.binary_search_by(|p| p.time.cmp(&t)) {
+        Ok(i) => {
+            return Interpolated {
+                point: Some(track[i].clone()),
+                nearest_fix: Some(chrono::Duration::zero()),
+            }
+        }
+        Err(i) => i,
+    };
+    let before = if after > 0 {
+        track.get(after - 1)
+    } else {
+        None
+    };
+    let after = track.get(after);
+    let nearest_fix = match (before, after) {
+        (Some(b), Some(a)) => Some(std::cmp::min(t - b.time, a.time - t)),
+        (Some(b), None) => Some(t - b.time),
+        (None, Some(a)) => Some(a.time - t),
+        (None, None) => None,
+    };
+    let point = match (before, after) {
+        (Some(b), Some(a)) if a.time - b.time <= max_gap => Some(interpolate(b, a, t)),
+        _ => None,
+    };
+    Interpolated {
+        point: point,
+        nearest_fix: nearest_fix,
+    }
+}
+
+/// An interval during which two tracks were within some distance of each other.
+#[derive(Debug, Clone)]
+pub struct Encounter {
+    /// Indices of the two tracks.
+    pub tracks: (usize, usize),
+    pub from: chrono::DateTime<chrono::Utc>,
+    pub to: chrono::DateTime<chrono::Utc>,
+    /// Smallest distance observed during the encounter, in meters.
+    pub min_distance: f64,
+}
+
+/// Find the intervals in which any two of `tracks` (sorted by time) were at most `max_distance`
+/// meters apart. Positions are interpolated at every `step` between `from` and `to`; `max_gap` is
+/// passed on to `position_at`.
+pub fn encounters(
+    tracks: &[Vec<types::GeoPoint>],
+    from: chrono::DateTime<chrono::Utc>,
+    to: chrono::DateTime<chrono::Utc>,
+    step: chrono::Duration,
+    max_distance: f64,
+    max_gap: chrono::Duration,
+) -> Vec<Encounter> {
+    let mut result = vec![];
+    // Don't sample outside of the time covered by the tracks.
+    let first = tracks
+        .iter()
+        .filter_map(|t| t.first())
+        .map(|p| p.time)
+        .min();
+    let last = tracks.iter().filter_map(|t| t.last()).map(|p| p.time).max();
+    let (from, to) = match (first, last) {
+        (Some(first), Some(last)) => (std::cmp::max(from, first), std::cmp::min(to, last)),
+        _ => return result,
+    };
+
+    let n = tracks.len();
+    // Currently open encounter per pair (i, j), at index i * n + j.
+    let mut open: Vec<Option<Encounter>> = vec![None; n * n];
+    let mut t = from;
+    while t <= to {
+        let positions = tracks
+            .iter()
+            .map(|track| position_at(track, t, max_gap).point)
+            .collect::<Vec<Option<types::GeoPoint>>>();
+        for i in 0..n {
+            for j in i + 1..n {
+                let dist = match (&positions[i], &positions[j]) {
+                    (Some(a), Some(b)) => Some(point_distance(a, b)),
+                    _ => None,
+                };
+                let slot = &mut open[i * n + j];
+                match dist {
+                    Some(d) if d <= max_distance => match slot {
+                        Some(enc) => {
+                            enc.to = t;
+                            enc.min_distance = enc.min_distance.min(d);
+                        }
+                        None => {
+                            *slot = Some(Encounter {
+                                tracks: (i, j),
+                                from: t,
+                                to: t,
+                                min_distance: d,
+                            })
+                        }
+                    },
+                    _ => {
+                        if let Some(enc) = slot.take() {
+                            result.push(enc);
+                        }
+                    }
+                }
+            }
+        }
+        t = t + step;
+    }
+    result.extend(open.into_iter().filter_map(|e| e));
+    result.sort_by_key(|e| e.from);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(seconds: i64) -> chrono::DateTime<chrono::Utc> {
+        chrono::DateTime::from_utc(
+            chrono::NaiveDateTime::from_timestamp(1_600_000_000 + seconds, 0),
+            chrono::Utc,
+        )
+    }
+
+    fn point(seconds: i64, lat: f64, long: f64) -> types::GeoPoint {
+        types::GeoPoint {
+            id: None,
+            lat: lat,
+            long: long,
+            spd: None,
+            ele: Some(seconds as f64),
+            accuracy: None,
+            time: at(seconds),
+            note: None,
+        }
+    }
+
+    #[test]
+    fn test_position_at_between_points() {
+        let track = vec![point(0, 0., 0.), point(100, 0., 1.)];
+        let pos = position_at(&track, at(25), chrono::Duration::seconds(100));
+        let p = pos.point.unwrap();
+        assert!(p.lat.abs() < 1e-9);
+        assert!((p.long - 0.25).abs() < 1e-9);
+        assert_eq!(p.ele, Some(25.));
+        assert_eq!(p.time, at(25));
+        assert_eq!(pos.nearest_fix, Some(chrono::Duration::seconds(25)));
+    }
+
+    #[test]
+    fn test_position_at_exact_point() {
+        let track = vec![point(0, 0., 0.), point(100, 0., 1.)];
+        let pos = position_at(&track, at(100), chrono::Duration::zero());
+        assert_eq!(pos.point.unwrap().long, 1.);
+        assert_eq!(pos.nearest_fix, Some(chrono::Duration::zero()));
+    }
+
+    #[test]
+    fn test_position_at_gap_too_large() {
+        let track = vec![point(0, 0., 0.), point(100, 0., 1.)];
+        let pos = position_at(&track, at(80), chrono::Duration::seconds(99));
+        assert!(pos.point.is_none());
+        assert_eq!(pos.nearest_fix, Some(chrono::Duration::seconds(20)));
+    }
+
+    #[test]
+    fn test_position_at_outside_track() {
+        let track = vec![point(0, 0., 0.), point(100, 0., 1.)];
+        let gap = chrono::Duration::seconds(1000);
+        let before = position_at(&track, at(-10), gap);
+        assert!(before.point.is_none());
+        assert_eq!(before.nearest_fix, Some(chrono::Duration::seconds(10)));
+        let after = position_at(&track, at(130), gap);
+        assert!(after.point.is_none());
+        assert_eq!(after.nearest_fix, Some(chrono::Duration::seconds(30)));
+        let empty = position_at(&[], at(0), gap);
+        assert!(empty.point.is_none());
+        assert!(empty.nearest_fix.is_none());
+    }
+
+    #[test]
+    fn test_encounters() {
+        // a stands still, b passes it at 100 s, moving 0.001° (111 m) every 10 s.
+        let a = vec![point(0, 0., 0.), point(200, 0., 0.)];
+        let b = vec![point(0, 0., -0.01), point(200, 0., 0.01)];
+        let encounters = encounters(
+            &[a, b],
+            at(-100),
+            at(300),
+            chrono::Duration::seconds(10),
+            150.,
+            chrono::Duration::seconds(300),
+        );
+        assert_eq!(encounters.len(), 1);
+        let enc = &encounters[0];
+        assert_eq!(enc.tracks, (0, 1));
+        assert_eq!((enc.from, enc.to), (at(90), at(110)));
+        assert!(enc.min_distance < 1.);
+    }
+
+    #[test]
+    fn test_encounters_pairs() {
+        let far = vec![point(0, 1., 0.), point(100, 1., 0.)];
+        let near = vec![point(0, 0., 0.), point(100, 0., 0.)];
+        let encounters = encounters(
+            &[near.clone(), far, near],
+            at(0),
+            at(100),
+            chrono::Duration::seconds(10),
+            10.,
+            chrono::Duration::seconds(300),
+        );
+        assert_eq!(encounters.len(), 1);
+        assert_eq!(encounters[0].tracks, (0, 2));
+        assert_eq!((encounters[0].from, encounters[0].to), (at(0), at(100)));
+    }
+
+    #[test]
+    fn test_encounters_gap() {
+        // Both tracks have a gap in the middle, so the encounter is interrupted.
+        let a = vec![
+            point(0, 0., 0.),
+            point(50, 0., 0.),
+            point(200, 0., 0.),
+            point(250, 0., 0.),
+        ];
+        let encounters = encounters(
+            &[a.clone(), a],
+            at(0),
+            at(250),
+            chrono::Duration::seconds(50),
+            10.,
+            chrono::Duration::seconds(100),
+        );
+        let intervals = encounters
+            .iter()
+            .map(|e| (e.from, e.to))
+            .collect::<Vec<_>>();
+        assert_eq!(intervals, vec![(at(0), at(50)), (at(200), at(250))]);
+    }
+
+    #[test]
+    fn test_encounters_empty() {
+        let step = chrono::Duration::seconds(10);
+        assert!(encounters(&[vec![], vec![]], at(0), at(100), step, 10., step).is_empty());
+        let a = vec![point(0, 0., 0.), point(100, 0., 0.)];
+        // Outside of the time range.
+        assert!(encounters(&[a.clone(), a], at(200), at(300), step, 10., step).is_empty());
+    }
+}
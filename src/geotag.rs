@@ -0,0 +1,363 @@
+use crate::types;
+
+use chrono::Timelike;
+use exif::experimental::Writer;
+use exif::{Field, In, Rational, Tag, Value};
+use std::io::Cursor;
+
+const EXIF_ID: &[u8] = b"Exif\0\0";
+
+/// Maximum size of an uploaded photo.
+pub const MAX_PHOTO_SIZE: u64 = 32 << 20;
+/// Maximum number of timestamps in one request.
+pub const MAX_TIMESTAMPS: usize = 1000;
+/// Maximum camera clock offset in seconds (a year).
+pub const MAX_CLOCK_OFFSET: f64 = 366. * 24. * 3600.;
+
+/// Timestamp of a photo as recorded by the camera: local time and, if known, its UTC offset.
+#[derive(Debug, Clone)]
+pub struct PhotoTime {
+    pub local: chrono::NaiveDateTime,
+    pub offset: Option<chrono::FixedOffset>,
+}
+
+impl PhotoTime {
+    /// Convert to UTC. `tz` is used if the photo has no offset of its own, otherwise UTC is
+    /// assumed. `clock_offset` is how far the camera clock was ahead of real time. `None` if the
+    /// result is out of range.
+    pub fn to_utc(
+        &self,
+        tz: Option<chrono::FixedOffset>,
+        clock_offset: chrono::Duration,
+    ) -> Option<chrono::DateTime<chrono::Utc>> {
+        let offset = self.offset.or(tz).unwrap_or(chrono::FixedOffset::east(0));
+        let utc = self
+            .local
+            .checked_sub_signed(chrono::Duration::seconds(offset.local_minus_utc() as i64))?
+            .checked_sub_signed(clock_offset)?;
+        Some(chrono::DateTime::from_utc(utc, chrono::Utc))
+    }
+}
+
+/// Parse a photo timestamp, either in EXIF format (`2021:04:05 14:32:10`) or as ISO 8601 with
+/// or without UTC offset.
+pub fn parse_photo_time(s: &str) -> Option<PhotoTime> {
+    if let Ok(dt) = exif::DateTime::from_ascii(s.as_bytes()) {
+        return photo_time_from_exif(&dt);
+    }
+    if let Ok(dt) = chrono::DateTime::parse_from_rfc3339(s) {
+        return Some(PhotoTime {
+            local: dt.naive_local(),
+            offset: Some(*dt.offset()),
+        });
+    }
+    for fs in &["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f"] {
+        if let Ok(local) = chrono::NaiveDateTime::parse_from_str(s, fs) {
+            return Some(PhotoTime {
+                local: local,
+                offset: None,
+            });
+        }
+    }
+    None
+}
+
+/// Parse a UTC offset like `+02:00`, `-0530`, or `Z`. As `+` in a query string is decoded to a
+/// space, a leading space is treated like `+`.
+pub fn parse_utc_offset(s: &str) -> Option<chrono::FixedOffset> {
+    if s == "Z" || s == "UTC" {
+        return Some(chrono::FixedOffset::east(0));
+    }
+    let (sign, rest) = match s.chars().next()? {
+        '-' => (-1, &s[1..]),
+        '+' | ' ' => (1, &s[1..]),
+        _ => (1, s),
+    };
+    let digits = rest.replace(':', "");
+    if digits.len() != 4 || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let (h, m) = (
+        digits[0..2].parse::<i32>().ok()?,
+        digits[2..4].parse::<i32>().ok()?,
+    );
+    chrono::FixedOffset::east_opt(sign * (h * 3600 + m * 60))
+}
+
+fn photo_time_from_exif(dt: &exif::DateTime) -> Option<PhotoTime> {
+    let local = chrono::NaiveDate::from_ymd_opt(dt.year as i32, dt.month as u32, dt.day as u32)?
+        .and_hms_nano_opt(
+            dt.hour as u32,
+            dt.minute as u32,
+            dt.second as u32,
+            dt.nanosecond.unwrap_or(0),
+        )?;
+    let offset = dt
+        .offset
+        .and_then(|o| chrono::FixedOffset::east_opt(o as i32 * 60));
+    Some(PhotoTime {
+        local: local,
+        offset: offset,
+    })
+}
+
+/// Read `DateTimeOriginal` (and `OffsetTimeOriginal`, if present) from a JPEG file.
+pub fn jpeg_photo_time(jpeg: &[u8]) -> Result<PhotoTime, String> {
+    let exif = exif::Reader::new()
+        .read_from_container(&mut Cursor::new(jpeg))
+        .map_err(|e| format!("Couldn't read EXIF data: {}", e))?;
+    let field = exif
+        .get_field(Tag::DateTimeOriginal, In::PRIMARY)
+        .ok_or_else(|| "Photo has no DateTimeOriginal".to_string())?;
+    let mut dt = match field.value {
+        Value::Ascii(ref v) if !v.is_empty() => exif::DateTime::from_ascii(&v[0])
+            .map_err(|e| format!("Invalid DateTimeOriginal: {}", e))?,
+        _ => return Err("Invalid DateTimeOriginal".into()),
+    };
+    if let Some(field) = exif.get_field(Tag::OffsetTimeOriginal, In::PRIMARY) {
+        if let Value::Ascii(ref v) = field.value {
+            if let Some(v) = v.first() {
+                dt.parse_offset(v).ok();
+            }
+        }
+    }
+    photo_time_from_exif(&dt).ok_or_else(|| "Invalid DateTimeOriginal".into())
+}
+
+fn rational(x: f64, denom: u32) -> Rational {
+    Rational {
+        num: (x * denom as f64).round() as u32,
+        denom: denom,
+    }
+}
+
+/// Degrees, minutes, seconds.
+fn dms(deg: f64) -> Value {
+    let deg = deg.abs();
+    let d = deg.trunc();
+    let m = ((deg - d) * 60.).trunc();
+    let s = (deg - d - m / 60.) * 3600.;
+    Value::Rational(vec![rational(d, 1), rational(m, 1), rational(s, 1000)])
+}
+
+fn gps_fields(point: &types::GeoPoint) -> Vec<Field> {
+    let gps = |tag, value| Field {
+        tag: tag,
+        ifd_num: In::PRIMARY,
+        value: value,
+    };
+    let ascii = |s: &str| Value::Ascii(vec![s.as_bytes().to_vec()]);
+    let t = point.time.naive_utc();
+    let mut fields = vec![
+        gps(Tag::GPSVersionID, Value::Byte(vec![2, 3, 0, 0])),
+        gps(
+            Tag::GPSLatitudeRef,
+            ascii(if point.lat < 0. { "S" } else { "N" }),
+        ),
+        gps(Tag::GPSLatitude, dms(point.lat)),
+        gps(
+            Tag::GPSLongitudeRef,
+            ascii(if point.long < 0. { "W" } else { "E" }),
+        ),
+        gps(Tag::GPSLongitude, dms(point.long)),
+        gps(
+            Tag::GPSTimeStamp,
+            Value::Rational(vec![
+                rational(t.hour() as f64, 1),
+                rational(t.minute() as f64, 1),
+                rational(t.second() as f64 + t.nanosecond() as f64 / 1e9, 1000),
+            ]),
+        ),
+        gps(Tag::GPSDateStamp, ascii(&t.format("%Y:%m:%d").to_string())),
+    ];
+    if let Some(ele) = point.ele {
+        fields.push(gps(
+            Tag::GPSAltitudeRef,
+            Value::Byte(vec![if ele < 0. { 1 } else { 0 }]),
+        ));
+        fields.push(gps(
+            Tag::GPSAltitude,
+            Value::Rational(vec![rational(ele.abs(), 100)]),
+        ));
+    }
+    if let Some(spd) = point.spd {
+        fields.push(gps(Tag::GPSSpeedRef, ascii("K")));
+        fields.push(gps(
+            Tag::GPSSpeed,
+            Value::Rational(vec![rational(spd.max(0.), 100)]),
+        ));
+    }
+    if let Some(acc) = point.accuracy {
+        fields.push(gps(
+            Tag::GPSHPositioningError,
+            Value::Rational(vec![rational(acc.max(0.), 100)]),
+        ));
+    }
+    fields
+}
+
+/// Return a copy of `jpeg` with the GPS tags set to `point`. Existing EXIF data of the primary
+/// image is kept, except for previous GPS tags and the thumbnail.
+pub fn write_gps(jpeg: &[u8], point: &types::GeoPoint) -> Result<Vec<u8>, String> {
+    let existing = exif::Reader::new()
+        .read_from_container(&mut Cursor::new(jpeg))
+        .ok();
+    let gps = gps_fields(point);
+
+    let mut writer = Writer::new();
+    if let Some(existing) = existing.as_ref() {
+        for field in existing.fields() {
+            let keep = field.ifd_num == In::PRIMARY
+                && field.tag.context() != exif::Context::Gps
+                && !matches!(field.value, Value::Unknown(..));
+            if keep {
+                writer.push_field(field);
+            }
+        }
+    }
+    for field in gps.iter() {
+        writer.push_field(field);
+    }
+    let little_endian = existing
+        .as_ref()
+        .map(|e| e.little_endian())
+        .unwrap_or(false);
+    let mut tiff = Cursor::new(Vec::new());
+    writer
+        .write(&mut tiff, little_endian)
+        .map_err(|e| format!("Couldn't encode EXIF data: {}", e))?;
+
+    let mut app1 = EXIF_ID.to_vec();
+    app1.extend(tiff.into_inner());
+    replace_exif_segment(jpeg, &app1)
+}
+
+/// Replace the EXIF APP1 segment of a JPEG file by one with the payload `app1`, or insert it
+/// after SOI and any APP0 segments.
+fn replace_exif_segment(jpeg: &[u8], app1: &[u8]) -> Result<Vec<u8>, String> {
+    if jpeg.len() < 4 || jpeg[0..2] != [0xff, 0xd8] {
+        return Err("Not a JPEG file".into());
+    }
+    if app1.len() + 2 > 0xffff {
+        return Err("EXIF data too large".into());
+    }
+    let mut segment = vec![0xff, 0xe1];
+    segment.extend(&((app1.len() + 2) as u16).to_be_bytes());
+    segment.extend(app1);
+
+    let mut out = Vec::with_capacity(jpeg.len() + segment.len());
+    out.extend(&jpeg[0..2]);
+    let mut inserted = false;
+    let mut pos = 2;
+    while pos + 4 <= jpeg.len() && jpeg[pos] == 0xff {
+        let marker = jpeg[pos + 1];
+        // Start of scan: Everything from here on is copied verbatim.
+        if marker == 0xda {
+            break;
+        }
+        let len = u16::from_be_bytes([jpeg[pos + 2], jpeg[pos + 3]]) as usize;
+        let end = pos + 2 + len;
+        if len < 2 || end > jpeg.len() {
+            return Err("Broken JPEG file".into());
+        }
+        if !inserted && marker != 0xe0 {
+            out.extend(&segment);
+            inserted = true;
+        }
+        let is_exif = marker == 0xe1 && jpeg[pos + 4..end].starts_with(EXIF_ID);
+        if !is_exif {
+            out.extend(&jpeg[pos..end]);
+        }
+        pos = end;
+    }
+    if !inserted {
+        out.extend(&segment);
+    }
+    out.extend(&jpeg[pos..]);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_utc_offset() {
+        let east = |s| chrono::FixedOffset::east(s);
+        assert_eq!(parse_utc_offset("Z"), Some(east(0)));
+        assert_eq!(parse_utc_offset("UTC"), Some(east(0)));
+        assert_eq!(parse_utc_offset("+02:00"), Some(east(7200)));
+        assert_eq!(parse_utc_offset(" 02:00"), Some(east(7200)));
+        assert_eq!(parse_utc_offset("0130"), Some(east(5400)));
+        assert_eq!(parse_utc_offset("-0530"), Some(east(-19800)));
+        assert_eq!(parse_utc_offset(""), None);
+        assert_eq!(parse_utc_offset("+2"), None);
+        assert_eq!(parse_utc_offset("+02:0x"), None);
+        assert_eq!(parse_utc_offset("+99:00"), None);
+    }
+
+    #[test]
+    fn test_to_utc() {
+        let pt = parse_photo_time("2021:04:05 14:32:10").unwrap();
+        let utc = pt
+            .to_utc(parse_utc_offset("+02:00"), chrono::Duration::seconds(10))
+            .unwrap();
+        assert_eq!(utc.to_rfc3339(), "2021-04-05T12:32:00+00:00");
+        // The offset of the photo takes precedence.
+        let pt = parse_photo_time("2021-04-05T14:32:10-01:00").unwrap();
+        let utc = pt
+            .to_utc(parse_utc_offset("+02:00"), chrono::Duration::zero())
+            .unwrap();
+        assert_eq!(utc.to_rfc3339(), "2021-04-05T15:32:10+00:00");
+        assert!(pt.to_utc(None, chrono::Duration::max_value()).is_none());
+    }
+
+    /// A JPEG marker segment with `payload`.
+    fn segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+        let mut s = vec![0xff, marker];
+        s.extend(&((payload.len() + 2) as u16).to_be_bytes());
+        s.extend(payload);
+        s
+    }
+
+    fn jpeg(segments: &[Vec<u8>]) -> Vec<u8> {
+        let mut jpeg = vec![0xff, 0xd8];
+        for s in segments {
+            jpeg.extend(s);
+        }
+        // Start of scan, followed by image data.
+        jpeg.extend(&[0xff, 0xda, 0, 2, 1, 2, 3, 0xff, 0xd9]);
+        jpeg
+    }
+
+    #[test]
+    fn test_replace_exif_segment() {
+        let app0 = segment(0xe0, b"JFIF\0");
+        let old_exif = segment(0xe1, b"Exif\0\0old");
+        let xmp = segment(0xe1, b"http://ns.adobe.com/xap/1.0/\0");
+        let dqt = segment(0xdb, &[0; 8]);
+        let new_exif = segment(0xe1, b"Exif\0\0new");
+
+        let original = jpeg(&[app0.clone(), old_exif, xmp.clone(), dqt.clone()]);
+        let replaced = replace_exif_segment(&original, b"Exif\0\0new").unwrap();
+        assert_eq!(
+            replaced,
+            jpeg(&[app0.clone(), new_exif.clone(), xmp, dqt.clone()])
+        );
+
+        // Without EXIF data, the segment is inserted after APP0.
+        let original = jpeg(&[app0.clone(), dqt.clone()]);
+        let inserted = replace_exif_segment(&original, b"Exif\0\0new").unwrap();
+        assert_eq!(inserted, jpeg(&[app0, new_exif.clone(), dqt]));
+        let inserted = replace_exif_segment(&jpeg(&[]), b"Exif\0\0new").unwrap();
+        assert_eq!(inserted, jpeg(&[new_exif]));
+    }
+
+    #[test]
+    fn test_replace_exif_segment_invalid() {
+        assert!(replace_exif_segment(b"GIF89a", b"Exif\0\0").is_err());
+        // Segment length beyond the end of the file.
+        assert!(replace_exif_segment(&[0xff, 0xd8, 0xff, 0xe0, 0x10, 0], b"Exif\0\0").is_err());
+        assert!(replace_exif_segment(&jpeg(&[]), &vec![0; 0xffff]).is_err());
+    }
+}
@@ -16,10 +16,34 @@ pub enum GeoHubResponse {
     Json(String),
     #[response(status = 200, content_type = "application/gpx+xml")]
     Gpx(String),
+    #[response(status = 200, content_type = "image/jpeg")]
+    Jpeg(Vec<u8>),
+    #[response(status = 200, content_type = "application/vnd.mapbox-vector-tile")]
+    Mvt(Vec<u8>),
+    #[response(status = 200, content_type = "image/svg+xml")]
+    Svg(String),
+    #[response(status = 200, content_type = "image/png")]
+    Png(Vec<u8>),
     #[response(status = 400)]
     BadRequest(String),
+    #[response(status = 403)]
+    Forbidden(String),
+    #[response(status = 404)]
+    NotFound(String),
+    #[response(status = 409)]
+    Conflict(String),
+    #[response(status = 413)]
+    PayloadTooLarge(String),
+    #[response(status = 429)]
+    TooManyRequests(String, rocket::http::Header<'static>),
     #[response(status = 500)]
     ServerError(String),
+    #[response(status = 400, content_type = "json")]
+    BadRequestJson(String),
+    #[response(status = 500, content_type = "json")]
+    ServerErrorJson(String),
+    #[response(status = 503, content_type = "json")]
+    UnavailableJson(String),
 }
 
 fn content_disposition(attachment: bool) -> rocket::http::hyper::header::ContentDisposition {
@@ -49,6 +73,34 @@ pub fn return_gpx(gx: String) -> GeoHubResponder {
     resp
 }
 
+pub fn return_jpeg(jpeg: Vec<u8>) -> GeoHubResponder {
+    GeoHubResponder {
+        inner: GeoHubResponse::Jpeg(jpeg),
+        cd: content_disposition(true),
+    }
+}
+
+pub fn return_mvt(tile: Vec<u8>) -> GeoHubResponder {
+    GeoHubResponder {
+        inner: GeoHubResponse::Mvt(tile),
+        cd: content_disposition(false),
+    }
+}
+
+pub fn return_svg(svg: String) -> GeoHubResponder {
+    GeoHubResponder {
+        inner: GeoHubResponse::Svg(svg),
+        cd: content_disposition(false),
+    }
+}
+
+pub fn return_png(png: Vec<u8>) -> GeoHubResponder {
+    GeoHubResponder {
+        inner: GeoHubResponse::Png(png),
+        cd: content_disposition(false),
+    }
+}
+
 pub fn return_json<T: serde::Serialize>(obj: &T) -> GeoHubResponder {
     let json = serde_json::to_string(&obj);
     let cd = content_disposition(true);
@@ -74,6 +126,47 @@ pub fn bad_request(msg: String) -> GeoHubResponder {
     }
 }
 
+pub fn forbidden(msg: String) -> GeoHubResponder {
+    GeoHubResponder {
+        inner: GeoHubResponse::Forbidden(msg),
+        cd: content_disposition(false),
+    }
+}
+
+/// Answer with 429, asking the client to retry after `wait`.
+pub fn too_many_requests(wait: std::time::Duration) -> GeoHubResponder {
+    // Round up, so that the request succeeds when retried.
+    let seconds = wait.as_secs() + (wait.subsec_nanos() > 0) as u64;
+    GeoHubResponder {
+        inner: GeoHubResponse::TooManyRequests(
+            format!("Rate limit exceeded, retry after {} seconds", seconds),
+            rocket::http::Header::new("Retry-After", seconds.to_string()),
+        ),
+        cd: content_disposition(false),
+    }
+}
+
+pub fn not_found(msg: String) -> GeoHubResponder {
+    GeoHubResponder {
+        inner: GeoHubResponse::NotFound(msg),
+        cd: content_disposition(false),
+    }
+}
+
+pub fn conflict(msg: String) -> GeoHubResponder {
+    GeoHubResponder {
+        inner: GeoHubResponse::Conflict(msg),
+        cd: content_disposition(false),
+    }
+}
+
+pub fn payload_too_large(msg: String) -> GeoHubResponder {
+    GeoHubResponder {
+        inner: GeoHubResponse::PayloadTooLarge(msg),
+        cd: content_disposition(false),
+    }
+}
+
 use std::fmt::Debug;
 
 pub fn server_error<E: Debug>(err: E) -> GeoHubResponder {
@@ -83,12 +176,52 @@ pub fn server_error<E: Debug>(err: E) -> GeoHubResponder {
     }
 }
 
+/// Serialize `obj` as an error response built by `variant`.
+fn error_json<T: serde::Serialize>(
+    obj: &T,
+    variant: fn(String) -> GeoHubResponse,
+) -> GeoHubResponder {
+    match serde_json::to_string(&obj) {
+        Ok(json) => GeoHubResponder {
+            inner: variant(json),
+            cd: content_disposition(false),
+        },
+        Err(e) => server_error(e.to_string()),
+    }
+}
+
+/// Like `return_json`, but with status 400, for reporting which parts of a request are invalid.
+pub fn bad_request_json<T: serde::Serialize>(obj: &T) -> GeoHubResponder {
+    error_json(obj, GeoHubResponse::BadRequestJson)
+}
+
+/// Like `return_json`, but with status 500, for reporting which parts of a request failed.
+pub fn server_error_json<T: serde::Serialize>(obj: &T) -> GeoHubResponder {
+    error_json(obj, GeoHubResponse::ServerErrorJson)
+}
+
+/// Like `return_json`, but with status 503, for reporting why the service is unavailable.
+pub fn unavailable_json<T: serde::Serialize>(obj: &T) -> GeoHubResponder {
+    error_json(obj, GeoHubResponse::UnavailableJson)
+}
+
+/// Answer a failed database operation with a 500, counting it in the metrics.
+pub fn db_error(err: postgres::Error) -> GeoHubResponder {
+    geohub::metrics::METRICS.db_error();
+    log::error!("Database error: {}", err);
+    server_error(err.to_string())
+}
+
 pub fn read_data(d: rocket::Data, limit: u64) -> Result<String, GeoHubResponder> {
+    let dest = read_bytes(d, limit)?;
+    String::from_utf8(dest).map_err(|e| bad_request(format!("Decoding error: {}", e)))
+}
+
+pub fn read_bytes(d: rocket::Data, limit: u64) -> Result<Vec<u8>, GeoHubResponder> {
     let mut ds = d.open().take(limit);
-    let mut dest = Vec::with_capacity(limit as usize);
+    let mut dest = vec![];
     if let Err(e) = std::io::copy(&mut ds, &mut dest) {
         return Err(bad_request(format!("Error reading request: {}", e)));
     }
-
-    String::from_utf8(dest).map_err(|e| bad_request(format!("Decoding error: {}", e)))
+    Ok(dest)
 }
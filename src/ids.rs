@@ -1,3 +1,12 @@
+/// Check if a point key (e.g. a UUID, or a device name and a timestamp) is acceptable.
+pub fn point_key_acceptable(key: &str) -> bool {
+    !key.is_empty()
+        && key.len() <= 128
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_:.+".contains(c))
+}
+
 /// Check if client name and secret are acceptable.
 pub fn name_and_secret_acceptable(client: &str, secret: Option<&str>) -> bool {
     !(client.chars().any(|c| !c.is_ascii_alphanumeric())
@@ -6,3 +15,20 @@ pub fn name_and_secret_acceptable(client: &str, secret: Option<&str>) -> bool {
             .chars()
             .any(|c| !c.is_ascii_alphanumeric()))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_point_key_acceptable() {
+        assert!(point_key_acceptable("3f2b8c1e-7a4d-4e2f-9b1a-0c5d6e7f8a9b"));
+        assert!(point_key_acceptable("phone:2021-04-05T12:00:00.000+02:00"));
+        assert!(point_key_acceptable(&"k".repeat(128)));
+        assert!(!point_key_acceptable(""));
+        assert!(!point_key_acceptable(&"k".repeat(129)));
+        assert!(!point_key_acceptable("a b"));
+        assert!(!point_key_acceptable("a/b"));
+        assert!(!point_key_acceptable("schlüssel"));
+    }
+}
@@ -0,0 +1,13 @@
+//! Types, geometry and database access shared by the GeoHub server and its tools.
+
+#[cfg(feature = "server")]
+pub mod db;
+pub mod geo;
+#[cfg(feature = "server")]
+pub mod logging;
+#[cfg(feature = "server")]
+pub mod metrics;
+#[cfg(feature = "server")]
+pub mod notifier;
+pub mod profile;
+pub mod types;
@@ -0,0 +1,245 @@
+//! Leveled logging to stderr, as text or JSON lines, with the ID of the HTTP request being
+//! handled.
+//!
+//! Rocket handles each request on one worker thread, so the request ID is kept in a thread-local
+//! variable by the `RequestLogger` fairing. Everything logged while handling the request, also
+//! by Rocket and the database code, carries it. Other threads (e.g. the notifier) set it while
+//! working on behalf of a request, see `with_request_id`.
+
+use std::cell::RefCell;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time;
+
+thread_local! {
+    static REQUEST_ID: RefCell<Option<String>> = RefCell::new(None);
+}
+
+/// Query parameters whose values are never logged: anything ending in `secret`, and these.
+const SECRET_PARAMS: &[&str] = &["token"];
+
+struct Logger {
+    json: AtomicBool,
+}
+
+static LOGGER: Logger = Logger {
+    json: AtomicBool::new(false),
+};
+
+/// Install the logger, logging at info level as text until `configure` is called. Must be
+/// called before Rocket is ignited, so that Rocket's own messages are logged by it, too.
+pub fn init() {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(log::LevelFilter::Info);
+    }
+}
+
+/// Set the output format and the most verbose level that is logged.
+pub fn configure(json: bool, level: log::LevelFilter) {
+    LOGGER.json.store(json, Ordering::SeqCst);
+    log::set_max_level(level);
+}
+
+/// ID of the request handled by this thread.
+pub fn request_id() -> Option<String> {
+    REQUEST_ID.with(|id| id.borrow().clone())
+}
+
+fn set_request_id(id: Option<String>) {
+    REQUEST_ID.with(|current| *current.borrow_mut() = id);
+}
+
+/// Run `f` with messages tagged by request `id`, e.g. on another thread than the one that
+/// handled the request.
+pub fn with_request_id<R, F: FnOnce() -> R>(id: Option<&str>, f: F) -> R {
+    let previous = request_id();
+    set_request_id(id.map(|id| id.to_string()));
+    let result = f();
+    set_request_id(previous);
+    result
+}
+
+/// Replace the values of secret query parameters in `s` (e.g. `secret=abc` in a URL).
+pub fn redact(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(eq) = rest.find('=') {
+        let (before, after) = rest.split_at(eq);
+        // The key starts after the last other character, which may be longer than one byte.
+        let key_start = before
+            .char_indices()
+            .rev()
+            .find(|(_, c)| !(c.is_ascii_alphanumeric() || *c == '_'))
+            .map(|(i, c)| i + c.len_utf8())
+            .unwrap_or(0);
+        let key = &before[key_start..];
+        out.push_str(before);
+        out.push('=');
+        let value_len = after[1..]
+            .find(|c: char| c == '&' || c == '"' || c == '\'' || c.is_whitespace())
+            .unwrap_or(after.len() - 1);
+        let is_secret = key.to_ascii_lowercase().ends_with("secret")
+            || SECRET_PARAMS.contains(&key.to_ascii_lowercase().as_str());
+        if is_secret && value_len > 0 {
+            out.push_str("REDACTED");
+        } else {
+            out.push_str(&after[1..1 + value_len]);
+        }
+        rest = &after[1 + value_len..];
+    }
+    out.push_str(rest);
+    out
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let time = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+        let message = redact(&record.args().to_string());
+        let request = request_id();
+        let line = if self.json.load(Ordering::Relaxed) {
+            serde_json::json!({
+                "time": time,
+                "level": record.level().to_string(),
+                "target": record.target(),
+                "request_id": request,
+                "message": message,
+            })
+            .to_string()
+        } else {
+            match request {
+                Some(id) => format!(
+                    "{} {:5} [{}] [{}] {}",
+                    time,
+                    record.level(),
+                    record.target(),
+                    id,
+                    message
+                ),
+                None => format!(
+                    "{} {:5} [{}] {}",
+                    time,
+                    record.level(),
+                    record.target(),
+                    message
+                ),
+            }
+        };
+        let stderr = std::io::stderr();
+        let mut stderr = stderr.lock();
+        writeln!(stderr, "{}", line).ok();
+    }
+
+    fn flush(&self) {
+        std::io::stderr().flush().ok();
+    }
+}
+
+/// Time of arrival and ID of a request, for `RequestLogger`.
+struct RequestInfo(Option<(time::Instant, String)>);
+
+/// Fairing assigning an ID to every request (or taking it from the `X-Request-Id` header), and
+/// logging each request with its outcome. The ID is returned in the `X-Request-Id` header.
+pub struct RequestLogger;
+
+impl RequestLogger {
+    fn new_id() -> String {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        // Unique per process, and unlikely to repeat across restarts.
+        format!(
+            "{:x}-{:x}",
+            chrono::Utc::now().timestamp() & 0xffffff,
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        )
+    }
+}
+
+impl rocket::fairing::Fairing for RequestLogger {
+    fn info(&self) -> rocket::fairing::Info {
+        rocket::fairing::Info {
+            name: "Request Logger",
+            kind: rocket::fairing::Kind::Request | rocket::fairing::Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut rocket::Request, _: &rocket::Data) {
+        let id = request
+            .headers()
+            .get_one("X-Request-Id")
+            .filter(|id| {
+                !id.is_empty()
+                    && id.len() <= 64
+                    && id
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            })
+            .map(|id| id.to_string())
+            .unwrap_or_else(RequestLogger::new_id);
+        set_request_id(Some(id.clone()));
+        request.local_cache(|| RequestInfo(Some((time::Instant::now(), id))));
+    }
+
+    fn on_response(&self, request: &rocket::Request, response: &mut rocket::Response) {
+        if let Some((start, id)) = &request.local_cache(|| RequestInfo(None)).0 {
+            log::info!(
+                target: "geohub::request",
+                "{} {} {} {}ms",
+                request.method(),
+                request.uri(),
+                response.status().code,
+                start.elapsed().as_millis()
+            );
+            response.set_raw_header("X-Request-Id", id.clone());
+        }
+        set_request_id(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(
+            redact("GET /geo/alice/log?secret=abc&lat=52.5"),
+            "GET /geo/alice/log?secret=REDACTED&lat=52.5"
+        );
+        assert_eq!(
+            redact("POST /geo/alice/rename?secret=abc&to=bob&tosecret=xyz"),
+            "POST /geo/alice/rename?secret=REDACTED&to=bob&tosecret=REDACTED"
+        );
+        // At the end of the string, and in other case.
+        assert_eq!(
+            redact("GET /geo/metrics?token=hunter2"),
+            "GET /geo/metrics?token=REDACTED"
+        );
+        assert_eq!(
+            redact("Secret=abc Token=def"),
+            "Secret=REDACTED Token=REDACTED"
+        );
+        // Values end at quotes and whitespace.
+        assert_eq!(
+            redact(r#"{"uri":"/geo/a/log?secret=abc"} x"#),
+            r#"{"uri":"/geo/a/log?secret=REDACTED"} x"#
+        );
+        // Other and empty values are kept.
+        assert_eq!(redact("secret=&lat=1"), "secret=&lat=1");
+        assert_eq!(redact("secretary=bob a == b"), "secretary=bob a == b");
+        assert_eq!(redact("no parameters"), "no parameters");
+    }
+
+    #[test]
+    fn test_redact_non_ascii() {
+        assert_eq!(redact("Grüße=1 ä=2 €=3"), "Grüße=1 ä=2 €=3");
+        assert_eq!(redact("üsecret=abc"), "üsecret=REDACTED");
+        assert_eq!(redact("note=Käse&secret=äbc"), "note=Käse&secret=REDACTED");
+        assert_eq!(redact("ß="), "ß=");
+    }
+}
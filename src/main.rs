@@ -1,13 +1,17 @@
 #![feature(proc_macro_hygiene, decl_macro)]
 
-mod db;
+mod config;
+mod geotag;
 mod http;
 mod ids;
-mod notifier;
-mod types;
+mod mvt;
+mod proximity;
+mod ratelimit;
+mod render;
+mod replay;
 mod util;
 
-use std::sync::{mpsc, Arc, Mutex};
+use geohub::{db, geo, logging, metrics, notifier, profile, types};
 
 use postgres;
 use rocket;
@@ -18,6 +22,7 @@ use rocket;
 #[rocket::get("/geo/<client>/retrieve/last?<secret>&<last>&<limit>")]
 fn retrieve_last(
     db: db::DBConn,
+    config: rocket::State<config::Config>,
     client: String,
     secret: Option<String>,
     last: Option<i32>,
@@ -32,7 +37,7 @@ fn retrieve_last(
     } else {
         secret
     };
-    let db = db::DBQuery(&db.0);
+    let db = db::DBQuery(&db.0, &config.secrets);
     if let Some((points, newlast)) = db.check_for_new_rows(&client, &secret, &last, &limit) {
         let geojson = types::geojson_from_points(points);
         rocket_contrib::json::Json(types::LiveUpdate::new(
@@ -81,17 +86,28 @@ fn retrieve_live(
 }
 
 /// Retrieve GeoJSON data.
-#[rocket::get("/geo/<client>/retrieve/json?<secret>&<from>&<to>&<limit>&<last>")]
+#[rocket::get(
+    "/geo/<client>/retrieve/json?<secret>&<from>&<to>&<limit>&<last>&<bbox>&<near>&<radius>&<within>"
+)]
 fn retrieve_json(
     db: db::DBConn,
+    config: rocket::State<config::Config>,
     client: String,
     secret: Option<String>,
     from: Option<String>,
     to: Option<String>,
     limit: Option<i64>,
     last: Option<i32>,
+    bbox: Option<String>,
+    near: Option<String>,
+    radius: Option<f64>,
+    within: Option<String>,
 ) -> http::GeoHubResponder {
-    let result = common_retrieve(db, client, secret, from, to, limit, last);
+    let spatial = match util::parse_spatial_filter(bbox, near, radius, within) {
+        Ok(spatial) => spatial,
+        Err(e) => return e,
+    };
+    let result = common_retrieve(db, &config, client, secret, from, to, limit, last, spatial);
     match result {
         Ok(points) => {
             let json = types::geojson_from_points(points);
@@ -102,17 +118,28 @@ fn retrieve_json(
 }
 
 /// Retrieve GPX data.
-#[rocket::get("/geo/<client>/retrieve/gpx?<secret>&<from>&<to>&<limit>&<last>")]
+#[rocket::get(
+    "/geo/<client>/retrieve/gpx?<secret>&<from>&<to>&<limit>&<last>&<bbox>&<near>&<radius>&<within>"
+)]
 fn retrieve_gpx(
     db: db::DBConn,
+    config: rocket::State<config::Config>,
     client: String,
     secret: Option<String>,
     from: Option<String>,
     to: Option<String>,
     limit: Option<i64>,
     last: Option<i32>,
+    bbox: Option<String>,
+    near: Option<String>,
+    radius: Option<f64>,
+    within: Option<String>,
 ) -> http::GeoHubResponder {
-    let result = common_retrieve(db, client, secret, from, to, limit, last);
+    let spatial = match util::parse_spatial_filter(bbox, near, radius, within) {
+        Ok(spatial) => spatial,
+        Err(e) => return e,
+    };
+    let result = common_retrieve(db, &config, client, secret, from, to, limit, last, spatial);
     match result {
         Ok(points) => {
             let gx = types::gpx_track_from_points(points);
@@ -129,14 +156,179 @@ fn retrieve_gpx(
     }
 }
 
+/// Render points as SVG image. See `render_options` for the parameters.
+#[rocket::get(
+    "/geo/<client>/retrieve/svg?<secret>&<from>&<to>&<limit>&<last>&<width>&<height>&<padding>&<speed>&<chart>"
+)]
+fn retrieve_svg(
+    db: db::DBConn,
+    config: rocket::State<config::Config>,
+    client: String,
+    secret: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    limit: Option<i64>,
+    last: Option<i32>,
+    width: Option<u32>,
+    height: Option<u32>,
+    padding: Option<u32>,
+    speed: Option<bool>,
+    chart: Option<String>,
+) -> http::GeoHubResponder {
+    let opts = match render_options(width, height, padding, speed, chart) {
+        Ok(opts) => opts,
+        Err(e) => return e,
+    };
+    match common_retrieve(db, &config, client, secret, from, to, limit, last, None) {
+        Ok(points) => http::return_svg(render::render(&points, &opts).to_svg()),
+        Err(e) => e,
+    }
+}
+
+/// Render points as PNG image. Like `retrieve_svg`, but without labels.
+#[rocket::get(
+    "/geo/<client>/retrieve/png?<secret>&<from>&<to>&<limit>&<last>&<width>&<height>&<padding>&<speed>&<chart>"
+)]
+fn retrieve_png(
+    db: db::DBConn,
+    config: rocket::State<config::Config>,
+    client: String,
+    secret: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    limit: Option<i64>,
+    last: Option<i32>,
+    width: Option<u32>,
+    height: Option<u32>,
+    padding: Option<u32>,
+    speed: Option<bool>,
+    chart: Option<String>,
+) -> http::GeoHubResponder {
+    let opts = match render_options(width, height, padding, speed, chart) {
+        Ok(opts) => opts,
+        Err(e) => return e,
+    };
+    match common_retrieve(db, &config, client, secret, from, to, limit, last, None) {
+        Ok(points) => match render::render(&points, &opts).to_png() {
+            Ok(png) => http::return_png(png),
+            Err(e) => http::server_error(e),
+        },
+        Err(e) => e,
+    }
+}
+
+/// Distance vs. elevation and speed, resampled every `step` meters (default 20) and with elevation
+/// smoothed over `smooth` meters (default 100). `format` is `json` (default) or `svg`; the SVG
+/// chart takes the same parameters as `retrieve_svg`.
+#[rocket::get(
+    "/geo/<client>/retrieve/profile?<secret>&<from>&<to>&<step>&<smooth>&<format>&<width>&<height>&<padding>&<speed>"
+)]
+fn retrieve_profile(
+    db: db::DBConn,
+    config: rocket::State<config::Config>,
+    client: String,
+    secret: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    step: Option<f64>,
+    smooth: Option<f64>,
+    format: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    padding: Option<u32>,
+    speed: Option<bool>,
+) -> http::GeoHubResponder {
+    let step = step.unwrap_or(20.);
+    let smooth = smooth.unwrap_or(100.);
+    if !(step >= 1.) || !(smooth >= 0. && smooth <= profile::MAX_SMOOTH) {
+        return http::bad_request(format!(
+            "step must be at least 1, smooth between 0 and {}",
+            profile::MAX_SMOOTH
+        ));
+    }
+    let svg = match format.as_ref().map(|f| f.as_str()) {
+        None | Some("json") => false,
+        Some("svg") => true,
+        Some(f) => return http::bad_request(format!("Unknown format: {}", f)),
+    };
+    let opts = match render_options(width, height, padding, speed, Some("profile".into())) {
+        Ok(opts) => opts,
+        Err(e) => return e,
+    };
+    let points = match common_retrieve(
+        db,
+        &config,
+        client.clone(),
+        secret,
+        from,
+        to,
+        None,
+        None,
+        None,
+    ) {
+        Ok(points) => points,
+        Err(e) => return e,
+    };
+    let resampled = profile::resample(&points, step, smooth);
+    if svg {
+        let (chart, speeds) = resampled.chart_data();
+        http::return_svg(render::render_chart(&chart, &speeds, &opts).to_svg())
+    } else {
+        http::return_json(&types::Profile::new(client, resampled))
+    }
+}
+
+/// Image size defaults to 800x600 with 20 pixels of padding; `chart` is `track` (default) or
+/// `profile`.
+fn render_options(
+    width: Option<u32>,
+    height: Option<u32>,
+    padding: Option<u32>,
+    speed: Option<bool>,
+    chart: Option<String>,
+) -> Result<render::RenderOptions, http::GeoHubResponder> {
+    let (width, height, padding) = (
+        width.unwrap_or(800),
+        height.unwrap_or(600),
+        padding.unwrap_or(20),
+    );
+    if width == 0 || height == 0 || width > render::MAX_SIZE || height > render::MAX_SIZE {
+        return Err(http::bad_request(format!(
+            "width and height must be between 1 and {}",
+            render::MAX_SIZE
+        )));
+    }
+    if padding.saturating_mul(2) >= std::cmp::min(width, height) {
+        return Err(http::bad_request(
+            "padding must be less than half of width and height".into(),
+        ));
+    }
+    let chart = match chart.as_ref().map(|c| c.as_str()) {
+        None => render::Chart::Track,
+        Some(c) => match render::Chart::parse(c) {
+            Some(chart) => chart,
+            None => return Err(http::bad_request(format!("Unknown chart: {}", c))),
+        },
+    };
+    Ok(render::RenderOptions {
+        width: width,
+        height: height,
+        padding: padding,
+        color_by_speed: speed.unwrap_or(false),
+        chart: chart,
+    })
+}
+
 fn common_retrieve(
     db: db::DBConn,
+    config: &config::Config,
     client: String,
     secret: Option<String>,
     from: Option<String>,
     to: Option<String>,
     limit: Option<i64>,
     last: Option<i32>,
+    spatial: Option<db::SpatialFilter>,
 ) -> Result<Vec<types::GeoPoint>, http::GeoHubResponder> {
     if !ids::name_and_secret_acceptable(client.as_str(), secret.as_ref().map(|s| s.as_str())) {
         return Err(http::bad_request(
@@ -144,6 +336,11 @@ fn common_retrieve(
                 .into(),
         ));
     }
+    if spatial.as_ref().map(|f| f.needs_postgis()).unwrap_or(false) && !config.postgis {
+        return Err(http::bad_request(
+            "near and within filters require PostGIS, which is not enabled on this server.".into(),
+        ));
+    }
     let secret = if let Some(secret) = secret {
         if secret.is_empty() {
             None
@@ -153,7 +350,7 @@ fn common_retrieve(
     } else {
         secret
     };
-    let db = db::DBQuery(&db.0);
+    let db = db::DBQuery(&db.0, &config.secrets);
     let from_ts =
         from.and_then(util::flexible_timestamp_parse)
             .unwrap_or(chrono::DateTime::from_utc(
@@ -164,26 +361,638 @@ fn common_retrieve(
         .and_then(util::flexible_timestamp_parse)
         .unwrap_or(chrono::Utc::now());
     let limit = limit.unwrap_or(1 << 16); // 65536
-    let result = db.retrieve(client.as_str(), from_ts, to_ts, &secret, limit, last);
+    let result = db.retrieve(
+        client.as_str(),
+        from_ts,
+        to_ts,
+        &secret,
+        limit,
+        last,
+        &spatial,
+        config.postgis,
+    );
     match result {
-        Ok(points) => Ok(points),
-        Err(e) => Err(http::server_error(e.to_string())),
+        Ok(points) => {
+            metrics::METRICS.retrieved(points.len());
+            Ok(points)
+        }
+        Err(e) => Err(http::db_error(e)),
+    }
+}
+
+/// Aggregate points into cells, returning counts and dwell times as GeoJSON polygons.
+///
+/// `mode` is `grid` (`precision` is the cell size in degrees) or `geohash` (`precision` is the
+/// geohash length). `clients` is a comma-separated list of further clients whose public points
+/// are included.
+#[rocket::get(
+    "/geo/<client>/retrieve/heatmap?<secret>&<from>&<to>&<mode>&<precision>&<clients>&<maxdwell>"
+)]
+fn retrieve_heatmap(
+    db: db::DBConn,
+    config: rocket::State<config::Config>,
+    client: String,
+    secret: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    mode: Option<String>,
+    precision: Option<f64>,
+    clients: Option<String>,
+    maxdwell: Option<f64>,
+) -> http::GeoHubResponder {
+    if !ids::name_and_secret_acceptable(client.as_str(), secret.as_ref().map(|s| s.as_str())) {
+        return http::bad_request(
+            "You have supplied an invalid secret or client. Both must be ASCII alphanumeric strings."
+                .into(),
+        );
+    }
+    let secret = secret.filter(|s| !s.is_empty());
+    let others = clients
+        .map(|c| {
+            c.split(',')
+                .filter(|c| !c.is_empty())
+                .map(String::from)
+                .collect::<Vec<String>>()
+        })
+        .unwrap_or(vec![]);
+    if others
+        .iter()
+        .any(|c| !ids::name_and_secret_acceptable(c.as_str(), None))
+    {
+        return http::bad_request("clients must be a comma-separated list of client names".into());
+    }
+    let binning = match mode.as_ref().map(|m| m.as_str()).unwrap_or("grid") {
+        "grid" => match precision.unwrap_or(0.01) {
+            p if p >= db::MIN_GRID_PRECISION && p <= 90. => db::HeatmapBinning::Grid(p),
+            _ => {
+                return http::bad_request(format!(
+                    "precision must be in [{}, 90] degrees",
+                    db::MIN_GRID_PRECISION
+                ))
+            }
+        },
+        "geohash" => {
+            if !config.postgis {
+                return http::bad_request(
+                    "geohash mode requires PostGIS, which is not enabled on this server.".into(),
+                );
+            }
+            match precision.unwrap_or(7.) {
+                p if p >= 1. && p <= 12. => db::HeatmapBinning::Geohash(p as i32),
+                _ => return http::bad_request("precision must be 1 to 12 characters".into()),
+            }
+        }
+        m => return http::bad_request(format!("Unknown mode '{}'", m)),
+    };
+    let from_ts =
+        from.and_then(util::flexible_timestamp_parse)
+            .unwrap_or(chrono::DateTime::from_utc(
+                chrono::NaiveDateTime::from_timestamp(0, 0),
+                chrono::Utc,
+            ));
+    let to_ts = to
+        .and_then(util::flexible_timestamp_parse)
+        .unwrap_or(chrono::Utc::now());
+    let max_dwell = match maxdwell {
+        Some(d) if !(d.is_finite() && d > 0.) => {
+            return http::bad_request("maxdwell must be a positive number of seconds".into())
+        }
+        Some(d) => d,
+        None => config.max_interpolation_gap as f64,
+    };
+
+    let db = db::DBQuery(&db.0, &config.secrets);
+    match db.heatmap(
+        &client, &secret, &others, from_ts, to_ts, max_dwell, binning,
+    ) {
+        Ok(cells) => http::return_json(&types::heatmap_from_cells(
+            cells
+                .into_iter()
+                .map(|c| (c.cell, c.bounds, c.count, c.dwell)),
+        )),
+        Err(e) => http::db_error(e),
     }
 }
 
+/// Render points and lines as Mapbox Vector Tile. `y` is the tile row followed by `.mvt`.
+#[rocket::get("/geo/<client>/tiles/<z>/<x>/<y>?<secret>&<from>&<to>")]
+fn tiles(
+    db: db::DBConn,
+    config: rocket::State<config::Config>,
+    client: String,
+    z: u32,
+    x: u32,
+    y: String,
+    secret: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+) -> http::GeoHubResponder {
+    let tile = match y
+        .trim_end_matches(".mvt")
+        .parse::<u32>()
+        .ok()
+        .and_then(|y| mvt::Tile::new(z, x, y))
+    {
+        Some(tile) => tile,
+        None => return http::bad_request("Invalid tile coordinates".into()),
+    };
+    let (minlon, minlat, maxlon, maxlat) = tile.bounds(mvt::BUFFER);
+    let spatial = Some(db::SpatialFilter::BBox(minlon, minlat, maxlon, maxlat));
+    // Tiles are supposed to show everything, but a low zoom level covers a lot of points.
+    let limit = Some(config.max_tile_points);
+    let max_gap = chrono::Duration::seconds(config.max_interpolation_gap);
+    match common_retrieve(db, &config, client, secret, from, to, limit, None, spatial) {
+        Ok(points) => http::return_mvt(mvt::encode_tile(tile, &points, max_gap)),
+        Err(e) => e,
+    }
+}
+
+/// Interpolate the position of a client at a given time.
+///
+/// `maxgap` (seconds) overrides the configured maximum time between the surrounding points.
+#[rocket::get("/geo/<client>/retrieve/at?<secret>&<time>&<maxgap>")]
+fn retrieve_at(
+    db: db::DBConn,
+    config: rocket::State<config::Config>,
+    client: String,
+    secret: Option<String>,
+    time: Option<String>,
+    maxgap: Option<i64>,
+) -> http::GeoHubResponder {
+    if !ids::name_and_secret_acceptable(client.as_str(), secret.as_ref().map(|s| s.as_str())) {
+        return http::bad_request(
+            "You have supplied an invalid secret or client. Both must be ASCII alphanumeric strings."
+                .into(),
+        );
+    }
+    let secret = if let Some(secret) = secret {
+        if secret.is_empty() {
+            None
+        } else {
+            Some(secret)
+        }
+    } else {
+        secret
+    };
+    let ts = match time.and_then(util::flexible_timestamp_parse) {
+        Some(ts) => ts,
+        None => return http::bad_request("Missing or invalid time".into()),
+    };
+    let max_gap = match interpolation_gap(&config, maxgap) {
+        Ok(max_gap) => max_gap,
+        Err(e) => return e,
+    };
+
+    let db = db::DBQuery(&db.0, &config.secrets);
+    match db.retrieve_around(client.as_str(), &secret, ts) {
+        Ok(points) => {
+            let pos = geo::position_at(&points, ts, max_gap);
+            http::return_json(&types::PositionAt::new(
+                client,
+                ts,
+                pos.point,
+                pos.nearest_fix,
+                None,
+            ))
+        }
+        Err(e) => http::db_error(e),
+    }
+}
+
+/// The `maxgap` parameter in seconds, or the configured maximum gap.
+fn interpolation_gap(
+    config: &config::Config,
+    maxgap: Option<i64>,
+) -> Result<chrono::Duration, http::GeoHubResponder> {
+    match maxgap.unwrap_or(config.max_interpolation_gap) {
+        gap if gap >= 0 && gap <= geo::MAX_INTERPOLATION_GAP => Ok(chrono::Duration::seconds(gap)),
+        _ => Err(http::bad_request(format!(
+            "maxgap must be between 0 and {} seconds",
+            geo::MAX_INTERPOLATION_GAP
+        ))),
+    }
+}
+
+/// Find the position of a client at time `t`, using the points within `max_gap` of it.
+fn locate(
+    db: &db::DBQuery,
+    client: &str,
+    secret: &Option<String>,
+    t: chrono::DateTime<chrono::Utc>,
+    max_gap: chrono::Duration,
+) -> Result<geo::Interpolated, postgres::Error> {
+    let (from, to) = match (t.checked_sub_signed(max_gap), t.checked_add_signed(max_gap)) {
+        (Some(from), Some(to)) => (from, to),
+        _ => return Ok(geo::position_at(&[], t, max_gap)),
+    };
+    let points = db.retrieve(client, from, to, secret, 1 << 16, None, &None, false)?;
+    Ok(geo::position_at(&points, t, max_gap))
+}
+
+/// Geotag photos.
+///
+/// The body is either a JPEG file or a JSON `GeotagRequest` with photo timestamps. `offset` is
+/// the number of seconds the camera clock was ahead, `tz` the UTC offset of photo times without
+/// zone. If `format` is `jpeg`, an uploaded photo is returned with GPS tags; otherwise JSON.
+#[rocket::post(
+    "/geo/<client>/geotag?<secret>&<offset>&<tz>&<format>&<maxgap>",
+    data = "<body>"
+)]
+fn geotag(
+    db: db::DBConn,
+    config: rocket::State<config::Config>,
+    content_type: &rocket::http::ContentType,
+    client: String,
+    secret: Option<String>,
+    offset: Option<f64>,
+    tz: Option<String>,
+    format: Option<String>,
+    maxgap: Option<i64>,
+    body: rocket::data::Data,
+) -> http::GeoHubResponder {
+    if !ids::name_and_secret_acceptable(client.as_str(), secret.as_ref().map(|s| s.as_str())) {
+        return http::bad_request(
+            "You have supplied an invalid secret or client. Both must be ASCII alphanumeric strings."
+                .into(),
+        );
+    }
+    let secret = if let Some(secret) = secret {
+        if secret.is_empty() {
+            None
+        } else {
+            Some(secret)
+        }
+    } else {
+        secret
+    };
+    let tz = match tz {
+        Some(tz) => match geotag::parse_utc_offset(&tz) {
+            Some(tz) => Some(tz),
+            None => return http::bad_request(format!("Invalid time zone offset '{}'", tz)),
+        },
+        None => None,
+    };
+    let offset = offset.unwrap_or(0.);
+    if !(offset.abs() <= geotag::MAX_CLOCK_OFFSET) {
+        return http::bad_request(format!(
+            "offset must be at most {} seconds",
+            geotag::MAX_CLOCK_OFFSET
+        ));
+    }
+    let clock_offset = chrono::Duration::milliseconds((offset * 1000.) as i64);
+    let max_gap = match interpolation_gap(&config, maxgap) {
+        Ok(max_gap) => max_gap,
+        Err(e) => return e,
+    };
+    let db = db::DBQuery(&db.0, &config.secrets);
+
+    // Batch of timestamps.
+    if content_type.is_json() {
+        let request = match http::read_data(body, 1 << 20).and_then(|b| {
+            serde_json::from_str::<types::GeotagRequest>(&b)
+                .map_err(|e| http::bad_request(format!("Invalid request: {}", e)))
+        }) {
+            Ok(r) => r,
+            Err(e) => return e,
+        };
+        if request.timestamps.len() > geotag::MAX_TIMESTAMPS {
+            return http::bad_request(format!(
+                "At most {} timestamps are allowed per request",
+                geotag::MAX_TIMESTAMPS
+            ));
+        }
+        let mut photos = Vec::with_capacity(request.timestamps.len());
+        for ts in request.timestamps {
+            let t = match geotag::parse_photo_time(&ts).map(|pt| pt.to_utc(tz, clock_offset)) {
+                Some(Some(t)) => t,
+                invalid => {
+                    let error = match invalid {
+                        None => "Invalid timestamp",
+                        _ => "Timestamp out of range",
+                    };
+                    photos.push(types::PhotoPosition::new(
+                        Some(ts),
+                        None,
+                        None,
+                        None,
+                        Some(error.into()),
+                    ));
+                    continue;
+                }
+            };
+            match locate(&db, &client, &secret, t, max_gap) {
+                Ok(pos) => photos.push(types::PhotoPosition::new(
+                    Some(ts),
+                    Some(t),
+                    pos.point,
+                    pos.nearest_fix,
+                    None,
+                )),
+                Err(e) => return http::db_error(e),
+            }
+        }
+        return http::return_json(&types::GeotagResult::new(client, photos));
+    }
+
+    // Single JPEG upload.
+    let jpeg = match http::read_bytes(body, geotag::MAX_PHOTO_SIZE) {
+        Ok(jpeg) => jpeg,
+        Err(e) => return e,
+    };
+    let pt = match geotag::jpeg_photo_time(&jpeg) {
+        Ok(pt) => pt,
+        Err(e) => return http::bad_request(e),
+    };
+    let photo_time = pt.local.format("%Y:%m:%d %H:%M:%S").to_string();
+    let t = match pt.to_utc(tz, clock_offset) {
+        Some(t) => t,
+        None => return http::bad_request(format!("Photo time {} out of range", photo_time)),
+    };
+    let pos = match locate(&db, &client, &secret, t, max_gap) {
+        Ok(pos) => pos,
+        Err(e) => return http::db_error(e),
+    };
+
+    if format.as_ref().map(|f| f.as_str()) == Some("jpeg") {
+        match pos.point {
+            Some(point) => match geotag::write_gps(&jpeg, &point) {
+                Ok(tagged) => http::return_jpeg(tagged),
+                Err(e) => http::bad_request(e),
+            },
+            None => http::bad_request(format!("No position known at {}", t.to_rfc3339())),
+        }
+    } else {
+        let photo =
+            types::PhotoPosition::new(Some(photo_time), Some(t), pos.point, pos.nearest_fix, None);
+        http::return_json(&types::GeotagResult::new(client, vec![photo]))
+    }
+}
+
+/// Find out when two or more clients were close to each other.
+#[rocket::post("/geo/encounters", data = "<body>")]
+fn encounters(
+    db: db::DBConn,
+    config: rocket::State<config::Config>,
+    body: rocket_contrib::json::Json<types::EncounterRequest>,
+) -> http::GeoHubResponder {
+    // Upper limit for the number of positions interpolated, over all clients.
+    const MAX_SAMPLES: i64 = 1 << 18;
+    const MAX_CLIENTS: usize = 16;
+    // Upper limit for the sampling interval in seconds.
+    const MAX_STEP: i64 = 24 * 3600;
+
+    let req = body.into_inner();
+    if req.clients.len() < 2 || req.clients.len() > MAX_CLIENTS {
+        return http::bad_request(format!(
+            "Between two and {} clients are required",
+            MAX_CLIENTS
+        ));
+    }
+    for cs in req.clients.iter() {
+        if !ids::name_and_secret_acceptable(
+            cs.client.as_str(),
+            cs.secret.as_ref().map(|s| s.as_str()),
+        ) {
+            return http::bad_request(
+                "You have supplied an invalid secret or client. Both must be ASCII alphanumeric strings."
+                    .into(),
+            );
+        }
+    }
+    let from_ts =
+        req.from
+            .and_then(util::flexible_timestamp_parse)
+            .unwrap_or(chrono::DateTime::from_utc(
+                chrono::NaiveDateTime::from_timestamp(0, 0),
+                chrono::Utc,
+            ));
+    let to_ts = req
+        .to
+        .and_then(util::flexible_timestamp_parse)
+        .unwrap_or(chrono::Utc::now());
+    let step = match req.step.unwrap_or(10) {
+        step if step >= 1 && step <= MAX_STEP => chrono::Duration::seconds(step),
+        _ => return http::bad_request(format!("step must be between 1 and {} seconds", MAX_STEP)),
+    };
+    let max_gap = chrono::Duration::seconds(config.max_interpolation_gap);
+
+    let db = db::DBQuery(&db.0, &config.secrets);
+    let mut tracks = Vec::with_capacity(req.clients.len());
+    for cs in req.clients.iter() {
+        let secret = cs.secret.clone().filter(|s| !s.is_empty());
+        match db.retrieve(
+            &cs.client,
+            from_ts,
+            to_ts,
+            &secret,
+            1 << 20,
+            None,
+            &None,
+            false,
+        ) {
+            Ok(points) => tracks.push(points),
+            Err(e) => return http::db_error(e),
+        }
+    }
+
+    let first = tracks
+        .iter()
+        .filter_map(|t| t.first())
+        .map(|p| p.time)
+        .min();
+    let last = tracks.iter().filter_map(|t| t.last()).map(|p| p.time).max();
+    if let (Some(first), Some(last)) = (first, last) {
+        let span = std::cmp::min(to_ts, last) - std::cmp::max(from_ts, first);
+        let samples = span.num_seconds() / step.num_seconds() + 1;
+        if samples.saturating_mul(tracks.len() as i64) > MAX_SAMPLES {
+            return http::bad_request(
+                "Time window too large for step and number of clients; use a larger step or a \
+                 smaller window"
+                    .into(),
+            );
+        }
+    }
+
+    let mut result = types::Encounters::new();
+    for enc in geo::encounters(&tracks, from_ts, to_ts, step, req.distance, max_gap) {
+        let (a, b) = enc.tracks;
+        result.push(
+            req.clients[a].client.clone(),
+            req.clients[b].client.clone(),
+            enc.from,
+            enc.to,
+            enc.min_distance,
+        );
+    }
+    http::return_json(&result)
+}
+
+/// Create a proximity rule: Get notified when `other` is within `radius` meters of `client`.
+///
+/// `othersecret` is the secret of the points of `other` that are to be considered.
+#[rocket::post("/geo/<client>/proximity?<secret>&<other>&<othersecret>&<radius>")]
+fn proximity_create(
+    db: db::DBConn,
+    config: rocket::State<config::Config>,
+    client: String,
+    secret: Option<String>,
+    other: String,
+    othersecret: Option<String>,
+    radius: f64,
+) -> http::GeoHubResponder {
+    if !ids::name_and_secret_acceptable(client.as_str(), secret.as_ref().map(|s| s.as_str()))
+        || !ids::name_and_secret_acceptable(
+            other.as_str(),
+            othersecret.as_ref().map(|s| s.as_str()),
+        )
+    {
+        return http::bad_request(
+            "You have supplied an invalid secret or client. Both must be ASCII alphanumeric strings."
+                .into(),
+        );
+    }
+    if client == other {
+        return http::bad_request("client and other must be different".into());
+    }
+    if !(radius.is_finite() && radius > 0.) {
+        return http::bad_request("radius must be a positive number of meters".into());
+    }
+    let secret = secret.filter(|s| !s.is_empty());
+    let othersecret = othersecret.filter(|s| !s.is_empty());
+    let db = db::DBQuery(&db.0, &config.secrets);
+    match db.create_proximity_rule(&client, &secret, &other, &othersecret, radius) {
+        Ok(id) => http::return_json(&types::ProximityRule {
+            id: id,
+            client: client,
+            other: other,
+            radius: radius,
+            inside: false,
+        }),
+        Err(e) => http::db_error(e),
+    }
+}
+
+/// List the proximity rules of a client and secret.
+#[rocket::get("/geo/<client>/proximity?<secret>")]
+fn proximity_list(
+    db: db::DBConn,
+    config: rocket::State<config::Config>,
+    client: String,
+    secret: Option<String>,
+) -> http::GeoHubResponder {
+    if !ids::name_and_secret_acceptable(client.as_str(), secret.as_ref().map(|s| s.as_str())) {
+        return http::bad_request(
+            "You have supplied an invalid secret or client. Both must be ASCII alphanumeric strings."
+                .into(),
+        );
+    }
+    let secret = secret.filter(|s| !s.is_empty());
+    let db = db::DBQuery(&db.0, &config.secrets);
+    match db.proximity_rules(&client, &secret) {
+        Ok(rules) => http::return_json(&rules),
+        Err(e) => http::db_error(e),
+    }
+}
+
+/// Delete a proximity rule.
+#[rocket::delete("/geo/<client>/proximity/<id>?<secret>")]
+fn proximity_delete(
+    db: db::DBConn,
+    config: rocket::State<config::Config>,
+    client: String,
+    id: i32,
+    secret: Option<String>,
+) -> http::GeoHubResponder {
+    if !ids::name_and_secret_acceptable(client.as_str(), secret.as_ref().map(|s| s.as_str())) {
+        return http::bad_request(
+            "You have supplied an invalid secret or client. Both must be ASCII alphanumeric strings."
+                .into(),
+        );
+    }
+    let secret = secret.filter(|s| !s.is_empty());
+    let db = db::DBQuery(&db.0, &config.secrets);
+    match db.delete_proximity_rule(&client, &secret, id) {
+        Ok(0) => http::bad_request(format!(
+            "No proximity rule {} for this client and secret",
+            id
+        )),
+        Ok(_) => http::return_ok("".into()),
+        Err(e) => http::db_error(e),
+    }
+}
+
+/// Wait for a proximity event of any of the rules of a client and secret.
+#[rocket::get("/geo/<client>/proximity/live?<secret>&<timeout>")]
+fn proximity_live(
+    db: db::DBConn,
+    config: rocket::State<config::Config>,
+    notify_manager: rocket::State<notifier::NotifyManager>,
+    client: String,
+    secret: Option<String>,
+    timeout: Option<u64>,
+) -> http::GeoHubResponder {
+    if !ids::name_and_secret_acceptable(client.as_str(), secret.as_ref().map(|s| s.as_str())) {
+        return http::bad_request(
+            "You have supplied an invalid secret or client. Both must be ASCII alphanumeric strings."
+                .into(),
+        );
+    }
+    let secret = secret.filter(|s| !s.is_empty());
+    let rules = {
+        let db = db::DBQuery(&db.0, &config.secrets);
+        match db.proximity_rules(&client, &secret) {
+            Ok(rules) => rules.into_iter().map(|r| r.id).collect::<Vec<i32>>(),
+            Err(e) => return http::db_error(e),
+        }
+    };
+    // Don't hold on to the database connection while waiting.
+    drop(db);
+    if rules.is_empty() {
+        return http::bad_request("No proximity rules for this client and secret".into());
+    }
+    http::return_json(&notify_manager.wait_for_proximity_event(client, rules, timeout))
+}
+
 /// Ingest geo data.
 
+/// Upgrade legacy hashes of `secret` (see `db::DBQuery::upgrade_secret`) in `trans`, so that they
+/// are committed or rolled back together with the points stored in it. A failed upgrade doesn't
+/// abort the transaction. Returns whether the session may be marked as upgraded once `trans` is
+/// committed.
+fn upgrade_secret(
+    db: &db::DBQuery,
+    trans: &postgres::transaction::Transaction,
+    name: &str,
+    secret: &Option<String>,
+) -> Result<bool, postgres::Error> {
+    let savepoint = trans.savepoint("upgrade")?;
+    match db.upgrade_secret(name, secret) {
+        Ok(_) => {
+            savepoint.commit()?;
+            Ok(true)
+        }
+        // Dropping the savepoint rolls it back.
+        Err(e) => {
+            log::warn!("Couldn't upgrade secret hashes: {}", e);
+            Ok(false)
+        }
+    }
+}
+
 /// Ingest individual points by URL query string.
 ///
 /// time is like 2020-11-30T20:12:36.444Z (ISO 8601). By default, server time is set.
 /// secret can be used to protect points.
 #[rocket::post(
-    "/geo/<name>/log?<lat>&<longitude>&<time>&<s>&<ele>&<secret>&<accuracy>&<unit>&<datesecret>",
+    "/geo/<name>/log?<lat>&<longitude>&<time>&<s>&<ele>&<secret>&<accuracy>&<unit>&<datesecret>&<key>",
     data = "<note>"
 )]
 fn log(
     db: db::DBConn,
+    config: rocket::State<config::Config>,
     notify_manager: rocket::State<notifier::NotifyManager>,
+    limits: rocket::State<ratelimit::Limits>,
+    ip: ratelimit::ClientIp,
     name: String,
     lat: f64,
     longitude: f64,
@@ -194,6 +1003,7 @@ fn log(
     accuracy: Option<f64>,
     unit: Option<String>,
     datesecret: Option<bool>,
+    key: Option<String>,
     note: rocket::data::Data,
 ) -> http::GeoHubResponder {
     // Check that secret and client name are legal.
@@ -203,6 +1013,16 @@ fn log(
                 .into(),
         );
     }
+    if !key
+        .as_ref()
+        .map(|k| ids::point_key_acceptable(k))
+        .unwrap_or(true)
+    {
+        return http::bad_request(
+            "You have supplied an invalid point key. It must be at most 128 characters from A-Z, a-z, 0-9 and -_:.+"
+                .into(),
+        );
+    }
     let mut ts = chrono::Utc::now();
     if let Some(time) = time {
         ts = util::flexible_timestamp_parse(time).unwrap_or(ts);
@@ -219,7 +1039,7 @@ fn log(
     } else {
         secret
     };
-    let db = db::DBQuery(&db.0);
+    let db = db::DBQuery(&db.0, &config.secrets);
 
     // Length-limit notes.
     let note = match http::read_data(note, 4096) {
@@ -252,24 +1072,69 @@ fn log(
         accuracy: accuracy,
         note: note,
     };
-    if let Err(e) = db.log_geopoint(name.as_str(), &secret, &point) {
-        return http::server_error(e.to_string());
+    if let Err(e) = point.validate() {
+        return http::bad_request(e);
+    }
+    if let Err(wait) = limits.check(name.as_str(), &ip.0, 1) {
+        return http::too_many_requests(wait);
+    }
+    // Checking for duplicates and storing the point must not be interleaved with another
+    // request for the same client, so with `dedupe_points` both happen under a lock. Keyed
+    // points take it as well, so that `rename` can check for colliding keys.
+    let trans = match db.0.transaction() {
+        Ok(trans) => trans,
+        Err(e) => return http::db_error(e),
+    };
+    if config.dedupe_points || key.is_some() {
+        if let Err(e) = db.lock_client(name.as_str()) {
+            return http::db_error(e);
+        }
+    }
+    // Points logged with a legacy hash of the secret are rehashed on the way.
+    let upgraded = match upgrade_secret(&db, &trans, name.as_str(), &secret) {
+        Ok(upgraded) => upgraded,
+        Err(e) => return http::db_error(e),
+    };
+    let id = match db.log_geopoint(name.as_str(), &secret, &point, &key, config.dedupe_points) {
+        Ok(id) => id,
+        Err(e) => return http::db_error(e),
+    };
+    if let Err(e) = trans.commit() {
+        return http::db_error(e);
+    }
+    if upgraded {
+        config.secrets.mark_upgraded(name.as_str(), &secret);
     }
+    if id.is_none() {
+        // Stored before, so waiters have already been notified.
+        return http::return_ok("".into());
+    }
+    metrics::METRICS.points_ingested(name.as_str(), 1);
     if let Err(e) = notify_manager.send_notification(&db, name.as_str(), &secret, Some(1)) {
-        eprintln!("Couldn't send notification: {}", e);
+        log::warn!("Couldn't send notification: {}", e);
+    }
+    if let Err(e) = proximity::evaluate(&db, &notify_manager, name.as_str(), &secret, &point) {
+        log::warn!("Couldn't check proximity rules: {}", e);
     }
     http::return_ok("".into())
 }
 
 /// Ingest GeoJSON.
-#[rocket::post("/geo/<name>/logjson?<secret>&<datesecret>&<unit>", data = "<body>")]
+#[rocket::post(
+    "/geo/<name>/logjson?<secret>&<datesecret>&<unit>&<atomic>",
+    data = "<body>"
+)]
 fn log_json(
     db: db::DBConn,
+    config: rocket::State<config::Config>,
     notify_manager: rocket::State<notifier::NotifyManager>,
+    limits: rocket::State<ratelimit::Limits>,
+    ip: ratelimit::ClientIp,
     name: String,
     secret: Option<String>,
     datesecret: Option<bool>,
     unit: Option<String>,
+    atomic: Option<bool>,
     body: rocket_contrib::json::Json<types::LogLocations>,
 ) -> http::GeoHubResponder {
     // Check that secret and client name are legal.
@@ -291,14 +1156,29 @@ fn log_json(
     } else {
         secret
     };
-    let db = db::DBQuery(&db.0);
+    let db = db::DBQuery(&db.0, &config.secrets);
 
     let geofeats = body.into_inner().locations;
-    let nrows = geofeats.len() as i64;
+    if geofeats.len() > limits.max_batch {
+        log::warn!(
+            "Rejected {} points of client {}: more than {} per request",
+            geofeats.len(),
+            name,
+            limits.max_batch
+        );
+        return http::payload_too_large(format!(
+            "At most {} points can be logged at once",
+            limits.max_batch
+        ));
+    }
+    let atomic = atomic.unwrap_or(true);
 
-    // Due to prepared statements, this isn't as bad as it looks.
-    let mut errs = vec![];
-    for feat in geofeats {
+    // Validate all points before storing any.
+    let mut points = Vec::with_capacity(geofeats.len());
+    let mut keys = Vec::with_capacity(geofeats.len());
+    let mut results = Vec::with_capacity(geofeats.len());
+    for (i, feat) in geofeats.into_iter().enumerate() {
+        let key = feat.point_key();
         let mut point = types::geopoint_from_feature(feat);
 
         if let (Some(u), Some(speed)) = (unit.as_ref(), point.spd) {
@@ -308,28 +1188,367 @@ fn log_json(
             }
         }
 
-        if let Err(e) = db.log_geopoint(name.as_str(), &secret, &point) {
-            errs.push(e);
+        let valid = match key.as_ref() {
+            Some(k) if !ids::point_key_acceptable(k) => Err(format!("invalid point key {:?}", k)),
+            _ => point.validate(),
+        };
+        results.push(types::IngestResult {
+            index: i,
+            id: None,
+            duplicate: false,
+            error: valid.err(),
+        });
+        points.push(point);
+        keys.push(key);
+    }
+    if atomic && results.iter().any(|r| r.error.is_some()) {
+        return http::bad_request_json(&types::IngestReport::new(atomic, results));
+    }
+    // Only valid points count against the rate limits, and those that can't be stored are
+    // refunded below.
+    let rejected = results.iter().filter(|r| r.error.is_some()).count();
+    let accepted = results.len() - rejected;
+    if let Err(wait) = limits.check(name.as_str(), &ip.0, accepted) {
+        return http::too_many_requests(wait);
+    }
+
+    let trans = match db.0.transaction() {
+        Ok(trans) => trans,
+        Err(e) => return http::db_error(e),
+    };
+    // See `log`.
+    if config.dedupe_points || keys.iter().any(Option::is_some) {
+        if let Err(e) = db.lock_client(name.as_str()) {
+            return http::db_error(e);
+        }
+    }
+    let upgraded = match upgrade_secret(&db, &trans, name.as_str(), &secret) {
+        Ok(upgraded) => upgraded,
+        Err(e) => return http::db_error(e),
+    };
+    // Due to prepared statements, this isn't as bad as it looks.
+    for ((point, key), result) in points.iter().zip(keys.iter()).zip(results.iter_mut()) {
+        if result.error.is_some() {
+            continue;
+        }
+        // Unless atomic, each point is stored in a savepoint, so that a failed insert doesn't
+        // abort the transaction.
+        let savepoint = if atomic {
+            None
+        } else {
+            match trans.savepoint("point") {
+                Ok(savepoint) => Some(savepoint),
+                Err(e) => return http::db_error(e),
+            }
+        };
+        match db.log_geopoint(name.as_str(), &secret, point, key, config.dedupe_points) {
+            Ok(id) => {
+                if let Some(savepoint) = savepoint {
+                    if let Err(e) = savepoint.commit() {
+                        return http::db_error(e);
+                    }
+                }
+                result.id = id;
+                result.duplicate = id.is_none();
+            }
+            Err(e) => {
+                metrics::METRICS.db_error();
+                log::error!("Couldn't write point {} of {}: {}", result.index, name, e);
+                result.error = Some(e.to_string());
+                if atomic {
+                    break;
+                }
+            }
+        }
+    }
+    let failed = results.iter().filter(|r| r.error.is_some()).count() - rejected;
+    if failed > 0 {
+        limits.refund(name.as_str(), &ip.0, if atomic { accepted } else { failed });
+    }
+    if atomic && failed > 0 {
+        // Dropping the transaction rolls it back.
+        drop(trans);
+        for result in results.iter_mut() {
+            result.id = None;
+            result.duplicate = false;
+        }
+        return http::server_error_json(&types::IngestReport::new(atomic, results));
+    }
+    if let Err(e) = trans.commit() {
+        return http::db_error(e);
+    }
+    if upgraded {
+        config.secrets.mark_upgraded(name.as_str(), &secret);
+    }
+
+    let stored = points
+        .into_iter()
+        .zip(results.iter())
+        .filter(|(_, r)| r.id.is_some())
+        .map(|(p, _)| p)
+        .collect::<Vec<types::GeoPoint>>();
+    metrics::METRICS.points_ingested(name.as_str(), stored.len() as u64);
+    if !stored.is_empty() {
+        // Only notify once.
+        let nrows = Some(stored.len() as i64);
+        if let Err(e) = notify_manager.send_notification(&db, name.as_str(), &secret, nrows) {
+            log::warn!("Couldn't send notification: {}", e);
+        }
+    }
+    // Most recent point, for checking proximity rules.
+    if let Some(point) = stored.into_iter().max_by_key(|p| p.time) {
+        if let Err(e) = proximity::evaluate(&db, &notify_manager, name.as_str(), &secret, &point) {
+            log::warn!("Couldn't check proximity rules: {}", e);
+        }
+    }
+
+    http::return_json(&types::IngestReport::new(atomic, results))
+}
+
+/// Re-emit the points of another client (or session) into `client`, paced like the original and
+/// with shifted timestamps. Returns immediately; the replay happens in the background.
+#[rocket::post("/geo/<client>/replay?<secret>", data = "<body>")]
+fn replay(
+    db: db::DBConn,
+    config: rocket::State<config::Config>,
+    notify_manager: rocket::State<notifier::NotifyManager>,
+    client: String,
+    secret: Option<String>,
+    body: rocket_contrib::json::Json<types::ReplayRequest>,
+) -> http::GeoHubResponder {
+    if !ids::name_and_secret_acceptable(client.as_str(), secret.as_ref().map(|s| s.as_str())) {
+        return http::bad_request(
+            "You have supplied an invalid secret or client. Both must be ASCII alphanumeric strings."
+                .into(),
+        );
+    }
+    let secret = if let Some(secret) = secret {
+        if secret.is_empty() {
+            None
+        } else {
+            Some(secret)
         }
+    } else {
+        secret
+    };
+    let req = body.into_inner();
+    let speed = req.speed.unwrap_or(1.);
+    if !(speed >= replay::MIN_SPEED && speed <= replay::MAX_SPEED) {
+        return http::bad_request(format!(
+            "speed must be between {} and {}",
+            replay::MIN_SPEED,
+            replay::MAX_SPEED
+        ));
     }
+    let points = match common_retrieve(
+        db,
+        &config,
+        req.source,
+        req.source_secret,
+        req.from,
+        req.to,
+        None,
+        None,
+        None,
+    ) {
+        Ok(points) => points,
+        Err(e) => return e,
+    };
 
-    // Only notify once.
-    if let Err(e) = notify_manager.send_notification(&db, name.as_str(), &secret, Some(nrows)) {
-        eprintln!("Couldn't send notification: {}", e);
+    let r = replay::Replay {
+        client: client.clone(),
+        secret: secret,
+        points: points,
+        speed: speed,
+        live_only: req.live_only,
+    };
+    let started = types::ReplayStarted::new(
+        client,
+        r.points.len(),
+        chrono::Utc::now() + r.duration(),
+        r.live_only,
+    );
+    let running = replay::start(
+        r,
+        config.database_url.clone(),
+        config.secrets.clone(),
+        notify_manager.inner().clone(),
+        config.max_replays,
+    );
+    if !running {
+        log::warn!(
+            "Refusing replay: {} replays running already",
+            config.max_replays
+        );
+        return http::too_many_requests(std::time::Duration::from_secs(60));
     }
+    http::return_json(&started)
+}
 
-    if errs.is_empty() {
-        http::return_ok("".into())
+/// Move the points of a session to client `to` ("rename"), or into an existing session with
+/// `merge=true`. Points keep their IDs, and live waiters of the target receive the latest moved
+/// points.
+///
+/// The session is identified by `secret`. Its points keep their secret, unless `tosecret` is given
+/// (`tosecret=` makes them public). Moving public points, or all sessions of a client (`all=true`,
+/// keeping every secret), requires the `admin_token` from Rocket.toml as `token`.
+#[rocket::post("/geo/<client>/rename?<secret>&<to>&<tosecret>&<merge>&<all>&<token>")]
+fn rename(
+    db: db::DBConn,
+    config: rocket::State<config::Config>,
+    client: String,
+    secret: Option<String>,
+    to: String,
+    tosecret: Option<String>,
+    merge: Option<bool>,
+    all: Option<bool>,
+    token: Option<String>,
+) -> http::GeoHubResponder {
+    if !ids::name_and_secret_acceptable(client.as_str(), secret.as_ref().map(|s| s.as_str()))
+        || !ids::name_and_secret_acceptable(to.as_str(), tosecret.as_ref().map(|s| s.as_str()))
+    {
+        return http::bad_request(
+            "You have supplied an invalid secret or client. Both must be ASCII alphanumeric strings."
+                .into(),
+        );
+    }
+    let admin = config.is_admin(&token);
+    if token.is_some() && !admin {
+        return http::forbidden("Invalid admin token".into());
+    }
+    let all = all.unwrap_or(false);
+    let merge = merge.unwrap_or(false);
+    let secret = secret.filter(|s| !s.is_empty());
+    // Absent: keep the secret. Empty: make public.
+    let tosecret = match tosecret {
+        Some(s) => Some(s).filter(|s| !s.is_empty()),
+        None => secret.clone(),
+    };
+    if (all || secret.is_none()) && !admin {
+        return http::forbidden(
+            "Moving public points or whole clients requires the admin token".into(),
+        );
+    }
+    if client == to && (all || secret == tosecret) {
+        return http::bad_request("Source and target are the same".into());
+    }
+
+    // Notifications sent in the transaction are only delivered after committing.
+    let trans = match db.0.transaction() {
+        Ok(trans) => trans,
+        Err(e) => return http::db_error(e),
+    };
+    let dbq = db::DBQuery(&db.0, &config.secrets);
+    let exists = if all {
+        dbq.client_exists(&to)
     } else {
-        let errstring = errs
-            .into_iter()
-            .take(10)
-            .map(|e| e.to_string())
-            .collect::<Vec<String>>()
-            .join(";");
-        eprintln!("Couldn't write points: {}", errstring);
-        http::server_error(errstring)
+        dbq.session_exists(&to, &tosecret)
+    };
+    match exists {
+        Ok(true) if !merge => {
+            return http::bad_request(format!(
+                "{} already has points; use merge=true to add to them",
+                to
+            ))
+        }
+        Ok(_) => {}
+        Err(e) => return http::db_error(e),
+    }
+    // Point keys are unique per session, so points whose key the target session uses already
+    // can't be moved. The lock keeps points with such keys from being logged to the target meanwhile.
+    if let Err(e) = dbq.lock_client(&to) {
+        return http::db_error(e);
     }
+    let session = if all {
+        None
+    } else {
+        Some((&secret, &tosecret))
+    };
+    match dbq.count_key_conflicts(&client, session, &to) {
+        Ok(0) => {}
+        Ok(n) => {
+            return http::conflict(format!(
+                "{} points have a key that the same session of {} uses already; delete them from either client first",
+                n, to
+            ))
+        }
+        Err(e) => return http::db_error(e),
+    }
+    let moved = if all {
+        dbq.move_client(&client, &to, notifier::MAX_NOTIFY_ROWS)
+    } else {
+        dbq.move_session(&client, &secret, &to, &tosecret, notifier::MAX_NOTIFY_ROWS)
+    };
+    let (moved, latest) = match moved {
+        Ok((0, _)) => return http::not_found("No points to move".into()),
+        Ok(moved) => moved,
+        Err(e) => return http::db_error(e),
+    };
+    // Moved points keep their IDs, which are likely below the last one waiters have seen, so
+    // they are sent along instead of being announced as new. Secrets of other sessions are only
+    // known as hashes, so for whole clients only the public session is notified (`move_client`
+    // returns only public points).
+    let notify_secret = if all { None } else { tosecret };
+    if let Err(e) = notifier::notify_points(&dbq, &to, &notify_secret, &latest) {
+        return http::db_error(e);
+    }
+    if let Err(e) = trans.commit() {
+        return http::db_error(e);
+    }
+    http::return_json(&types::PointsMoved::new(client, to, moved))
+}
+
+/// Liveness check: answers as long as the process is serving requests.
+#[rocket::get("/geo/health")]
+fn health() -> http::GeoHubResponder {
+    http::return_ok("OK".into())
+}
+
+/// Readiness check: whether the database, the notifier thread and the assets are usable.
+/// Answers with 503 if not.
+#[rocket::get("/geo/ready")]
+fn ready(
+    db: Option<db::DBConn>,
+    notify_manager: rocket::State<notifier::NotifyManager>,
+) -> http::GeoHubResponder {
+    let database = match db {
+        Some(db) => {
+            db.0.execute("SELECT 1", &[])
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        }
+        None => Err("no connection available from pool".into()),
+    };
+    let assets = if std::path::Path::new("assets/").is_dir() {
+        Ok(())
+    } else {
+        Err("assets/ not found in working directory".into())
+    };
+    let readiness = types::Readiness::new(
+        types::ReadinessCheck::from_result(database),
+        types::ReadinessCheck::from_result(notify_manager.health().check()),
+        types::ReadinessCheck::from_result(assets),
+        notify_manager.health().status(),
+    );
+    if readiness.ready {
+        http::return_json(&readiness)
+    } else {
+        http::unavailable_json(&readiness)
+    }
+}
+
+/// Metrics in the Prometheus text format. They name clients, so the `admin_token` is required.
+#[rocket::get("/geo/metrics?<token>")]
+fn export_metrics(
+    config: rocket::State<config::Config>,
+    notify_manager: rocket::State<notifier::NotifyManager>,
+    token: Option<String>,
+) -> http::GeoHubResponder {
+    if !config.is_admin(&token) {
+        return http::forbidden("Metrics require the admin token".into());
+    }
+    let waiters = notify_manager.health().status().waiters;
+    http::return_ok(metrics::METRICS.render(waiters))
 }
 
 /// Serve static files.
@@ -343,23 +1562,33 @@ fn assets(
 }
 
 fn main() {
-    let (send, recv) = mpsc::channel();
-    let send = notifier::NotifyManager(notifier::SendableSender {
-        sender: Arc::new(Mutex::new(send)),
-    });
-
+    logging::init();
     rocket::ignite()
+        .attach(logging::RequestLogger)
         .attach(db::DBConn::fairing())
-        .manage(send)
+        .attach(metrics::RequestMetrics)
+        .attach(rocket::fairing::AdHoc::on_attach(
+            "GeoHub Config",
+            |rocket| {
+                let config = config::Config::from_rocket(rocket.config());
+                logging::configure(config.log_json, config.log_level);
+                if !config.secrets.has_key() {
+                    log::warn!("No secret_key configured; secrets are stored as unkeyed hashes.");
+                }
+                metrics::METRICS.set_client_limit(config.metrics_client_limit);
+                let limits = ratelimit::Limits::from_rocket(rocket.config());
+                Ok(rocket.manage(config).manage(limits))
+            },
+        ))
         .attach(rocket::fairing::AdHoc::on_attach(
             "Database Notifications",
             |rocket| {
-                let dbconfig =
-                    rocket_contrib::databases::database_config("geohub", &rocket.config()).unwrap();
-                let url = dbconfig.url;
-                let conn = postgres::Connection::connect(url, postgres::TlsMode::None).unwrap();
-                std::thread::spawn(move || notifier::live_notifier_thread(recv, conn));
-                Ok(rocket)
+                let config = rocket.state::<config::Config>().unwrap();
+                let manager = notifier::NotifyManager::start(
+                    config.database_url.clone(),
+                    config.secrets.clone(),
+                );
+                Ok(rocket.manage(manager))
             },
         ))
         .mount(
@@ -369,8 +1598,25 @@ fn main() {
                 log_json,
                 retrieve_json,
                 retrieve_gpx,
+                retrieve_svg,
+                retrieve_png,
+                retrieve_profile,
                 retrieve_last,
                 retrieve_live,
+                retrieve_at,
+                retrieve_heatmap,
+                tiles,
+                geotag,
+                encounters,
+                proximity_create,
+                proximity_list,
+                proximity_delete,
+                proximity_live,
+                replay,
+                rename,
+                export_metrics,
+                health,
+                ready,
                 assets
             ],
         )
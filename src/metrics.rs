@@ -0,0 +1,390 @@
+//! Counters and histograms of the GeoHub server, exported at `/geo/metrics` in the Prometheus
+//! text format.
+//!
+//! All metrics live in the process-wide `METRICS`, so that they can be recorded anywhere without
+//! passing state around.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time;
+
+pub static METRICS: Metrics = Metrics::new();
+
+/// Clients beyond the client limit are counted under this label.
+const OTHER_CLIENTS: &str = "_other";
+
+/// Upper bounds of latency buckets, in seconds.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1., 2.5, 5., 10., 30.,
+];
+/// Upper bounds of result size buckets, in points.
+const SIZE_BUCKETS: &[f64] = &[0., 1., 10., 100., 1000., 10000., 65536., 1048576.];
+
+/// Cumulative histogram with fixed buckets.
+struct Histogram {
+    bounds: &'static [f64],
+    /// Observations per bucket (not cumulative); the last one counts observations above all
+    /// bounds.
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+impl Histogram {
+    const fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram {
+            bounds: bounds,
+            counts: Vec::new(),
+            sum: 0.,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if self.counts.is_empty() {
+            self.counts = vec![0; self.bounds.len() + 1];
+        }
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|b| value <= *b)
+            .unwrap_or(self.bounds.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+    }
+
+    /// Append the samples of this histogram. `labels` is empty or a list of `key="value"` pairs.
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (i, bound) in self.bounds.iter().enumerate() {
+            cumulative += self.counts.get(i).copied().unwrap_or(0);
+            writeln!(
+                out,
+                "{}_bucket{{{}{}le=\"{}\"}} {}",
+                name, labels, sep, bound, cumulative
+            )
+            .unwrap();
+        }
+        cumulative += self.counts.last().copied().unwrap_or(0);
+        writeln!(
+            out,
+            "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+            name, labels, sep, cumulative
+        )
+        .unwrap();
+        let braced = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels)
+        };
+        writeln!(out, "{}_sum{} {}", name, braced, self.sum).unwrap();
+        writeln!(out, "{}_count{} {}", name, braced, cumulative).unwrap();
+    }
+}
+
+/// Count and latency of requests to one route.
+struct RouteStats {
+    /// Requests by status code.
+    responses: BTreeMap<u16, u64>,
+    latency: Histogram,
+}
+
+pub struct Metrics {
+    /// At most this many clients are labelled individually in `points_ingested`.
+    client_limit: AtomicUsize,
+    points_ingested: Mutex<BTreeMap<String, u64>>,
+    db_errors: AtomicU64,
+    notifications: AtomicU64,
+    notification_latency: Mutex<Histogram>,
+    retrieve_size: Mutex<Histogram>,
+    /// By method and route.
+    routes: Mutex<BTreeMap<(String, String), RouteStats>>,
+}
+
+impl Metrics {
+    const fn new() -> Metrics {
+        Metrics {
+            client_limit: AtomicUsize::new(100),
+            points_ingested: Mutex::new(BTreeMap::new()),
+            db_errors: AtomicU64::new(0),
+            notifications: AtomicU64::new(0),
+            notification_latency: Mutex::new(Histogram::new(LATENCY_BUCKETS)),
+            retrieve_size: Mutex::new(Histogram::new(SIZE_BUCKETS)),
+            routes: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Limit the number of client labels. Points of further clients are counted together.
+    pub fn set_client_limit(&self, limit: usize) {
+        self.client_limit.store(limit, Ordering::SeqCst);
+    }
+
+    pub fn points_ingested(&self, client: &str, n: u64) {
+        let mut points = self.points_ingested.lock().unwrap();
+        if let Some(count) = points.get_mut(client) {
+            *count += n;
+            return;
+        }
+        // The overflow label doesn't count against the limit.
+        let labelled = points.len() - points.contains_key(OTHER_CLIENTS) as usize;
+        let label = if labelled < self.client_limit.load(Ordering::SeqCst) {
+            client
+        } else {
+            OTHER_CLIENTS
+        };
+        *points.entry(label.to_string()).or_insert(0) += n;
+    }
+
+    pub fn db_error(&self) {
+        self.db_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A database notification was processed, `latency` after it was sent (if known).
+    pub fn notification(&self, latency: Option<time::Duration>) {
+        self.notifications.fetch_add(1, Ordering::Relaxed);
+        if let Some(latency) = latency {
+            self.notification_latency
+                .lock()
+                .unwrap()
+                .observe(latency.as_secs_f64());
+        }
+    }
+
+    /// A retrieve request returned `points` points.
+    pub fn retrieved(&self, points: usize) {
+        self.retrieve_size.lock().unwrap().observe(points as f64);
+    }
+
+    pub fn request(&self, method: &str, route: &str, status: u16, latency: time::Duration) {
+        let mut routes = self.routes.lock().unwrap();
+        let stats = routes
+            .entry((method.to_string(), route.to_string()))
+            .or_insert_with(|| RouteStats {
+                responses: BTreeMap::new(),
+                latency: Histogram::new(LATENCY_BUCKETS),
+            });
+        *stats.responses.entry(status).or_insert(0) += 1;
+        stats.latency.observe(latency.as_secs_f64());
+    }
+
+    /// Render all metrics in the Prometheus text format. `waiters` is the number of live
+    /// waiters registered with the notifier.
+    pub fn render(&self, waiters: usize) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "geohub_points_ingested_total",
+            "counter",
+            "Points stored, by client.",
+        );
+        for (client, count) in self.points_ingested.lock().unwrap().iter() {
+            writeln!(
+                out,
+                "geohub_points_ingested_total{{client=\"{}\"}} {}",
+                escape(client),
+                count
+            )
+            .unwrap();
+        }
+
+        header(
+            &mut out,
+            "geohub_requests_total",
+            "counter",
+            "HTTP requests, by route and status.",
+        );
+        let routes = self.routes.lock().unwrap();
+        for ((method, route), stats) in routes.iter() {
+            for (status, count) in stats.responses.iter() {
+                writeln!(
+                    out,
+                    "geohub_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}",
+                    method,
+                    escape(route),
+                    status,
+                    count
+                )
+                .unwrap();
+            }
+        }
+        header(
+            &mut out,
+            "geohub_request_duration_seconds",
+            "histogram",
+            "HTTP request latency, by route.",
+        );
+        for ((method, route), stats) in routes.iter() {
+            stats.latency.render(
+                &mut out,
+                "geohub_request_duration_seconds",
+                &format!("method=\"{}\",route=\"{}\"", method, escape(route)),
+            );
+        }
+        drop(routes);
+
+        header(
+            &mut out,
+            "geohub_db_errors_total",
+            "counter",
+            "Failed database operations.",
+        );
+        writeln!(
+            out,
+            "geohub_db_errors_total {}",
+            self.db_errors.load(Ordering::Relaxed)
+        )
+        .unwrap();
+
+        header(
+            &mut out,
+            "geohub_live_waiters",
+            "gauge",
+            "Requests waiting for live updates or proximity events.",
+        );
+        writeln!(out, "geohub_live_waiters {}", waiters).unwrap();
+
+        header(
+            &mut out,
+            "geohub_notifications_total",
+            "counter",
+            "Database notifications processed by the notifier.",
+        );
+        writeln!(
+            out,
+            "geohub_notifications_total {}",
+            self.notifications.load(Ordering::Relaxed)
+        )
+        .unwrap();
+        header(
+            &mut out,
+            "geohub_notification_delivery_seconds",
+            "histogram",
+            "Time from sending a notification to answering its waiters.",
+        );
+        self.notification_latency.lock().unwrap().render(
+            &mut out,
+            "geohub_notification_delivery_seconds",
+            "",
+        );
+
+        header(
+            &mut out,
+            "geohub_retrieve_points",
+            "histogram",
+            "Number of points returned by retrieve requests.",
+        );
+        self.retrieve_size
+            .lock()
+            .unwrap()
+            .render(&mut out, "geohub_retrieve_points", "");
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, typ: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, typ).unwrap();
+}
+
+/// Escape a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Time of arrival of a request, for `RequestMetrics`.
+struct RequestStart(Option<time::Instant>);
+
+/// Fairing recording the count and latency of requests per route.
+pub struct RequestMetrics;
+
+impl rocket::fairing::Fairing for RequestMetrics {
+    fn info(&self) -> rocket::fairing::Info {
+        rocket::fairing::Info {
+            name: "Request Metrics",
+            kind: rocket::fairing::Kind::Request | rocket::fairing::Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut rocket::Request, _: &rocket::Data) {
+        request.local_cache(|| RequestStart(Some(time::Instant::now())));
+    }
+
+    fn on_response(&self, request: &rocket::Request, response: &mut rocket::Response) {
+        let start = request.local_cache(|| RequestStart(None));
+        if let Some(start) = start.0 {
+            // Label by route, not by URI, to keep the number of series bounded.
+            let route = request
+                .route()
+                .map(|r| r.uri.path().to_string())
+                .unwrap_or_else(|| "unmatched".into());
+            METRICS.request(
+                request.method().as_str(),
+                &route,
+                response.status().code,
+                start.elapsed(),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_render() {
+        let mut h = Histogram::new(&[1., 10.]);
+        for value in &[0.5, 5., 5., 20.] {
+            h.observe(*value);
+        }
+        let mut out = String::new();
+        h.render(&mut out, "latency", "route=\"/geo\"");
+        assert_eq!(
+            out,
+            "latency_bucket{route=\"/geo\",le=\"1\"} 1\n\
+             latency_bucket{route=\"/geo\",le=\"10\"} 3\n\
+             latency_bucket{route=\"/geo\",le=\"+Inf\"} 4\n\
+             latency_sum{route=\"/geo\"} 30.5\n\
+             latency_count{route=\"/geo\"} 4\n"
+        );
+    }
+
+    #[test]
+    fn test_histogram_render_empty() {
+        let h = Histogram::new(&[1.]);
+        let mut out = String::new();
+        h.render(&mut out, "size", "");
+        assert_eq!(
+            out,
+            "size_bucket{le=\"1\"} 0\n\
+             size_bucket{le=\"+Inf\"} 0\n\
+             size_sum 0\n\
+             size_count 0\n"
+        );
+    }
+
+    #[test]
+    fn test_points_ingested_client_limit() {
+        let metrics = Metrics::new();
+        metrics.set_client_limit(2);
+        for client in &["alice", "bob", "carol", "alice", "dave"] {
+            metrics.points_ingested(client, 1);
+        }
+        let out = metrics.render(0);
+        assert!(out.contains("geohub_points_ingested_total{client=\"alice\"} 2\n"));
+        assert!(out.contains("geohub_points_ingested_total{client=\"bob\"} 1\n"));
+        assert!(out.contains("geohub_points_ingested_total{client=\"_other\"} 2\n"));
+        assert!(!out.contains("carol"));
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
@@ -0,0 +1,553 @@
+use crate::types;
+
+use std::collections::{HashMap, HashSet};
+use std::f64::consts::PI;
+
+/// Tile coordinate resolution.
+pub const EXTENT: u32 = 4096;
+/// Geometry is simplified to this many tile units (one pixel of a 256 pixel tile).
+const TOLERANCE: f64 = (EXTENT / 256) as f64;
+/// Points this far outside of the tile are still included, to avoid clipped lines at the edges.
+pub const BUFFER: f64 = 64. / EXTENT as f64;
+/// Upper bound for the number of points loaded for one tile, whatever `max_tile_points` says.
+pub const MAX_TILE_POINTS: i64 = 1 << 16;
+
+/// Number of points to load for a tile, given the configured `max_tile_points`.
+pub fn point_limit(configured: i64) -> i64 {
+    configured.max(1).min(MAX_TILE_POINTS)
+}
+
+/// A map tile in the usual XYZ (web mercator) scheme.
+#[derive(Debug, Clone, Copy)]
+pub struct Tile {
+    pub z: u32,
+    pub x: u32,
+    pub y: u32,
+}
+
+impl Tile {
+    pub fn new(z: u32, x: u32, y: u32) -> Option<Tile> {
+        if z > 24 || x >= 1 << z || y >= 1 << z {
+            return None;
+        }
+        Some(Tile { z: z, x: x, y: y })
+    }
+
+    /// Bounding box (minlon, minlat, maxlon, maxlat) of the tile, extended by `buffer` tiles.
+    pub fn bounds(&self, buffer: f64) -> (f64, f64, f64, f64) {
+        let n = (1u64 << self.z) as f64;
+        let lon = |x: f64| (x / n * 360. - 180.).max(-180.).min(180.);
+        let lat = |y: f64| {
+            let y = y.max(0.).min(n);
+            (PI * (1. - 2. * y / n)).sinh().atan().to_degrees()
+        };
+        (
+            lon(self.x as f64 - buffer),
+            lat(self.y as f64 + 1. + buffer),
+            lon(self.x as f64 + 1. + buffer),
+            lat(self.y as f64 - buffer),
+        )
+    }
+
+    /// Position of a point in tile coordinates (0 to EXTENT within the tile).
+    fn project(&self, lat: f64, long: f64) -> (f64, f64) {
+        let n = (1u64 << self.z) as f64;
+        let lat = lat.max(-85.0511).min(85.0511).to_radians();
+        let x = (long + 180.) / 360. * n;
+        let y = (1. - (lat.tan() + 1. / lat.cos()).ln() / PI) / 2. * n;
+        (
+            (x - self.x as f64) * EXTENT as f64,
+            (y - self.y as f64) * EXTENT as f64,
+        )
+    }
+}
+
+/// Douglas-Peucker simplification.
+fn simplify(line: &[(f64, f64)], tolerance: f64) -> Vec<(f64, f64)> {
+    if line.len() < 3 {
+        return line.to_vec();
+    }
+    let mut keep = vec![false; line.len()];
+    keep[0] = true;
+    keep[line.len() - 1] = true;
+    let mut stack = vec![(0, line.len() - 1)];
+    while let Some((first, last)) = stack.pop() {
+        let (a, b) = (line[first], line[last]);
+        let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+        let len = (dx * dx + dy * dy).sqrt();
+        let mut max = (0., first);
+        for i in first + 1..last {
+            let p = line[i];
+            let d = if len > 0. {
+                ((p.0 - a.0) * dy - (p.1 - a.1) * dx).abs() / len
+            } else {
+                ((p.0 - a.0).powi(2) + (p.1 - a.1).powi(2)).sqrt()
+            };
+            if d > max.0 {
+                max = (d, i);
+            }
+        }
+        if max.0 > tolerance {
+            keep[max.1] = true;
+            stack.push((first, max.1));
+            stack.push((max.1, last));
+        }
+    }
+    line.iter()
+        .zip(keep)
+        .filter(|(_, k)| *k)
+        .map(|(p, _)| *p)
+        .collect()
+}
+
+fn zigzag(n: i32) -> u32 {
+    ((n << 1) ^ (n >> 31)) as u32
+}
+
+fn command(id: u32, count: usize) -> u32 {
+    (id & 0x7) | ((count as u32) << 3)
+}
+
+/// Encode a geometry (one point, or a line) as MVT drawing commands.
+fn encode_geometry(coords: &[(i32, i32)]) -> Vec<u32> {
+    let mut geom = Vec::with_capacity(2 * coords.len() + 2);
+    let mut cursor = (0, 0);
+    for (i, c) in coords.iter().enumerate() {
+        if i == 0 {
+            geom.push(command(1, 1)); // MoveTo
+        } else if i == 1 {
+            geom.push(command(2, coords.len() - 1)); // LineTo
+        }
+        geom.push(zigzag(c.0 - cursor.0));
+        geom.push(zigzag(c.1 - cursor.1));
+        cursor = *c;
+    }
+    geom
+}
+
+/// Minimal protobuf writer, sufficient for vector tiles.
+struct Pbf(Vec<u8>);
+
+impl Pbf {
+    fn varint(&mut self, mut v: u64) {
+        while v >= 0x80 {
+            self.0.push((v as u8) | 0x80);
+            v >>= 7;
+        }
+        self.0.push(v as u8);
+    }
+    fn key(&mut self, field: u32, wire_type: u32) {
+        self.varint(((field << 3) | wire_type) as u64);
+    }
+    fn uint(&mut self, field: u32, v: u64) {
+        self.key(field, 0);
+        self.varint(v);
+    }
+    fn bytes(&mut self, field: u32, b: &[u8]) {
+        self.key(field, 2);
+        self.varint(b.len() as u64);
+        self.0.extend_from_slice(b);
+    }
+    fn double(&mut self, field: u32, v: f64) {
+        self.key(field, 1);
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+    fn packed(&mut self, field: u32, vs: &[u32]) {
+        let mut inner = Pbf(vec![]);
+        for v in vs {
+            inner.varint(*v as u64);
+        }
+        self.bytes(field, &inner.0);
+    }
+}
+
+/// Feature property values.
+#[derive(Debug, Clone)]
+enum PropValue {
+    Str(String),
+    Double(f64),
+}
+
+impl PropValue {
+    /// Encode as `Value` message.
+    fn encode(&self) -> Vec<u8> {
+        let mut vp = Pbf(vec![]);
+        match self {
+            PropValue::Str(s) => vp.bytes(1, s.as_bytes()),
+            PropValue::Double(d) => vp.double(3, *d),
+        }
+        vp.0
+    }
+}
+
+/// A layer under construction. Keys and values are deduplicated.
+struct Layer {
+    name: &'static str,
+    keys: Vec<&'static str>,
+    /// Encoded values and their indices.
+    values: Vec<Vec<u8>>,
+    value_index: HashMap<Vec<u8>, usize>,
+    features: Vec<Vec<u8>>,
+}
+
+impl Layer {
+    fn new(name: &'static str) -> Layer {
+        Layer {
+            name: name,
+            keys: vec![],
+            values: vec![],
+            value_index: HashMap::new(),
+            features: vec![],
+        }
+    }
+
+    fn add_feature(
+        &mut self,
+        id: Option<u64>,
+        geom_type: u64,
+        geometry: Vec<u32>,
+        props: Vec<(&'static str, PropValue)>,
+    ) {
+        let mut tags = Vec::with_capacity(2 * props.len());
+        for (k, v) in props {
+            let ki = match self.keys.iter().position(|x| *x == k) {
+                Some(i) => i,
+                None => {
+                    self.keys.push(k);
+                    self.keys.len() - 1
+                }
+            };
+            let v = v.encode();
+            let vi = match self.value_index.get(&v) {
+                Some(i) => *i,
+                None => {
+                    self.values.push(v.clone());
+                    self.value_index.insert(v, self.values.len() - 1);
+                    self.values.len() - 1
+                }
+            };
+            tags.push(ki as u32);
+            tags.push(vi as u32);
+        }
+        let mut f = Pbf(vec![]);
+        if let Some(id) = id {
+            f.uint(1, id);
+        }
+        f.packed(2, &tags);
+        f.uint(3, geom_type);
+        f.packed(4, &geometry);
+        self.features.push(f.0);
+    }
+
+    /// Simplify a line, and add it if anything is left of it.
+    fn add_line(
+        &mut self,
+        line: &[(f64, f64)],
+        from: chrono::DateTime<chrono::Utc>,
+        to: chrono::DateTime<chrono::Utc>,
+    ) {
+        let mut coords = simplify(line, TOLERANCE)
+            .into_iter()
+            .map(|(x, y)| (x.round() as i32, y.round() as i32))
+            .collect::<Vec<(i32, i32)>>();
+        coords.dedup();
+        if coords.len() >= 2 {
+            let props = vec![
+                ("from", PropValue::Str(from.to_rfc3339())),
+                ("to", PropValue::Str(to.to_rfc3339())),
+            ];
+            self.add_feature(None, 2, encode_geometry(&coords), props);
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut l = Pbf(vec![]);
+        l.uint(15, 2);
+        l.bytes(1, self.name.as_bytes());
+        for f in self.features.iter() {
+            l.bytes(2, f);
+        }
+        for k in self.keys.iter() {
+            l.bytes(3, k.as_bytes());
+        }
+        for v in self.values.iter() {
+            l.bytes(4, v);
+        }
+        l.uint(5, EXTENT as u64);
+        l.0
+    }
+}
+
+/// Encode a track (sorted by time) as vector tile with the layers `points` and `lines`. Lines are
+/// split where consecutive points are more than `max_gap` apart.
+pub fn encode_tile(tile: Tile, points: &[types::GeoPoint], max_gap: chrono::Duration) -> Vec<u8> {
+    let mut point_layer = Layer::new("points");
+    let mut line_layer = Layer::new("lines");
+
+    // Only one point per pixel is shown.
+    let mut occupied = HashSet::new();
+    let mut line: Vec<(f64, f64)> = vec![];
+    let mut line_start: Option<&types::GeoPoint> = None;
+    let mut prev: Option<&types::GeoPoint> = None;
+
+    for p in points {
+        let (x, y) = tile.project(p.lat, p.long);
+
+        if let (Some(start), Some(prev)) = (line_start, prev) {
+            if p.time - prev.time > max_gap {
+                line_layer.add_line(&line, start.time, prev.time);
+                line.clear();
+                line_start = None;
+            }
+        }
+        line.push((x, y));
+        line_start = line_start.or(Some(p));
+        prev = Some(p);
+
+        let inside = x >= 0. && y >= 0. && x < EXTENT as f64 && y < EXTENT as f64;
+        let pixel = ((x / TOLERANCE) as i64, (y / TOLERANCE) as i64);
+        if inside && occupied.insert(pixel) {
+            let mut props = vec![("time", PropValue::Str(p.time.to_rfc3339()))];
+            if let Some(spd) = p.spd {
+                props.push(("speed", PropValue::Double(spd)));
+            }
+            point_layer.add_feature(
+                p.id.map(|id| id as u64),
+                1,
+                encode_geometry(&[(x.round() as i32, y.round() as i32)]),
+                props,
+            );
+        }
+    }
+    if let (Some(start), Some(prev)) = (line_start, prev) {
+        line_layer.add_line(&line, start.time, prev.time);
+    }
+
+    let mut t = Pbf(vec![]);
+    for layer in &[point_layer, line_layer] {
+        if !layer.features.is_empty() {
+            t.bytes(3, &layer.encode());
+        }
+    }
+    t.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decoded protobuf field: number and value (varint, or bytes of length-delimited fields).
+    #[derive(Debug, PartialEq)]
+    enum Field {
+        Varint(u32, u64),
+        Bytes(u32, Vec<u8>),
+        Fixed64(u32, u64),
+    }
+
+    fn varint(buf: &[u8], pos: &mut usize) -> u64 {
+        let mut v = 0;
+        let mut shift = 0;
+        loop {
+            let b = buf[*pos];
+            *pos += 1;
+            v |= ((b & 0x7f) as u64) << shift;
+            if b < 0x80 {
+                return v;
+            }
+            shift += 7;
+        }
+    }
+
+    fn decode(buf: &[u8]) -> Vec<Field> {
+        let mut fields = vec![];
+        let mut pos = 0;
+        while pos < buf.len() {
+            let key = varint(buf, &mut pos);
+            let field = (key >> 3) as u32;
+            match key & 0x7 {
+                0 => fields.push(Field::Varint(field, varint(buf, &mut pos))),
+                1 => {
+                    let mut b = [0; 8];
+                    b.copy_from_slice(&buf[pos..pos + 8]);
+                    pos += 8;
+                    fields.push(Field::Fixed64(field, u64::from_le_bytes(b)));
+                }
+                2 => {
+                    let len = varint(buf, &mut pos) as usize;
+                    fields.push(Field::Bytes(field, buf[pos..pos + len].to_vec()));
+                    pos += len;
+                }
+                t => panic!("unexpected wire type {}", t),
+            }
+        }
+        fields
+    }
+
+    fn bytes(fields: &[Field], number: u32) -> Vec<&[u8]> {
+        fields
+            .iter()
+            .filter_map(|f| match f {
+                Field::Bytes(n, b) if *n == number => Some(b.as_slice()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn packed(b: &[u8]) -> Vec<u64> {
+        let mut pos = 0;
+        let mut vs = vec![];
+        while pos < b.len() {
+            vs.push(varint(b, &mut pos));
+        }
+        vs
+    }
+
+    fn point(id: i32, seconds: i64, lat: f64, long: f64) -> types::GeoPoint {
+        types::GeoPoint {
+            id: Some(id),
+            lat: lat,
+            long: long,
+            spd: Some(10.),
+            ele: None,
+            accuracy: None,
+            time: chrono::DateTime::from_utc(
+                chrono::NaiveDateTime::from_timestamp(1_600_000_000 + seconds, 0),
+                chrono::Utc,
+            ),
+            note: None,
+        }
+    }
+
+    #[test]
+    fn test_tile_new() {
+        assert!(Tile::new(0, 0, 0).is_some());
+        assert!(Tile::new(2, 3, 3).is_some());
+        assert!(Tile::new(2, 4, 0).is_none());
+        assert!(Tile::new(2, 0, 4).is_none());
+        assert!(Tile::new(25, 0, 0).is_none());
+    }
+
+    #[test]
+    fn test_point_limit() {
+        assert_eq!(point_limit(10000), 10000);
+        assert_eq!(point_limit(1 << 20), MAX_TILE_POINTS);
+        assert_eq!(point_limit(0), 1);
+        assert_eq!(point_limit(-5), 1);
+    }
+
+    #[test]
+    fn test_project() {
+        let tile = Tile::new(1, 1, 0).unwrap();
+        let (x, y) = tile.project(0., 0.);
+        assert!(x.abs() < 1e-6);
+        assert!((y - EXTENT as f64).abs() < 1e-6);
+        let (minlon, minlat, maxlon, maxlat) = tile.bounds(0.);
+        assert_eq!((minlon, minlat, maxlon), (0., 0., 180.));
+        assert!((maxlat - 85.0511).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_simplify() {
+        // Short lines are kept as they are.
+        let short = vec![(0., 0.), (5., 5.)];
+        assert_eq!(simplify(&short, 1.), short);
+        // Points close to a straight line are dropped.
+        let straight = vec![(0., 0.), (1., 0.2), (2., -0.2), (3., 0.1), (4., 0.)];
+        assert_eq!(simplify(&straight, 1.), vec![(0., 0.), (4., 0.)]);
+        // Corners are kept.
+        let corner = vec![(0., 0.), (5., 0.1), (10., 0.), (10., 5.), (10., 10.)];
+        assert_eq!(simplify(&corner, 1.), vec![(0., 0.), (10., 0.), (10., 10.)]);
+        // A line returning to its start.
+        let back = vec![(0., 0.), (10., 0.), (0., 0.)];
+        assert_eq!(simplify(&back, 1.), back);
+    }
+
+    #[test]
+    fn test_encode_geometry() {
+        // Examples from the vector tile specification.
+        assert_eq!(encode_geometry(&[(25, 17)]), vec![9, 50, 34]);
+        assert_eq!(
+            encode_geometry(&[(2, 2), (2, 10), (10, 10)]),
+            vec![9, 4, 4, 18, 0, 16, 16, 0]
+        );
+        assert_eq!(zigzag(-1), 1);
+        assert_eq!(zigzag(-3), 5);
+        assert_eq!(zigzag(3), 6);
+    }
+
+    #[test]
+    fn test_pbf() {
+        let mut p = Pbf(vec![]);
+        p.uint(1, 300);
+        p.bytes(2, b"ab");
+        p.packed(3, &[1, 150]);
+        p.double(4, 1.5);
+        assert_eq!(
+            decode(&p.0),
+            vec![
+                Field::Varint(1, 300),
+                Field::Bytes(2, b"ab".to_vec()),
+                Field::Bytes(3, vec![1, 150, 1]),
+                Field::Fixed64(4, 1.5f64.to_bits()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_encode_tile() {
+        let tile = Tile::new(0, 0, 0).unwrap();
+        let points = vec![
+            point(1, 0, 0., 0.),
+            point(2, 10, 10., 10.),
+            // Same pixel as the previous point.
+            point(3, 20, 10., 10.0001),
+            // After a gap: starts a new line, which has only one point.
+            point(4, 1000, -10., -10.),
+        ];
+        let tile = decode(&encode_tile(tile, &points, chrono::Duration::seconds(60)));
+        let layers = bytes(&tile, 3).into_iter().map(decode).collect::<Vec<_>>();
+        assert_eq!(layers.len(), 2);
+        let names = layers
+            .iter()
+            .map(|l| bytes(l, 1)[0].to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec![b"points".to_vec(), b"lines".to_vec()]);
+        for layer in layers.iter() {
+            assert!(layer.contains(&Field::Varint(15, 2)));
+            assert!(layer.contains(&Field::Varint(5, EXTENT as u64)));
+        }
+
+        let features = bytes(&layers[0], 2)
+            .into_iter()
+            .map(decode)
+            .collect::<Vec<_>>();
+        let ids = features
+            .iter()
+            .filter_map(|f| match f[0] {
+                Field::Varint(1, id) => Some(id),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![1, 2, 4]);
+        // The first point is in the center of the tile.
+        assert!(features[0].contains(&Field::Varint(3, 1)));
+        let geometry = packed(bytes(&features[0], 4)[0]);
+        assert_eq!(geometry, vec![9, 4096, 4096]);
+        // Keys are shared by all features.
+        assert_eq!(bytes(&layers[0], 3), vec![&b"time"[..], &b"speed"[..]]);
+
+        let lines = bytes(&layers[1], 2)
+            .into_iter()
+            .map(decode)
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains(&Field::Varint(3, 2)));
+        assert_eq!(packed(bytes(&lines[0], 4)[0])[..2], [9, 4096]);
+    }
+
+    #[test]
+    fn test_encode_tile_empty() {
+        let tile = Tile::new(3, 1, 1).unwrap();
+        assert!(encode_tile(tile, &[], chrono::Duration::seconds(60)).is_empty());
+    }
+}
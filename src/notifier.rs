@@ -1,17 +1,51 @@
 use crate::db;
+use crate::logging;
+use crate::metrics;
 use crate::types;
 
 use fallible_iterator::FallibleIterator;
 use std::collections::HashMap;
+use std::panic;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize, Ordering};
 use std::sync::{mpsc, Arc, Mutex};
 use std::time;
 
+/// At most this many points are delivered with one notification.
+pub const MAX_NOTIFY_ROWS: i64 = 256;
+
 /// Request of a web client thread to the notifier thread.
 pub struct NotifyRequest {
     pub client: String,
     pub secret: Option<String>,
+    /// If set, wait for proximity events of these rules (owned by `client`) instead of points.
+    pub proximity_rules: Option<Vec<i32>>,
     pub respond: SendableSender<NotifyResponse>,
+    /// When the web client thread stops waiting.
+    pub deadline: time::Instant,
+    /// ID of the HTTP request, for logging.
+    pub request_id: Option<String>,
+}
+
+impl NotifyRequest {
+    /// Log `event` with the ID of the request.
+    fn log(&self, event: &str) {
+        logging::with_request_id(self.request_id.as_deref(), || {
+            log::debug!("Live request of {}: {}", self.client, event)
+        });
+    }
+
+    fn answer(&self, response: NotifyResponse) {
+        if let Some(error) = response.error.as_ref() {
+            self.log(&format!("answered with error: {}", error));
+        } else if let Some(event) = response.event.as_ref() {
+            self.log(&format!("answered with event of rule {}", event.rule));
+        } else {
+            let points = response.geo.as_ref().map(|g| g.features.len());
+            self.log(&format!("answered with {} points", points.unwrap_or(0)));
+        }
+        self.respond.send(response).ok();
+    }
 }
 
 /// Response from the notifier thread to a web client thread.
@@ -19,6 +53,19 @@ pub struct NotifyResponse {
     // The GeoJSON object containing the update and the `last` page token.
     pub geo: Option<types::GeoJSON>,
     pub last: Option<i32>,
+    pub event: Option<types::ProximityEvent>,
+    pub error: Option<String>,
+}
+
+impl NotifyResponse {
+    fn error(error: &str) -> NotifyResponse {
+        NotifyResponse {
+            geo: None,
+            last: None,
+            event: None,
+            error: Some(error.into()),
+        }
+    }
 }
 
 /// A `Send` sender.
@@ -34,75 +81,236 @@ impl<T> SendableSender<T> {
     }
 }
 
-fn encode_client_id(client: &str, secret: &Option<String>) -> String {
-    format!(
-        "{} {}",
-        client,
-        secret.as_ref().map(|s| s.as_str()).unwrap_or(""),
-    )
+/// Hex-encoded prefix of the keyed hash (see `SecretHasher::digest`) of `parts`, each
+/// terminated by a NUL byte. 128 bits are plenty to tell sessions apart, and keep channel names
+/// well below the 63 bytes that Postgres allows.
+fn hash_id(secrets: &db::SecretHasher, parts: &[&str]) -> String {
+    let mut data = b"geohub channel\0".to_vec();
+    for part in parts {
+        data.extend_from_slice(part.as_bytes());
+        data.push(0);
+    }
+    secrets.digest(&data)[..16]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
 }
 
-fn encode_notify_payload(client: &str, secret: &Option<String>, nrows: Option<i64>) -> String {
+/// Channel on which new points of `client` and `secret` are announced. The secret only enters
+/// the channel name hashed, so that it never leaves the web process. Channel names can be seen
+/// by every database user (`pg_stat_activity`, `pg_listening_channels()`); with a `secret_key`,
+/// the secret can't be brute-forced from them.
+fn session_channel(secrets: &db::SecretHasher, client: &str, secret: &Option<String>) -> String {
     format!(
-        "{} {} {}",
-        client,
-        secret.as_ref().map(|s| s.as_str()).unwrap_or(""),
-        nrows.unwrap_or(1)
+        "geohubsession_{}",
+        hash_id(
+            secrets,
+            &[client, secret.as_ref().map(|s| s.as_str()).unwrap_or("")]
+        )
     )
 }
 
-fn decode_notify_payload(payload: &str) -> (String, Option<String>, Option<i64>) {
-    let parts = payload.split(' ').collect::<Vec<&str>>();
-    assert!(parts.len() >= 1);
-    let secret = if parts.len() > 1 {
-        Some(parts[1].into())
-    } else {
-        None
-    };
-    let nrows = if parts.len() > 2 {
-        i64::from_str(parts[2]).ok()
-    } else {
-        None
-    };
-    return (parts[0].into(), secret, nrows);
+/// Channel on which proximity events for rules owned by `client` are sent.
+fn proximity_channel(secrets: &db::SecretHasher, client: &str) -> String {
+    // Hashing also avoids LISTEN folding unquoted identifiers to lower case. The event payload
+    // identifies the client.
+    format!("geohubproximity_{}", hash_id(secrets, &[client]))
 }
 
-/// Build a channel name from a client name and secret.
-fn channel_name(client: &str, secret: &str) -> String {
-    // The log handler should already have checked this.
-    assert!(secret.find(' ').is_none());
-    assert!(client.find(' ').is_none());
-    format!("geohubclient_update_{}_{}", client, secret)
+/// The payload of a session notification is the number of new rows and the time of sending (in
+/// milliseconds since the epoch), optionally followed by the serialized point for points that are
+/// only sent live. Client and secret are known to the waiters on the channel.
+fn encode_notify_payload(nrows: Option<i64>, feature: Option<&str>) -> String {
+    let sent = chrono::Utc::now().timestamp_millis();
+    match feature {
+        Some(feature) => format!("{} {} {}", nrows.unwrap_or(0), sent, feature),
+        None => format!("{} {}", nrows.unwrap_or(1), sent),
+    }
 }
 
-pub struct NotifyManager(pub SendableSender<NotifyRequest>);
+/// Decode a payload into number of new rows, time of sending, and serialized point.
+fn decode_notify_payload(payload: &str) -> (Option<i64>, Option<i64>, Option<String>) {
+    let mut parts = payload.splitn(3, ' ');
+    let nrows = parts.next().and_then(|n| i64::from_str(n).ok());
+    let sent = parts.next().and_then(|t| i64::from_str(t).ok());
+    let feature = parts.next().map(|f| f.to_string());
+    (nrows, sent, feature)
+}
+
+/// Channel on which request handlers wake the notifier thread when they register a waiter.
+const WAKEUP_CHANNEL: &str = "geohub_wakeup";
+
+/// Wakes the notifier thread while it is blocked waiting for database notifications.
+#[derive(Clone)]
+struct Wakeup {
+    db_url: String,
+    /// Set by the notifier thread before it blocks, and cleared by the first waker. This way,
+    /// there is at most one wakeup per wait.
+    sleeping: Arc<AtomicBool>,
+    /// Connection for sending wakeups, shared by all request handlers. It is taken out while in
+    /// use, so that the lock is never held while connecting or sending.
+    conn: Arc<Mutex<Option<postgres::Connection>>>,
+}
+
+impl Wakeup {
+    fn wake(&self) {
+        if !self.sleeping.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        // There is one waker per wait of the notifier thread, so new connections are only made
+        // concurrently if connecting takes longer than the notifier's idle timeout.
+        let mut conn = self.conn.lock().unwrap().take();
+        // Retry once on a new connection.
+        for _ in 0..2 {
+            let c = match conn.take() {
+                Some(c) => c,
+                None => {
+                    match postgres::Connection::connect(
+                        self.db_url.as_str(),
+                        postgres::TlsMode::None,
+                    ) {
+                        Ok(c) => c,
+                        Err(e) => {
+                            log::warn!("Couldn't connect for notifier wakeup: {}", e);
+                            return;
+                        }
+                    }
+                }
+            };
+            match c.execute(&format!("NOTIFY {}", WAKEUP_CHANNEL), &[]) {
+                Ok(_) => {
+                    // Keep the connection, unless another waker has returned one meanwhile.
+                    let mut shared = self.conn.lock().unwrap();
+                    if shared.is_none() {
+                        *shared = Some(c);
+                    }
+                    return;
+                }
+                Err(e) => log::warn!("Couldn't send notifier wakeup: {}", e),
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct NotifyManager {
+    sender: SendableSender<NotifyRequest>,
+    health: Health,
+    wakeup: Wakeup,
+}
 
 impl NotifyManager {
+    /// Start the notifier thread, which uses its own connection to `db_url`.
+    pub fn start(db_url: String, secrets: db::SecretHasher) -> NotifyManager {
+        let (send, recv) = mpsc::channel();
+        let manager = NotifyManager {
+            sender: SendableSender {
+                sender: Arc::new(Mutex::new(send)),
+            },
+            health: Health::default(),
+            wakeup: Wakeup {
+                db_url: db_url,
+                sleeping: Arc::new(AtomicBool::new(false)),
+                conn: Arc::new(Mutex::new(None)),
+            },
+        };
+        let (health, wakeup) = (manager.health.clone(), manager.wakeup.clone());
+        std::thread::spawn(move || live_notifier_thread(recv, secrets, health, wakeup));
+        manager
+    }
+
+    pub fn health(&self) -> &Health {
+        &self.health
+    }
+
     pub fn wait_for_notification(
         &self,
         client: String,
         secret: Option<String>,
         timeout: Option<u64>,
     ) -> types::LiveUpdate {
+        self.wait(client, secret, None, timeout)
+    }
+
+    /// Wait for a proximity event of one of `rules`, which must be owned by `client`.
+    pub fn wait_for_proximity_event(
+        &self,
+        client: String,
+        rules: Vec<i32>,
+        timeout: Option<u64>,
+    ) -> types::LiveUpdate {
+        self.wait(client, None, Some(rules), timeout)
+    }
+
+    fn wait(
+        &self,
+        client: String,
+        secret: Option<String>,
+        proximity_rules: Option<Vec<i32>>,
+        timeout: Option<u64>,
+    ) -> types::LiveUpdate {
+        if !self.health.connected() {
+            return types::LiveUpdate::new(
+                client,
+                None,
+                None,
+                Some("live updates unavailable (no database connection), try again later".into()),
+            );
+        }
         let (send, recv) = mpsc::channel();
         let send = SendableSender {
             sender: Arc::new(Mutex::new(send)),
         };
 
+        let timeout = time::Duration::new(timeout.unwrap_or(30), 0);
         let req = NotifyRequest {
             client: client.clone(),
             secret: secret,
+            proximity_rules: proximity_rules,
             respond: send,
+            deadline: time::Instant::now() + timeout,
+            request_id: logging::request_id(),
         };
-        self.0.send(req).unwrap();
+        if self.sender.send(req).is_err() {
+            return types::LiveUpdate::new(
+                client,
+                None,
+                None,
+                Some("live updates unavailable (notifier stopped)".into()),
+            );
+        }
+        self.wakeup.wake();
 
-        if let Ok(response) = recv.recv_timeout(time::Duration::new(timeout.unwrap_or(30), 0)) {
-            types::LiveUpdate::new(client, response.last, response.geo, None)
+        if let Ok(response) = recv.recv_timeout(timeout) {
+            types::LiveUpdate::new(client, response.last, response.geo, response.error)
+                .with_event(response.event)
         } else {
-            types::LiveUpdate::new(client, None, None, Some("timeout, try again".into()))
+            types::LiveUpdate::new(client, None, None, Some(types::LIVE_TIMEOUT.into()))
         }
     }
 
+    /// Notify waiters for proximity events of the rule's owner.
+    pub fn send_proximity_event(
+        &self,
+        dbq: &db::DBQuery,
+        event: &types::ProximityEvent,
+    ) -> Result<u64, postgres::Error> {
+        let payload = serde_json::to_string(event).unwrap();
+        let notify = dbq.0.prepare_cached("SELECT pg_notify($1, $2)").unwrap();
+        notify.execute(&[&proximity_channel(dbq.1, &event.client), &payload])
+    }
+
+    /// Send a point to live waiters without it being stored in the database.
+    pub fn send_live_point(
+        &self,
+        dbq: &db::DBQuery,
+        client: &str,
+        secret: &Option<String>,
+        point: &types::GeoPoint,
+    ) -> Result<u64, postgres::Error> {
+        notify_points(dbq, client, secret, std::slice::from_ref(point))
+    }
+
     pub fn send_notification(
         &self,
         dbq: &db::DBQuery,
@@ -110,122 +318,520 @@ impl NotifyManager {
         secret: &Option<String>,
         nrows: Option<i64>,
     ) -> Result<u64, postgres::Error> {
-        let channel = format!(
-            "NOTIFY {}, '{}'",
-            channel_name(client, secret.as_ref().unwrap_or(&"".into()).as_str()),
-            encode_notify_payload(client, secret, nrows),
-        );
-        let notify = dbq.0.prepare_cached(channel.as_str()).unwrap();
-        notify.execute(&[])
+        notify_new_points(dbq, client, secret, nrows)
     }
 }
 
-/// Listen for notifications in the database and dispatch to waiting clients.
-pub fn live_notifier_thread(rx: mpsc::Receiver<NotifyRequest>, db: postgres::Connection) {
-    const TICK_MILLIS: u32 = 500;
+/// Tell live waiters of `client` and `secret` that the `nrows` newest points are new. Inside a
+/// transaction, the notification is only delivered on commit.
+pub fn notify_new_points(
+    dbq: &db::DBQuery,
+    client: &str,
+    secret: &Option<String>,
+    nrows: Option<i64>,
+) -> Result<u64, postgres::Error> {
+    let notify = dbq.0.prepare_cached("SELECT pg_notify($1, $2)").unwrap();
+    notify.execute(&[
+        &session_channel(dbq.1, client, secret),
+        &encode_notify_payload(nrows, None),
+    ])
+}
 
-    let mut clients: HashMap<String, Vec<NotifyRequest>> = HashMap::new();
-    let db = db::DBQuery(&db);
+/// Send `points` to live waiters of `client` and `secret` as they are, like points that are only
+/// sent live. This is for stored points that aren't the newest, e.g. ones moved from another
+/// client: waiters only query points with IDs above the last one they have seen.
+pub fn notify_points(
+    dbq: &db::DBQuery,
+    client: &str,
+    secret: &Option<String>,
+    points: &[types::GeoPoint],
+) -> Result<u64, postgres::Error> {
+    let notify = dbq.0.prepare_cached("SELECT pg_notify($1, $2)").unwrap();
+    let channel = session_channel(dbq.1, client, secret);
+    let mut n = 0;
+    for point in points {
+        let feature = serde_json::to_string(&types::geofeature_from_point(point.clone())).unwrap();
+        n += notify.execute(&[&channel, &encode_notify_payload(Some(0), Some(&feature))])?;
+    }
+    Ok(n)
+}
 
-    fn listen(
-        db: &postgres::Connection,
-        client: &str,
-        secret: &Option<String>,
-    ) -> postgres::Result<u64> {
-        let n = db
-            .execute(
-                &format!(
-                    "LISTEN {}",
-                    channel_name(client, secret.as_ref().map(|s| s.as_str()).unwrap_or(""))
-                        .as_str()
-                ),
-                &[],
-            )
-            .unwrap();
-        Ok(n)
-    }
-    fn unlisten(
-        db: &postgres::Connection,
-        client: &str,
-        secret: &Option<String>,
-    ) -> postgres::Result<u64> {
-        let n = db
-            .execute(
-                &format!(
-                    "UNLISTEN {}",
-                    channel_name(client, secret.as_ref().map(|s| s.as_str()).unwrap_or(""))
-                        .as_str()
-                ),
-                &[],
-            )
-            .unwrap();
-        Ok(n)
+/// Health of the notifier thread, shared with the request handlers.
+#[derive(Clone, Default)]
+pub struct Health(Arc<HealthState>);
+
+#[derive(Default)]
+struct HealthState {
+    /// Whether the notifier has a working database connection.
+    connected: AtomicBool,
+    /// Number of times the connection was re-established.
+    reconnects: AtomicU64,
+    /// Unix time of the last iteration of the notifier loop.
+    heartbeat: AtomicI64,
+    /// Number of registered waiters.
+    waiters: AtomicUsize,
+    last_error: Mutex<Option<String>>,
+}
+
+impl Health {
+    pub fn connected(&self) -> bool {
+        self.0.connected.load(Ordering::SeqCst)
     }
 
-    loop {
-        // This loop checks for new messages on rx, then checks for new database notifications, etc.
+    pub fn status(&self) -> types::NotifierStatus {
+        let heartbeat = self.0.heartbeat.load(Ordering::SeqCst);
+        types::NotifierStatus {
+            connected: self.connected(),
+            reconnects: self.0.reconnects.load(Ordering::SeqCst),
+            waiters: self.0.waiters.load(Ordering::SeqCst),
+            heartbeat: if heartbeat > 0 {
+                Some(chrono::DateTime::from_utc(
+                    chrono::NaiveDateTime::from_timestamp(heartbeat, 0),
+                    chrono::Utc,
+                ))
+            } else {
+                None
+            },
+            last_error: self.0.last_error.lock().unwrap().clone(),
+        }
+    }
 
-        // Drain notification requests (clients asking to watch for notifications).
-        // We listen per client and secret to separate clients with different sessions (by secret).
-        loop {
-            if let Ok(nrq) = rx.try_recv() {
-                // client_id is also the payload sent to the channel. It keys waiters by client and
-                // secret.
-                let client_id = encode_client_id(nrq.client.as_str(), &nrq.secret);
-                if !clients.contains_key(&client_id) {
-                    listen(db.0, &nrq.client, &nrq.secret).ok();
+    /// Whether the notifier is connected and its loop is running.
+    pub fn check(&self) -> Result<(), String> {
+        // While connected, the loop runs at least every few seconds (see `serve`).
+        const MAX_HEARTBEAT_AGE: i64 = 30;
+
+        let status = self.status();
+        if !status.connected {
+            return Err(format!(
+                "not connected: {}",
+                status.last_error.unwrap_or("not started yet".into())
+            ));
+        }
+        match status.heartbeat {
+            Some(t) if (chrono::Utc::now() - t).num_seconds() <= MAX_HEARTBEAT_AGE => Ok(()),
+            Some(t) => Err(format!("loop stalled since {}", t.to_rfc3339())),
+            None => Err("loop not running".into()),
+        }
+    }
+
+    fn beat(&self, waiters: usize) {
+        self.0
+            .heartbeat
+            .store(chrono::Utc::now().timestamp(), Ordering::SeqCst);
+        self.0.waiters.store(waiters, Ordering::SeqCst);
+    }
+
+    fn set_connected(&self) {
+        if self.0.heartbeat.load(Ordering::SeqCst) > 0 {
+            self.0.reconnects.fetch_add(1, Ordering::SeqCst);
+        }
+        self.0.connected.store(true, Ordering::SeqCst);
+    }
+
+    fn set_disconnected(&self, error: String) {
+        log::error!("Notifier: {}", error);
+        self.0.connected.store(false, Ordering::SeqCst);
+        *self.0.last_error.lock().unwrap() = Some(error);
+    }
+}
+
+/// Waiters registered with the notifier thread.
+#[derive(Default)]
+struct Waiters {
+    /// Waiters for new points, by session channel.
+    sessions: HashMap<String, Vec<NotifyRequest>>,
+    /// Waiters for proximity events, by owning client.
+    proximity: HashMap<String, Vec<NotifyRequest>>,
+}
+
+impl Waiters {
+    fn len(&self) -> usize {
+        self.sessions.values().map(|w| w.len()).sum::<usize>()
+            + self.proximity.values().map(|w| w.len()).sum::<usize>()
+    }
+
+    /// LISTEN again on a new connection, including the wakeup channel.
+    fn relisten(&self, db: &db::DBQuery) -> postgres::Result<()> {
+        db.0.execute(&format!("LISTEN {}", WAKEUP_CHANNEL), &[])?;
+        for channel in self.sessions.keys() {
+            db.0.execute(&format!("LISTEN {}", channel), &[])?;
+        }
+        for client in self.proximity.keys() {
+            db.0.execute(&format!("LISTEN {}", proximity_channel(db.1, client)), &[])?;
+        }
+        Ok(())
+    }
+
+    /// Drop waiters whose web client thread has stopped waiting, and UNLISTEN channels nobody
+    /// waits on anymore.
+    fn expire(&mut self, db: &db::DBQuery) -> postgres::Result<()> {
+        let now = time::Instant::now();
+        let mut unlisten = vec![];
+        self.sessions.retain(|channel, pending| {
+            pending.retain(|request| {
+                if request.deadline > now {
+                    return true;
                 }
-                clients.entry(client_id).or_insert(vec![]).push(nrq);
-            } else {
-                break;
+                request.log("expired");
+                false
+            });
+            if pending.is_empty() {
+                unlisten.push(channel.clone());
             }
+            !pending.is_empty()
+        });
+        self.proximity.retain(|client, pending| {
+            pending.retain(|request| {
+                if request.deadline > now {
+                    return true;
+                }
+                request.log("expired");
+                false
+            });
+            if pending.is_empty() {
+                unlisten.push(proximity_channel(db.1, client));
+            }
+            !pending.is_empty()
+        });
+        for channel in unlisten {
+            db.0.execute(&format!("UNLISTEN {}", channel), &[])?;
         }
+        Ok(())
+    }
 
-        // Drain notifications from the database.
-        // Also provide updated rows to the client.
-        let notifications = db.0.notifications();
-        let mut iter = notifications.timeout_iter(time::Duration::new(0, TICK_MILLIS * 1_000_000));
-        let mut count = 0;
-
-        while let Ok(Some(notification)) = iter.next() {
-            // We can extract the client and secret from the channel payload. The payload itself is
-            // the hashmap key.
-            let (client, secret, nrows) = decode_notify_payload(&notification.payload);
-            let client_id = encode_client_id(&client, &secret);
-
-            unlisten(db.0, client.as_str(), &secret).ok();
-
-            // These queries use the primary key index returning one row only and will be quite fast.
-            let rows =
-                db.check_for_new_rows(client.as_str(), &secret, &None, &Some(nrows.unwrap_or(1)));
-            if let Some((points, last)) = rows {
-                let geojson = types::geojson_from_points(points);
-                for request in clients.remove(&client_id).unwrap_or(vec![]) {
-                    request
-                        .respond
-                        .send(NotifyResponse {
-                            geo: Some(geojson.clone()),
-                            last: Some(last),
-                        })
-                        .ok();
+    /// Register a new waiter, LISTENing on its channel if necessary.
+    /// We listen per client and secret to separate clients with different sessions (by secret).
+    fn add(&mut self, db: &db::DBQuery, nrq: NotifyRequest) -> postgres::Result<()> {
+        nrq.log("registered");
+        if nrq.proximity_rules.is_some() {
+            if !self.proximity.contains_key(&nrq.client) {
+                db.0.execute(
+                    &format!("LISTEN {}", proximity_channel(db.1, &nrq.client)),
+                    &[],
+                )?;
+            }
+            self.proximity
+                .entry(nrq.client.clone())
+                .or_insert(vec![])
+                .push(nrq);
+            return Ok(());
+        }
+        let channel = session_channel(db.1, &nrq.client, &nrq.secret);
+        if !self.sessions.contains_key(&channel) {
+            db.0.execute(&format!("LISTEN {}", channel), &[])?;
+        }
+        self.sessions.entry(channel).or_insert(vec![]).push(nrq);
+        Ok(())
+    }
+
+    /// Answer all waiters with `error`.
+    fn fail_all(&mut self, error: &str) {
+        let sessions = self.sessions.drain().flat_map(|(_, w)| w);
+        let proximity = self.proximity.drain().flat_map(|(_, w)| w);
+        for request in sessions.chain(proximity) {
+            request.answer(NotifyResponse::error(error));
+        }
+    }
+}
+
+/// Listen for notifications in the database and dispatch to waiting clients. Connects to
+/// the database of `wakeup`, and reconnects with increasing backoff whenever the connection is
+/// lost; in the meantime, waiters are answered with an error.
+fn live_notifier_thread(
+    rx: mpsc::Receiver<NotifyRequest>,
+    secrets: db::SecretHasher,
+    health: Health,
+    wakeup: Wakeup,
+) {
+    const MIN_BACKOFF: time::Duration = time::Duration::from_secs(1);
+    const MAX_BACKOFF: time::Duration = time::Duration::from_secs(60);
+    const UNAVAILABLE: &str = "live updates unavailable (no database connection), try again later";
+
+    let mut waiters = Waiters::default();
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        let connected =
+            postgres::Connection::connect(wakeup.db_url.as_str(), postgres::TlsMode::None)
+                .and_then(|conn| {
+                    waiters
+                        .relisten(&db::DBQuery(&conn, &secrets))
+                        .map(|_| conn)
+                });
+        match connected {
+            Ok(conn) => {
+                health.set_connected();
+                backoff = MIN_BACKOFF;
+                let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                    serve(
+                        &rx,
+                        &db::DBQuery(&conn, &secrets),
+                        &health,
+                        &wakeup.sleeping,
+                        &mut waiters,
+                    )
+                }));
+                wakeup.sleeping.store(false, Ordering::SeqCst);
+                match result {
+                    // All senders are gone, so the server is shutting down.
+                    Ok(Ok(())) => return,
+                    Ok(Err(e)) => {
+                        metrics::METRICS.db_error();
+                        health.set_disconnected(format!("connection lost: {}", e))
+                    }
+                    Err(_) => health.set_disconnected("notifier loop panicked".into()),
                 }
-            } else {
-                for request in clients.remove(&client_id).unwrap_or(vec![]) {
-                    request
-                        .respond
-                        .send(NotifyResponse {
-                            geo: None,
-                            last: None,
-                        })
-                        .ok();
+                // Reconnect right away; pending waiters are kept if that works.
+            }
+            Err(e) => {
+                health.set_disconnected(format!("couldn't connect: {}", e));
+                waiters.fail_all(UNAVAILABLE);
+                health.beat(0);
+                let until = time::Instant::now() + backoff;
+                while let Some(wait) = until.checked_duration_since(time::Instant::now()) {
+                    match rx.recv_timeout(wait) {
+                        Ok(request) => {
+                            request.answer(NotifyResponse::error(UNAVAILABLE));
+                        }
+                        Err(mpsc::RecvTimeoutError::Timeout) => break,
+                        Err(mpsc::RecvTimeoutError::Disconnected) => return,
+                    }
                 }
+                backoff = std::cmp::min(2 * backoff, MAX_BACKOFF);
             }
+        }
+    }
+}
 
-            // We also need to receive new notification requests.
-            count += 1;
-            if count > 3 {
-                break;
+/// Dispatch notifications until the connection fails (returning the error), or `rx` is closed.
+///
+/// The thread blocks on the database connection only. Request handlers registering a new waiter
+/// while it does so send a notification on `WAKEUP_CHANNEL` (see `Wakeup`), so that both new
+/// waiters and new points are handled immediately.
+fn serve(
+    rx: &mpsc::Receiver<NotifyRequest>,
+    db: &db::DBQuery,
+    health: &Health,
+    sleeping: &AtomicBool,
+    waiters: &mut Waiters,
+) -> postgres::Result<()> {
+    // Upper bound for blocking, so that timed out waiters are dropped eventually.
+    const IDLE_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+    // A silently broken connection would never deliver notifications, so check it regularly.
+    const PROBE_INTERVAL: time::Duration = time::Duration::from_secs(30);
+
+    let mut last_probe = time::Instant::now();
+    loop {
+        health.beat(waiters.len());
+        if last_probe.elapsed() > PROBE_INTERVAL {
+            db.0.execute("SELECT 1", &[])?;
+            last_probe = time::Instant::now();
+        }
+        waiters.expire(db)?;
+
+        // From here on, new requests wake us up. Requests sent before are received now.
+        sleeping.store(true, Ordering::SeqCst);
+        loop {
+            match rx.try_recv() {
+                Ok(nrq) => waiters.add(db, nrq)?,
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => return Ok(()),
             }
         }
+
+        // Block until the first notification, then take all others that have arrived.
+        let notifications = db.0.notifications();
+        let mut batch = vec![];
+        if let Some(notification) = notifications.timeout_iter(IDLE_TIMEOUT).next()? {
+            batch.push(notification);
+        }
+        sleeping.store(false, Ordering::SeqCst);
+        let mut iter = notifications.iter();
+        while let Some(notification) = iter.next()? {
+            batch.push(notification);
+        }
+
+        // Group notifications by channel, so that each session is queried only once.
+        let mut sessions: Vec<(String, Vec<String>)> = vec![];
+        for notification in batch {
+            if notification.channel == WAKEUP_CHANNEL {
+                continue;
+            }
+            if notification.channel.starts_with("geohubproximity_") {
+                metrics::METRICS.notification(None);
+                dispatch_proximity_event(db, waiters, &notification.payload)?;
+                continue;
+            }
+            match sessions
+                .iter_mut()
+                .find(|(c, _)| *c == notification.channel)
+            {
+                Some((_, payloads)) => payloads.push(notification.payload),
+                None => sessions.push((notification.channel, vec![notification.payload])),
+            }
+        }
+        for (channel, payloads) in sessions {
+            dispatch_new_points(db, waiters, &channel, &payloads)?;
+        }
+    }
+}
+
+/// Answer all waiters for the rule of the proximity event in `payload`.
+fn dispatch_proximity_event(
+    db: &db::DBQuery,
+    waiters: &mut Waiters,
+    payload: &str,
+) -> postgres::Result<()> {
+    let event = match serde_json::from_str::<types::ProximityEvent>(payload) {
+        Ok(event) => event,
+        Err(e) => {
+            log::warn!("Couldn't decode proximity event: {}", e);
+            return Ok(());
+        }
+    };
+    let pending = waiters.proximity.remove(&event.client).unwrap_or(vec![]);
+    let mut remaining = vec![];
+    for request in pending {
+        let wants = request
+            .proximity_rules
+            .as_ref()
+            .map(|r| r.contains(&event.rule))
+            .unwrap_or(false);
+        if wants {
+            request.answer(NotifyResponse {
+                geo: None,
+                last: None,
+                event: Some(event.clone()),
+                error: None,
+            });
+        } else {
+            remaining.push(request);
+        }
+    }
+    if remaining.is_empty() {
+        db.0.execute(
+            &format!("UNLISTEN {}", proximity_channel(db.1, &event.client)),
+            &[],
+        )?;
+    } else {
+        waiters.proximity.insert(event.client, remaining);
+    }
+    Ok(())
+}
+
+/// Answer all waiters on a session `channel` with the new and live points announced by
+/// `payloads`.
+fn dispatch_new_points(
+    db: &db::DBQuery,
+    waiters: &mut Waiters,
+    channel: &str,
+    payloads: &[String],
+) -> postgres::Result<()> {
+    // All waiters on a channel wait for the same client and secret.
+    let pending = waiters.sessions.remove(channel).unwrap_or(vec![]);
+    db.0.execute(&format!("UNLISTEN {}", channel), &[])?;
+    let (client, secret) = match pending.first() {
+        Some(w) => (w.client.clone(), w.secret.clone()),
+        None => {
+            for _ in payloads {
+                metrics::METRICS.notification(None);
+            }
+            return Ok(());
+        }
+    };
+
+    let mut nrows = 0;
+    let mut live = vec![];
+    let mut sent = vec![];
+    for payload in payloads {
+        let (n, t, feature) = decode_notify_payload(payload);
+        sent.push(t);
+        match feature {
+            // Points that are only sent live are delivered as they are.
+            Some(feature) => match serde_json::from_str::<types::GeoFeature>(&feature) {
+                Ok(feature) => live.push(feature),
+                Err(e) => log::warn!("Couldn't decode live point: {}", e),
+            },
+            None => nrows += n.unwrap_or(1),
+        }
+    }
+
+    let mut geojson = types::GeoJSON::new();
+    let mut last = None;
+    if nrows > 0 {
+        // These queries use the primary key index and will be quite fast.
+        let nrows = std::cmp::min(nrows, MAX_NOTIFY_ROWS);
+        if let Some((points, l)) =
+            db.check_for_new_rows(client.as_str(), &secret, &None, &Some(nrows))
+        {
+            geojson = types::geojson_from_points(points);
+            last = Some(l);
+        }
+    }
+    for feature in live {
+        geojson.push_feature(feature);
+    }
+    let geo = if last.is_some() || !geojson.features.is_empty() {
+        Some(geojson)
+    } else {
+        None
+    };
+
+    for request in pending {
+        request.answer(NotifyResponse {
+            geo: geo.clone(),
+            last: last,
+            event: None,
+            error: None,
+        });
+    }
+    // Clocks of other GeoHub instances may be off a bit.
+    let now = chrono::Utc::now().timestamp_millis();
+    for t in sent {
+        let latency = t.map(|t| time::Duration::from_millis(std::cmp::max(now - t, 0) as u64));
+        metrics::METRICS.notification(latency);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_proximity_channel_mixed_case() {
+        let secrets = db::SecretHasher::new(None);
+        // LISTEN folds unquoted channel names to lower case, pg_notify() doesn't.
+        let channel = proximity_channel(&secrets, "AliceInWonderland");
+        assert_eq!(channel, channel.to_lowercase());
+        assert!(channel.len() <= 63);
+        // Client names are case sensitive.
+        assert_ne!(channel, proximity_channel(&secrets, "aliceinwonderland"));
+        let long = proximity_channel(&secrets, &"X".repeat(100));
+        assert_eq!(long, long.to_lowercase());
+        assert!(long.len() <= 63);
+    }
+
+    #[test]
+    fn test_session_channel_keyed() {
+        let unkeyed = db::SecretHasher::new(None);
+        let keyed = db::SecretHasher::new(Some(b"server key"));
+        let other_key = db::SecretHasher::new(Some(b"other key"));
+        let secret = Some("verysecret".to_string());
+        let channel = session_channel(&keyed, "alice", &secret);
+        assert!(channel.starts_with("geohubsession_"));
+        assert!(channel.len() <= 63);
+        // The same for every instance with the key, but not computable without it.
+        assert_eq!(channel, session_channel(&keyed, "alice", &secret));
+        assert_ne!(channel, session_channel(&unkeyed, "alice", &secret));
+        assert_ne!(channel, session_channel(&other_key, "alice", &secret));
+        // Sessions are told apart.
+        assert_ne!(channel, session_channel(&keyed, "alice", &None));
+        assert_ne!(channel, session_channel(&keyed, "bob", &secret));
+        // Parts are separated, so that they can't be shifted.
+        assert_ne!(
+            session_channel(&keyed, "ab", &Some("c".into())),
+            session_channel(&keyed, "a", &Some("bc".into()))
+        );
     }
 }
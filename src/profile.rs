@@ -0,0 +1,230 @@
+use crate::geo;
+use crate::types;
+
+/// Upper bound for the number of samples; the step is increased for longer tracks.
+const MAX_SAMPLES: f64 = 20000.;
+/// Upper bound for the smoothing window in meters.
+pub const MAX_SMOOTH: f64 = 5000.;
+
+/// A track resampled at fixed distance steps.
+#[derive(Debug, Clone)]
+pub struct Resampled {
+    /// Distance between samples in meters.
+    pub step: f64,
+    /// Cumulative distance of every sample, in meters.
+    pub distance: Vec<f64>,
+    /// Smoothed elevation, if known at that distance.
+    pub elevation: Vec<Option<f64>>,
+    pub speed: Vec<Option<f64>>,
+    /// Total ascent and descent of the smoothed elevation, in meters.
+    pub ascent: f64,
+    pub descent: f64,
+}
+
+/// Linear interpolation in a series of (distance, value) pairs sorted by distance. `hint` is the
+/// index to start searching from, and is advanced; queries must be in ascending order.
+fn interpolate(series: &[(f64, f64)], d: f64, hint: &mut usize) -> Option<f64> {
+    let (first, last) = (series.first()?, series.last()?);
+    if d < first.0 || d > last.0 {
+        return None;
+    }
+    while *hint + 1 < series.len() && series[*hint + 1].0 < d {
+        *hint += 1;
+    }
+    let a = series[*hint];
+    let b = match series.get(*hint + 1) {
+        Some(b) => *b,
+        None => return Some(a.1),
+    };
+    if b.0 - a.0 <= 0. {
+        return Some(b.1);
+    }
+    Some(a.1 + (b.1 - a.1) * (d - a.0) / (b.0 - a.0))
+}
+
+/// Centered moving average over `2 * half + 1` samples. Missing values are skipped.
+fn smooth(values: &[Option<f64>], half: usize) -> Vec<Option<f64>> {
+    (0..values.len())
+        .map(|i| {
+            values[i]?;
+            let end = std::cmp::min(values.len(), i.saturating_add(half).saturating_add(1));
+            let window = &values[i.saturating_sub(half)..end];
+            let (sum, n) = window
+                .iter()
+                .filter_map(|v| *v)
+                .fold((0., 0), |(sum, n), v| (sum + v, n + 1));
+            Some(sum / n as f64)
+        })
+        .collect()
+}
+
+/// Resample `points` (sorted by time) every `step` meters of distance travelled. Elevation is
+/// smoothed with a moving average over `window` meters to reduce GPS noise.
+pub fn resample(points: &[types::GeoPoint], step: f64, window: f64) -> Resampled {
+    let mut total = 0.;
+    let mut elevations = vec![];
+    let mut speeds = vec![];
+    let mut prev: Option<&types::GeoPoint> = None;
+    for p in points {
+        if let Some(prev) = prev {
+            total += geo::point_distance(prev, p);
+        }
+        prev = Some(p);
+        if let Some(ele) = p.ele {
+            elevations.push((total, ele));
+        }
+        if let Some(spd) = p.spd {
+            speeds.push((total, spd));
+        }
+    }
+
+    let step = step.max(total / MAX_SAMPLES);
+    let mut distance = vec![];
+    let mut d = 0.;
+    while d < total {
+        distance.push(d);
+        d += step;
+    }
+    if !points.is_empty() {
+        distance.push(total);
+    }
+
+    let (mut ehint, mut shint) = (0, 0);
+    let elevation = distance
+        .iter()
+        .map(|d| interpolate(&elevations, *d, &mut ehint))
+        .collect::<Vec<Option<f64>>>();
+    let speed = distance
+        .iter()
+        .map(|d| interpolate(&speeds, *d, &mut shint))
+        .collect();
+    let elevation = smooth(&elevation, (window / step / 2.).round() as usize);
+
+    let (mut ascent, mut descent) = (0., 0.);
+    let known = elevation.iter().filter_map(|e| *e).collect::<Vec<f64>>();
+    for w in known.windows(2) {
+        if w[1] > w[0] {
+            ascent += w[1] - w[0];
+        } else {
+            descent += w[0] - w[1];
+        }
+    }
+
+    Resampled {
+        step: step,
+        distance: distance,
+        elevation: elevation,
+        speed: speed,
+        ascent: ascent,
+        descent: descent,
+    }
+}
+
+impl Resampled {
+    /// (distance, elevation) pairs and the corresponding speeds, for samples with elevation.
+    pub fn chart_data(&self) -> (Vec<(f64, f64)>, Vec<Option<f64>>) {
+        self.distance
+            .iter()
+            .zip(self.elevation.iter())
+            .zip(self.speed.iter())
+            .filter_map(|((d, e), s)| e.map(|e| ((*d, e), *s)))
+            .unzip()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A point on the equator, `meters` east of 0°.
+    fn point(meters: f64, ele: Option<f64>, spd: Option<f64>) -> types::GeoPoint {
+        types::GeoPoint {
+            id: None,
+            lat: 0.,
+            long: (meters / geo::EARTH_RADIUS).to_degrees(),
+            spd: spd,
+            ele: ele,
+            accuracy: None,
+            time: chrono::DateTime::from_utc(
+                chrono::NaiveDateTime::from_timestamp(1_600_000_000 + meters as i64, 0),
+                chrono::Utc,
+            ),
+            note: None,
+        }
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-6
+    }
+
+    #[test]
+    fn test_smooth() {
+        let values = vec![Some(0.), Some(3.), None, Some(6.), Some(9.)];
+        assert_eq!(smooth(&values, 0), values);
+        assert_eq!(
+            smooth(&values, 1),
+            vec![Some(1.5), Some(1.5), None, Some(7.5), Some(7.5)]
+        );
+        // Windows larger than the series, even absurdly large ones.
+        let all = Some(4.5);
+        assert_eq!(smooth(&values, usize::MAX), vec![all, all, None, all, all]);
+        assert!(smooth(&[], 3).is_empty());
+    }
+
+    #[test]
+    fn test_resample() {
+        let points = vec![
+            point(0., Some(100.), Some(10.)),
+            point(100., Some(110.), None),
+            point(250., Some(95.), Some(20.)),
+        ];
+        let r = resample(&points, 50., 0.);
+        assert_eq!(r.step, 50.);
+        assert_eq!(r.distance.len(), 6);
+        for (d, expected) in r.distance.iter().zip(&[0., 50., 100., 150., 200., 250.]) {
+            assert!(close(*d, *expected), "{} != {}", d, expected);
+        }
+        let elevation = r.elevation.iter().map(|e| e.unwrap()).collect::<Vec<f64>>();
+        for (e, expected) in elevation.iter().zip(&[100., 105., 110., 105., 100., 95.]) {
+            assert!(close(*e, *expected), "{} != {}", e, expected);
+        }
+        // Speed is interpolated between the points that have one.
+        assert!(close(r.speed[1].unwrap(), 12.));
+        assert!(close(r.ascent, 10.));
+        assert!(close(r.descent, 15.));
+
+        let (chart, speeds) = r.chart_data();
+        assert_eq!(chart.len(), 6);
+        assert_eq!(speeds.len(), 6);
+    }
+
+    #[test]
+    fn test_resample_smoothing() {
+        // Noise of +-1 m is mostly smoothed away.
+        let points = (0..=100)
+            .map(|i| {
+                point(
+                    i as f64 * 10.,
+                    Some(if i % 2 == 0 { 1. } else { -1. }),
+                    None,
+                )
+            })
+            .collect::<Vec<_>>();
+        let raw = resample(&points, 10., 0.);
+        assert!(close(raw.ascent, 100.));
+        let smoothed = resample(&points, 10., 100.);
+        assert!(smoothed.ascent < raw.ascent / 5.);
+    }
+
+    #[test]
+    fn test_resample_limits() {
+        let empty = resample(&[], 10., 100.);
+        assert!(empty.distance.is_empty());
+        let single = resample(&[point(0., None, None)], 10., 100.);
+        assert_eq!(single.distance, vec![0.]);
+        assert_eq!(single.elevation, vec![None]);
+        // Long tracks get a larger step.
+        let long = resample(&[point(0., None, None), point(1e6, None, None)], 1., 0.);
+        assert!(long.distance.len() as f64 <= MAX_SAMPLES + 2.);
+    }
+}
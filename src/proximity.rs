@@ -0,0 +1,33 @@
+use crate::db;
+use crate::geo;
+use crate::notifier;
+use crate::types;
+
+/// Check the proximity rules involving `client` after it logged `point` with `secret`, and send
+/// an event for every rule whose state changed.
+pub fn evaluate(
+    db: &db::DBQuery,
+    notify_manager: &notifier::NotifyManager,
+    client: &str,
+    secret: &Option<String>,
+    point: &types::GeoPoint,
+) -> Result<(), postgres::Error> {
+    for check in db.proximity_checks(client, secret)? {
+        let other = match db.latest_point(&check.counterpart, &check.counterpart_secret)? {
+            Some(other) => other,
+            None => continue,
+        };
+        let distance = geo::point_distance(point, &other);
+        let inside = distance <= check.rule.radius;
+        if inside == check.rule.inside {
+            continue;
+        }
+        // Another request may have been faster.
+        if db.set_proximity_state(check.rule.id, inside)? == 0 {
+            continue;
+        }
+        let event = types::ProximityEvent::new(&check.rule, inside, distance, point.time);
+        notify_manager.send_proximity_event(db, &event)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,327 @@
+//! Rate limits for logging points, per client name and per source IP.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time;
+
+/// Buckets are only cleaned up once there are this many.
+const MAX_IDLE_BUCKETS: usize = 1024;
+/// Upper bound for the number of buckets. Beyond 7/8 of it, the fullest buckets are dropped
+/// down to 3/4 of it.
+const MAX_BUCKETS: usize = 65536;
+
+struct Bucket {
+    tokens: f64,
+    updated: time::Instant,
+}
+
+struct Buckets {
+    buckets: HashMap<String, Bucket>,
+    /// Clean up once there are this many buckets, so that the cost of cleaning up is spread over
+    /// as many calls as there are buckets left.
+    cleanup_at: usize,
+}
+
+/// Token buckets refilled at `rate` points per second, holding at most `burst` points.
+pub struct Limiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<Buckets>,
+}
+
+impl Limiter {
+    /// A limiter allowing `per_minute` points per minute on average, and `burst` points at once.
+    pub fn new(per_minute: f64, burst: f64) -> Limiter {
+        Limiter {
+            rate: per_minute / 60.,
+            burst: burst.max(1.),
+            buckets: Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                cleanup_at: MAX_IDLE_BUCKETS,
+            }),
+        }
+    }
+
+    /// Tokens of `bucket` at `now`.
+    fn refilled(&self, bucket: &Bucket, now: time::Instant) -> f64 {
+        (bucket.tokens + self.rate * now.duration_since(bucket.updated).as_secs_f64())
+            .min(self.burst)
+    }
+
+    /// Apply `f` to the refilled bucket of `key`, creating it if necessary.
+    fn with_bucket<R, F: FnOnce(&mut Bucket) -> R>(&self, key: &str, f: F) -> R {
+        let now = time::Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let buckets = &mut *buckets;
+        if !buckets.buckets.contains_key(key) && buckets.buckets.len() >= buckets.cleanup_at {
+            // Full buckets are the same as missing ones.
+            buckets
+                .buckets
+                .retain(|_, b| self.refilled(b, now) < self.burst);
+            if buckets.buckets.len() >= MAX_BUCKETS / 8 * 7 {
+                // Forgetting the fullest buckets allows the fewest extra points.
+                let mut tokens: Vec<(f64, String)> = buckets
+                    .buckets
+                    .iter()
+                    .map(|(k, b)| (self.refilled(b, now), k.clone()))
+                    .collect();
+                tokens.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+                let excess = tokens.len() - MAX_BUCKETS / 4 * 3;
+                for (_, k) in tokens.into_iter().take(excess) {
+                    buckets.buckets.remove(&k);
+                }
+            }
+            buckets.cleanup_at = (2 * buckets.buckets.len()).clamp(MAX_IDLE_BUCKETS, MAX_BUCKETS);
+        }
+        let bucket = buckets.buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        bucket.tokens = self.refilled(bucket, now);
+        bucket.updated = now;
+        f(bucket)
+    }
+
+    /// The time after which `bucket` will have enough points for `n`, or `Ok` if it has
+    /// already. Batches larger than the burst size are allowed from a full bucket.
+    fn wait(&self, bucket: &Bucket, n: f64) -> Result<(), time::Duration> {
+        let needed = n.min(self.burst);
+        if bucket.tokens >= needed {
+            return Ok(());
+        }
+        let missing = needed - bucket.tokens;
+        if self.rate > 0. {
+            Err(time::Duration::from_secs_f64(missing / self.rate))
+        } else {
+            Err(time::Duration::from_secs(3600))
+        }
+    }
+
+    /// Check whether `n` points could be taken from the bucket of `key`, without taking them.
+    /// Returns the time after which they could otherwise.
+    pub fn check(&self, key: &str, n: f64) -> Result<(), time::Duration> {
+        self.with_bucket(key, |bucket| self.wait(bucket, n))
+    }
+
+    /// Take `n` points from the bucket of `key`. If there are not enough, nothing is taken, and
+    /// the time after which there will be is returned.
+    pub fn take(&self, key: &str, n: f64) -> Result<(), time::Duration> {
+        self.with_bucket(key, |bucket| {
+            self.wait(bucket, n)?;
+            // A batch larger than the burst size leaves the bucket in debt.
+            bucket.tokens -= n;
+            Ok(())
+        })
+    }
+
+    /// Return `n` points taken before to the bucket of `key`.
+    pub fn refund(&self, key: &str, n: f64) {
+        self.with_bucket(key, |bucket| {
+            bucket.tokens = (bucket.tokens + n).min(self.burst);
+        })
+    }
+}
+
+/// Rate limits for the log endpoints. Managed by Rocket.
+pub struct Limits {
+    pub per_client: Option<Limiter>,
+    pub per_ip: Option<Limiter>,
+    /// Maximum number of points in a `logjson` request.
+    pub max_batch: usize,
+    /// Proxies whose `X-Real-IP` header is trusted.
+    pub trusted_proxies: Vec<std::net::IpAddr>,
+}
+
+impl Limits {
+    pub fn from_rocket(cfg: &rocket::Config) -> Limits {
+        // The burst size defaults to one minute's worth of points.
+        let limiter = |key: &str| {
+            let burst = cfg.get_float(&format!("{}_burst", key)).ok();
+            cfg.get_float(key)
+                .ok()
+                .filter(|r| *r > 0.)
+                .map(|r| Limiter::new(r, burst.unwrap_or(r)))
+        };
+        // By default, a proxy on the same host is trusted.
+        let trusted_proxies = match cfg.get_slice("trusted_proxies") {
+            Ok(proxies) => proxies
+                .iter()
+                .filter_map(|p| match p.as_str().map(std::net::IpAddr::from_str) {
+                    Some(Ok(ip)) => Some(ip),
+                    _ => {
+                        log::warn!("Ignoring invalid trusted proxy {}", p);
+                        None
+                    }
+                })
+                .collect(),
+            Err(_) => vec![
+                std::net::Ipv4Addr::LOCALHOST.into(),
+                std::net::Ipv6Addr::LOCALHOST.into(),
+            ],
+        };
+        Limits {
+            per_client: limiter("rate_limit_client"),
+            per_ip: limiter("rate_limit_ip"),
+            max_batch: cfg.get_int("max_logjson_points").unwrap_or(10000).max(1) as usize,
+            trusted_proxies: trusted_proxies,
+        }
+    }
+
+    /// Check whether `client` may log `n` points from `ip`, and charge them to both limits if so.
+    /// Returns the time to wait otherwise.
+    pub fn check(
+        &self,
+        client: &str,
+        ip: &Option<std::net::IpAddr>,
+        n: usize,
+    ) -> Result<(), time::Duration> {
+        let n = n as f64;
+        let per_ip = match (self.per_ip.as_ref(), ip) {
+            (Some(limiter), Some(ip)) => Some((limiter, ip.to_string())),
+            _ => None,
+        };
+        // Check both limits before taking from either, so that points refused by one limit
+        // aren't charged to the other.
+        if let Some(limiter) = self.per_client.as_ref() {
+            if let Err(wait) = limiter.check(client, n) {
+                log::warn!("Rate limit for client {} exceeded by {} points", client, n);
+                return Err(wait);
+            }
+        }
+        if let Some((limiter, ip)) = per_ip.as_ref() {
+            if let Err(wait) = limiter.check(ip, n) {
+                log::warn!(
+                    "Rate limit for {} exceeded by {} points of client {}",
+                    ip,
+                    n,
+                    client
+                );
+                return Err(wait);
+            }
+        }
+        // Concurrent requests may have taken points since.
+        if let Some(limiter) = self.per_client.as_ref() {
+            limiter.take(client, n)?;
+        }
+        if let Some((limiter, ip)) = per_ip.as_ref() {
+            if let Err(wait) = limiter.take(ip, n) {
+                if let Some(limiter) = self.per_client.as_ref() {
+                    limiter.refund(client, n);
+                }
+                return Err(wait);
+            }
+        }
+        Ok(())
+    }
+
+    /// Return `n` points charged by `check` that weren't stored after all.
+    pub fn refund(&self, client: &str, ip: &Option<std::net::IpAddr>, n: usize) {
+        let n = n as f64;
+        if let Some(limiter) = self.per_client.as_ref() {
+            limiter.refund(client, n);
+        }
+        if let (Some(limiter), Some(ip)) = (self.per_ip.as_ref(), ip) {
+            limiter.refund(&ip.to_string(), n);
+        }
+    }
+}
+
+/// The address of the client, or of the original client if the request was forwarded by one
+/// of the `trusted_proxies`, which set `X-Real-IP`. The header is ignored from anyone else, so
+/// that clients can't pick their own address.
+pub struct ClientIp(pub Option<std::net::IpAddr>);
+
+impl<'a, 'r> rocket::request::FromRequest<'a, 'r> for ClientIp {
+    type Error = ();
+
+    fn from_request(request: &'a rocket::Request<'r>) -> rocket::request::Outcome<Self, ()> {
+        let remote = request.remote().map(|addr| addr.ip());
+        let trusted = match (request.guard::<rocket::State<Limits>>(), remote) {
+            (rocket::Outcome::Success(limits), Some(remote)) => {
+                limits.trusted_proxies.contains(&remote)
+            }
+            _ => false,
+        };
+        let ip = if trusted {
+            request.real_ip().or(remote)
+        } else {
+            remote
+        };
+        rocket::Outcome::Success(ClientIp(ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limiter_take() {
+        let limiter = Limiter::new(0., 10.);
+        assert!(limiter.take("a", 6.).is_ok());
+        assert!(limiter.take("a", 4.).is_ok());
+        assert!(limiter.take("a", 1.).is_err());
+        // Other keys have their own bucket.
+        assert!(limiter.take("b", 10.).is_ok());
+    }
+
+    #[test]
+    fn test_limiter_refused_takes_nothing() {
+        let limiter = Limiter::new(60., 10.);
+        assert!(limiter.take("a", 8.).is_ok());
+        let wait = limiter.take("a", 5.).unwrap_err();
+        assert!(wait > time::Duration::from_secs(2) && wait <= time::Duration::from_secs(3));
+        assert!(limiter.take("a", 2.).is_ok());
+    }
+
+    #[test]
+    fn test_limiter_large_batch() {
+        let limiter = Limiter::new(60., 10.);
+        // A full bucket allows a batch larger than the burst size, but is in debt afterwards.
+        assert!(limiter.take("a", 25.).is_ok());
+        let wait = limiter.take("a", 1.).unwrap_err();
+        assert!(wait > time::Duration::from_secs(15));
+    }
+
+    #[test]
+    fn test_limiter_check_refund() {
+        let limiter = Limiter::new(0., 10.);
+        assert!(limiter.check("a", 10.).is_ok());
+        assert!(limiter.check("a", 10.).is_ok());
+        assert!(limiter.take("a", 10.).is_ok());
+        assert!(limiter.check("a", 1.).is_err());
+        limiter.refund("a", 20.);
+        assert!(limiter.take("a", 10.).is_ok());
+        assert!(limiter.take("a", 1.).is_err());
+    }
+
+    #[test]
+    fn test_limiter_bounded() {
+        let limiter = Limiter::new(0., 10.);
+        for i in 0..MAX_BUCKETS + 10 {
+            assert!(limiter.take(&i.to_string(), 1.).is_ok());
+        }
+        assert!(limiter.buckets.lock().unwrap().buckets.len() <= MAX_BUCKETS);
+    }
+
+    #[test]
+    fn test_limits_check() {
+        let limits = Limits {
+            per_client: Some(Limiter::new(0., 10.)),
+            per_ip: Some(Limiter::new(0., 5.)),
+            max_batch: 100,
+            trusted_proxies: vec![],
+        };
+        let ip = Some(std::net::Ipv4Addr::new(192, 0, 2, 1).into());
+        assert!(limits.check("client", &ip, 5).is_ok());
+        // Refused by the IP limit, so the client isn't charged.
+        assert!(limits.check("client", &ip, 5).is_err());
+        assert!(limits.check("client", &None, 5).is_ok());
+        assert!(limits.check("client", &None, 1).is_err());
+
+        limits.refund("client", &ip, 3);
+        assert!(limits.check("client", &ip, 3).is_ok());
+        assert!(limits.check("client", &ip, 1).is_err());
+    }
+}
@@ -0,0 +1,493 @@
+use crate::geo;
+use crate::types;
+
+use std::f64::consts::PI;
+use std::fmt::Write;
+
+/// Largest accepted image width or height.
+pub const MAX_SIZE: u32 = 4096;
+
+const BACKGROUND: Rgb = Rgb(255, 255, 255);
+const TRACK: Rgb = Rgb(0, 92, 230);
+const START: Rgb = Rgb(40, 167, 69);
+const END: Rgb = Rgb(220, 53, 69);
+const AXIS: Rgb = Rgb(120, 120, 120);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Rgb(u8, u8, u8);
+
+impl Rgb {
+    fn hex(&self) -> String {
+        format!("#{:02x}{:02x}{:02x}", self.0, self.1, self.2)
+    }
+}
+
+/// Color for a speed between 0 and `max`: blue (slow) over green to red (fast).
+fn speed_color(spd: f64, max: f64) -> Rgb {
+    let f = if max > 0. {
+        (spd / max).max(0.).min(1.)
+    } else {
+        0.
+    };
+    let (from, to, f) = if f < 0.5 {
+        ((0., 92., 230.), (40., 167., 69.), f * 2.)
+    } else {
+        ((40., 167., 69.), (220., 53., 69.), (f - 0.5) * 2.)
+    };
+    let mix = |a: f64, b: f64| (a + (b - a) * f).round() as u8;
+    Rgb(mix(from.0, to.0), mix(from.1, to.1), mix(from.2, to.2))
+}
+
+/// What to draw.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Chart {
+    /// The track as seen from above.
+    Track,
+    /// Elevation over distance.
+    Profile,
+}
+
+impl Chart {
+    pub fn parse(s: &str) -> Option<Chart> {
+        match s {
+            "track" => Some(Chart::Track),
+            "profile" => Some(Chart::Profile),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RenderOptions {
+    pub width: u32,
+    pub height: u32,
+    /// Space between the image border and the drawing, in pixels.
+    pub padding: u32,
+    /// Color segments by speed instead of using a single color.
+    pub color_by_speed: bool,
+    pub chart: Chart,
+}
+
+/// Elements of a rendered image, in image coordinates.
+#[derive(Debug, Clone)]
+enum Shape {
+    Line {
+        points: Vec<(f64, f64)>,
+        color: Rgb,
+        width: f64,
+    },
+    /// Closed and filled polygon.
+    Area { points: Vec<(f64, f64)>, color: Rgb },
+    Circle {
+        center: (f64, f64),
+        radius: f64,
+        color: Rgb,
+    },
+    /// Only rendered in SVG output.
+    Label {
+        at: (f64, f64),
+        text: String,
+        anchor: &'static str,
+    },
+}
+
+/// An image, independent of the output format.
+pub struct Scene {
+    width: u32,
+    height: u32,
+    shapes: Vec<Shape>,
+}
+
+impl Scene {
+    fn new(width: u32, height: u32) -> Scene {
+        Scene {
+            width: width,
+            height: height,
+            shapes: vec![],
+        }
+    }
+
+    /// Draw a line through `points`. With `speeds`, every segment is colored by the speed at its
+    /// start.
+    fn polyline(&mut self, points: &[(f64, f64)], speeds: Option<&[Option<f64>]>, width: f64) {
+        let speeds = match speeds {
+            Some(speeds) => speeds,
+            None => {
+                self.shapes.push(Shape::Line {
+                    points: points.to_vec(),
+                    color: TRACK,
+                    width: width,
+                });
+                return;
+            }
+        };
+        let max = speeds.iter().filter_map(|s| *s).fold(0., f64::max);
+        // Consecutive segments of the same color are joined.
+        let mut current: Option<(Rgb, Vec<(f64, f64)>)> = None;
+        for (i, w) in points.windows(2).enumerate() {
+            let color = speed_color(speeds[i].unwrap_or(0.), max);
+            if let Some((c, line)) = current.as_mut() {
+                if *c == color {
+                    line.push(w[1]);
+                    continue;
+                }
+            }
+            if let Some((c, line)) = current.take() {
+                self.shapes.push(Shape::Line {
+                    points: line,
+                    color: c,
+                    width: width,
+                });
+            }
+            current = Some((color, vec![w[0], w[1]]));
+        }
+        if let Some((c, line)) = current {
+            self.shapes.push(Shape::Line {
+                points: line,
+                color: c,
+                width: width,
+            });
+        }
+    }
+
+    /// Start and end markers.
+    fn markers(&mut self, points: &[(f64, f64)]) {
+        if let (Some(first), Some(last)) = (points.first(), points.last()) {
+            self.shapes.push(Shape::Circle {
+                center: *first,
+                radius: 5.,
+                color: START,
+            });
+            self.shapes.push(Shape::Circle {
+                center: *last,
+                radius: 5.,
+                color: END,
+            });
+        }
+    }
+
+    pub fn to_svg(&self) -> String {
+        let mut svg = String::new();
+        write!(
+            svg,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}">"#,
+            w = self.width,
+            h = self.height
+        )
+        .ok();
+        write!(
+            svg,
+            r#"<rect width="100%" height="100%" fill="{}"/>"#,
+            BACKGROUND.hex()
+        )
+        .ok();
+        let coords = |points: &[(f64, f64)]| {
+            points
+                .iter()
+                .map(|(x, y)| format!("{:.1},{:.1}", x, y))
+                .collect::<Vec<String>>()
+                .join(" ")
+        };
+        for shape in self.shapes.iter() {
+            match shape {
+                Shape::Line {
+                    points,
+                    color,
+                    width,
+                } => write!(
+                    svg,
+                    r#"<polyline points="{}" fill="none" stroke="{}" stroke-width="{}" stroke-linecap="round" stroke-linejoin="round"/>"#,
+                    coords(points),
+                    color.hex(),
+                    width
+                ),
+                Shape::Area { points, color } => write!(
+                    svg,
+                    r#"<polygon points="{}" fill="{}" fill-opacity="0.3"/>"#,
+                    coords(points),
+                    color.hex()
+                ),
+                Shape::Circle {
+                    center,
+                    radius,
+                    color,
+                } => write!(
+                    svg,
+                    r#"<circle cx="{:.1}" cy="{:.1}" r="{}" fill="{}" stroke="white" stroke-width="2"/>"#,
+                    center.0,
+                    center.1,
+                    radius,
+                    color.hex()
+                ),
+                Shape::Label { at, text, anchor } => write!(
+                    svg,
+                    r#"<text x="{:.1}" y="{:.1}" text-anchor="{}" font-family="sans-serif" font-size="12" fill="{}">{}</text>"#,
+                    at.0,
+                    at.1,
+                    anchor,
+                    AXIS.hex(),
+                    text
+                ),
+            }
+            .ok();
+        }
+        svg.push_str("</svg>");
+        svg
+    }
+
+    pub fn to_png(&self) -> Result<Vec<u8>, String> {
+        use tiny_skia::{
+            Color, FillRule, LineCap, LineJoin, Paint, PathBuilder, Stroke, Transform,
+        };
+
+        let mut pixmap = tiny_skia::Pixmap::new(self.width, self.height)
+            .ok_or_else(|| "Invalid image size".to_string())?;
+        pixmap.fill(Color::from_rgba8(
+            BACKGROUND.0,
+            BACKGROUND.1,
+            BACKGROUND.2,
+            255,
+        ));
+        let paint = |c: Rgb, alpha: u8| {
+            let mut paint = Paint::default();
+            paint.set_color_rgba8(c.0, c.1, c.2, alpha);
+            paint.anti_alias = true;
+            paint
+        };
+        let path = |points: &[(f64, f64)], close: bool| {
+            let mut pb = PathBuilder::new();
+            for (i, (x, y)) in points.iter().enumerate() {
+                if i == 0 {
+                    pb.move_to(*x as f32, *y as f32);
+                } else {
+                    pb.line_to(*x as f32, *y as f32);
+                }
+            }
+            if close {
+                pb.close();
+            }
+            pb.finish()
+        };
+        for shape in self.shapes.iter() {
+            match shape {
+                Shape::Line {
+                    points,
+                    color,
+                    width,
+                } => {
+                    if let Some(p) = path(points, false) {
+                        let stroke = Stroke {
+                            width: *width as f32,
+                            line_cap: LineCap::Round,
+                            line_join: LineJoin::Round,
+                            ..Stroke::default()
+                        };
+                        pixmap.stroke_path(
+                            &p,
+                            &paint(*color, 255),
+                            &stroke,
+                            Transform::identity(),
+                            None,
+                        );
+                    }
+                }
+                Shape::Area { points, color } => {
+                    if let Some(p) = path(points, true) {
+                        pixmap.fill_path(
+                            &p,
+                            &paint(*color, 77),
+                            FillRule::Winding,
+                            Transform::identity(),
+                            None,
+                        );
+                    }
+                }
+                Shape::Circle {
+                    center,
+                    radius,
+                    color,
+                } => {
+                    let (x, y) = (center.0 as f32, center.1 as f32);
+                    if let Some(p) = PathBuilder::from_circle(x, y, *radius as f32 + 2.) {
+                        pixmap.fill_path(
+                            &p,
+                            &paint(BACKGROUND, 255),
+                            FillRule::Winding,
+                            Transform::identity(),
+                            None,
+                        );
+                    }
+                    if let Some(p) = PathBuilder::from_circle(x, y, *radius as f32) {
+                        pixmap.fill_path(
+                            &p,
+                            &paint(*color, 255),
+                            FillRule::Winding,
+                            Transform::identity(),
+                            None,
+                        );
+                    }
+                }
+                // tiny-skia has no text rendering.
+                Shape::Label { .. } => {}
+            }
+        }
+        pixmap
+            .encode_png()
+            .map_err(|e| format!("Couldn't encode PNG: {}", e))
+    }
+}
+
+/// Web mercator projection, y growing southwards. The result is in radians.
+fn mercator(lat: f64, long: f64) -> (f64, f64) {
+    let lat = lat.max(-85.0511).min(85.0511).to_radians();
+    (long.to_radians(), -(PI / 4. + lat / 2.).tan().ln())
+}
+
+/// Scale and translate `coords` so that they fit into the drawing area of `opts`, centered. If
+/// `keep_aspect` is set, both axes are scaled equally.
+fn fit(coords: &[(f64, f64)], opts: &RenderOptions, keep_aspect: bool) -> Vec<(f64, f64)> {
+    let (mut minx, mut miny) = (std::f64::INFINITY, std::f64::INFINITY);
+    let (mut maxx, mut maxy) = (std::f64::NEG_INFINITY, std::f64::NEG_INFINITY);
+    for (x, y) in coords {
+        minx = minx.min(*x);
+        maxx = maxx.max(*x);
+        miny = miny.min(*y);
+        maxy = maxy.max(*y);
+    }
+    let w = (opts.width as f64 - 2. * opts.padding as f64).max(1.);
+    let h = (opts.height as f64 - 2. * opts.padding as f64).max(1.);
+    // A single point, or a straight line, is placed in the middle.
+    let (spanx, spany) = ((maxx - minx).max(1e-12), (maxy - miny).max(1e-12));
+    let (mut sx, mut sy) = (w / spanx, h / spany);
+    if keep_aspect {
+        sx = sx.min(sy);
+        sy = sx;
+    }
+    let (offx, offy) = (
+        opts.padding as f64 + (w - (maxx - minx) * sx) / 2.,
+        opts.padding as f64 + (h - (maxy - miny) * sy) / 2.,
+    );
+    coords
+        .iter()
+        .map(|(x, y)| (offx + (x - minx) * sx, offy + (y - miny) * sy))
+        .collect()
+}
+
+fn line_width(opts: &RenderOptions) -> f64 {
+    (opts.width.min(opts.height) as f64 / 150.).max(2.).min(6.)
+}
+
+/// Render the track formed by `points` (sorted by time).
+fn render_track(points: &[types::GeoPoint], opts: &RenderOptions) -> Scene {
+    let mut scene = Scene::new(opts.width, opts.height);
+    let projected = points
+        .iter()
+        .map(|p| mercator(p.lat, p.long))
+        .collect::<Vec<(f64, f64)>>();
+    let coords = fit(&projected, opts, true);
+    let speeds = points.iter().map(|p| p.spd).collect::<Vec<Option<f64>>>();
+    scene.polyline(
+        &coords,
+        if opts.color_by_speed {
+            Some(&speeds)
+        } else {
+            None
+        },
+        line_width(opts),
+    );
+    scene.markers(&coords);
+    scene
+}
+
+/// Render elevation over distance for the points of `points` (sorted by time) that have an
+/// elevation.
+fn render_profile(points: &[types::GeoPoint], opts: &RenderOptions) -> Scene {
+    let mut distance = 0.;
+    let mut prev: Option<&types::GeoPoint> = None;
+    let mut profile = vec![];
+    let mut speeds = vec![];
+    for p in points {
+        if let Some(prev) = prev {
+            distance += geo::point_distance(prev, p);
+        }
+        prev = Some(p);
+        if let Some(ele) = p.ele {
+            profile.push((distance, ele));
+            speeds.push(p.spd);
+        }
+    }
+    render_chart(&profile, &speeds, opts)
+}
+
+/// Render a profile chart of (distance in meters, elevation in meters) pairs.
+pub fn render_chart(profile: &[(f64, f64)], speeds: &[Option<f64>], opts: &RenderOptions) -> Scene {
+    let mut scene = Scene::new(opts.width, opts.height);
+    if profile.is_empty() {
+        return scene;
+    }
+    // Elevation grows upwards.
+    let flipped = profile
+        .iter()
+        .map(|(d, e)| (*d, -*e))
+        .collect::<Vec<(f64, f64)>>();
+    let coords = fit(&flipped, opts, false);
+
+    let bottom = opts.height as f64 - opts.padding as f64;
+    let (left, right) = (opts.padding as f64, opts.width as f64 - opts.padding as f64);
+    let mut area = coords.clone();
+    area.push((coords[coords.len() - 1].0, bottom));
+    area.push((coords[0].0, bottom));
+    scene.shapes.push(Shape::Area {
+        points: area,
+        color: TRACK,
+    });
+    scene.shapes.push(Shape::Line {
+        points: vec![(left, opts.padding as f64), (left, bottom), (right, bottom)],
+        color: AXIS,
+        width: 1.,
+    });
+    scene.polyline(
+        &coords,
+        if opts.color_by_speed {
+            Some(speeds)
+        } else {
+            None
+        },
+        line_width(opts) / 2.,
+    );
+
+    let (min, max) = profile.iter().fold(
+        (std::f64::INFINITY, std::f64::NEG_INFINITY),
+        |(min, max), (_, e)| (min.min(*e), max.max(*e)),
+    );
+    let total = profile[profile.len() - 1].0;
+    let labels = vec![
+        (
+            (left + 4., opts.padding as f64 + 12.),
+            format!("{:.0} m", max),
+            "start",
+        ),
+        ((left + 4., bottom - 4.), format!("{:.0} m", min), "start"),
+        (
+            (right, bottom + 14.),
+            format!("{:.1} km", total / 1000.),
+            "end",
+        ),
+    ];
+    for (at, text, anchor) in labels {
+        scene.shapes.push(Shape::Label {
+            at: at,
+            text: text,
+            anchor: anchor,
+        });
+    }
+    scene
+}
+
+/// Render `points` according to `opts`.
+pub fn render(points: &[types::GeoPoint], opts: &RenderOptions) -> Scene {
+    match opts.chart {
+        Chart::Track => render_track(points, opts),
+        Chart::Profile => render_profile(points, opts),
+    }
+}
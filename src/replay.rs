@@ -0,0 +1,130 @@
+use crate::db;
+use crate::logging;
+use crate::notifier;
+use crate::proximity;
+use crate::types;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Upper bound for the speed factor. Faster replays are hardly "live" anymore.
+pub const MAX_SPEED: f64 = 3600.;
+/// Lower bound for the speed factor, keeping the duration of replays within reason.
+pub const MIN_SPEED: f64 = 0.01;
+
+/// Number of replays currently running.
+static RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+/// Counts a running replay until it is dropped.
+struct Running;
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        RUNNING.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A track to be re-emitted as if it was being logged right now.
+pub struct Replay {
+    /// Target client and secret.
+    pub client: String,
+    pub secret: Option<String>,
+    /// Source points, sorted by time.
+    pub points: Vec<types::GeoPoint>,
+    /// Playback speed; 2 replays a track in half of the original time.
+    pub speed: f64,
+    /// Only send points to live subscribers, without storing them.
+    pub live_only: bool,
+}
+
+impl Replay {
+    /// Time at which point `i` is emitted, relative to the start of the replay.
+    fn offset(&self, i: usize) -> chrono::Duration {
+        let elapsed = self.points[i].time - self.points[0].time;
+        chrono::Duration::milliseconds((elapsed.num_milliseconds() as f64 / self.speed) as i64)
+    }
+
+    /// Duration of the whole replay.
+    pub fn duration(&self) -> chrono::Duration {
+        if self.points.is_empty() {
+            chrono::Duration::zero()
+        } else {
+            self.offset(self.points.len() - 1)
+        }
+    }
+}
+
+/// Replay in a background thread, using its own database connection. Points are emitted paced
+/// by their original time gaps (divided by `speed`), with timestamps shifted to the time of
+/// emission. Returns false without starting the replay if `max_running` replays are running
+/// already.
+pub fn start(
+    replay: Replay,
+    db_url: String,
+    secrets: db::SecretHasher,
+    notify_manager: notifier::NotifyManager,
+    max_running: usize,
+) -> bool {
+    if RUNNING.fetch_add(1, Ordering::SeqCst) >= max_running {
+        RUNNING.fetch_sub(1, Ordering::SeqCst);
+        return false;
+    }
+    let running = Running;
+    let request_id = logging::request_id();
+    std::thread::spawn(move || {
+        let _running = running;
+        logging::with_request_id(request_id.as_deref(), || {
+            run(replay, db_url, secrets, notify_manager)
+        })
+    });
+    true
+}
+
+fn run(
+    replay: Replay,
+    db_url: String,
+    secrets: db::SecretHasher,
+    notify_manager: notifier::NotifyManager,
+) {
+    let conn = match postgres::Connection::connect(db_url, postgres::TlsMode::None) {
+        Ok(conn) => conn,
+        Err(e) => {
+            log::error!("replay: Couldn't connect to database: {}", e);
+            return;
+        }
+    };
+    let db = db::DBQuery(&conn, &secrets);
+    let start = chrono::Utc::now();
+
+    for i in 0..replay.points.len() {
+        let due = start + replay.offset(i);
+        if let Ok(wait) = (due - chrono::Utc::now()).to_std() {
+            std::thread::sleep(wait);
+        }
+        let mut point = replay.points[i].clone();
+        point.id = None;
+        point.time = due;
+
+        let result = if replay.live_only {
+            notify_manager.send_live_point(&db, &replay.client, &replay.secret, &point)
+        } else {
+            db.log_geopoint(&replay.client, &replay.secret, &point, &None, false)
+                .and_then(|_| {
+                    notify_manager.send_notification(&db, &replay.client, &replay.secret, Some(1))
+                })
+                .and_then(|_| {
+                    proximity::evaluate(
+                        &db,
+                        &notify_manager,
+                        &replay.client,
+                        &replay.secret,
+                        &point,
+                    )
+                    .map(|_| 0)
+                })
+        };
+        if let Err(e) = result {
+            log::warn!("replay: Stopping replay for {}: {}", replay.client, e);
+            return;
+        }
+    }
+}
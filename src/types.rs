@@ -15,6 +15,37 @@ pub struct GeoPoint {
 }
 
 impl GeoPoint {
+    /// Check that the point is worth storing: coordinates within range, finite numbers, and a
+    /// time between the start of GPS and a day from now.
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.lat.is_finite() && self.lat >= -90. && self.lat <= 90.) {
+            return Err(format!("latitude {} out of range", self.lat));
+        }
+        if !(self.long.is_finite() && self.long >= -180. && self.long <= 180.) {
+            return Err(format!("longitude {} out of range", self.long));
+        }
+        for (what, value) in &[
+            ("speed", self.spd),
+            ("elevation", self.ele),
+            ("accuracy", self.accuracy),
+        ] {
+            if let Some(v) = value {
+                if !v.is_finite() {
+                    return Err(format!("{} {} is not a finite number", what, v));
+                }
+            }
+        }
+        let earliest = chrono::DateTime::<chrono::Utc>::from_utc(
+            chrono::NaiveDate::from_ymd(1980, 1, 6).and_hms(0, 0, 0),
+            chrono::Utc,
+        );
+        let latest = chrono::Utc::now() + chrono::Duration::days(1);
+        if self.time < earliest || self.time > latest {
+            return Err(format!("time {} out of range", self.time.to_rfc3339()));
+        }
+        Ok(())
+    }
+
     fn to_gpx_waypoint(self) -> gpx::Waypoint {
         let mut wp = gpx::Waypoint::new(Point::new(self.long, self.lat));
         wp.description = Some(format!("{}", self.id.unwrap_or(-1)));
@@ -27,15 +58,22 @@ impl GeoPoint {
     }
 }
 
+/// Error of a `LiveUpdate` if nothing happened before the timeout. Other errors mean that live
+/// updates are unavailable.
+pub const LIVE_TIMEOUT: &str = "timeout, try again";
+
 /// Returned by the retrieve/live endpoint.
-#[derive(serde::Serialize, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
 pub struct LiveUpdate {
     #[serde(rename = "type")]
     typ: String, // always "GeoHubUpdate"
-    client: String,
-    last: Option<i32>,
-    geo: Option<GeoJSON>,
-    error: Option<String>,
+    pub client: String,
+    pub last: Option<i32>,
+    pub geo: Option<GeoJSON>,
+    pub error: Option<String>,
+    /// Only set for proximity/live.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event: Option<ProximityEvent>,
 }
 
 impl LiveUpdate {
@@ -51,6 +89,400 @@ impl LiveUpdate {
             last: last,
             geo: geo,
             error: err,
+            event: None,
+        }
+    }
+
+    pub fn with_event(mut self, event: Option<ProximityEvent>) -> LiveUpdate {
+        self.event = event;
+        self
+    }
+}
+
+/// State of the notifier thread, which delivers live updates.
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct NotifierStatus {
+    /// Whether it has a working database connection.
+    pub connected: bool,
+    /// Number of times the connection was re-established.
+    pub reconnects: u64,
+    /// Number of clients waiting for updates.
+    pub waiters: usize,
+    /// Last time the notifier loop ran.
+    pub heartbeat: Option<chrono::DateTime<chrono::Utc>>,
+    pub last_error: Option<String>,
+}
+
+/// Outcome of one readiness check.
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct ReadinessCheck {
+    pub ok: bool,
+    pub error: Option<String>,
+}
+
+impl ReadinessCheck {
+    pub fn from_result(result: Result<(), String>) -> ReadinessCheck {
+        ReadinessCheck {
+            ok: result.is_ok(),
+            error: result.err(),
+        }
+    }
+}
+
+/// Returned by the ready endpoint.
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct Readiness {
+    #[serde(rename = "type")]
+    typ: String, // always "GeoHubReadiness"
+    /// Whether all checks passed.
+    pub ready: bool,
+    pub database: ReadinessCheck,
+    pub notifier: ReadinessCheck,
+    pub assets: ReadinessCheck,
+    pub notifier_status: NotifierStatus,
+}
+
+impl Readiness {
+    pub fn new(
+        database: ReadinessCheck,
+        notifier: ReadinessCheck,
+        assets: ReadinessCheck,
+        notifier_status: NotifierStatus,
+    ) -> Readiness {
+        Readiness {
+            typ: "GeoHubReadiness".into(),
+            ready: database.ok && notifier.ok && assets.ok,
+            database: database,
+            notifier: notifier,
+            assets: assets,
+            notifier_status: notifier_status,
+        }
+    }
+}
+
+/// A proximity rule: `client` wants to know when `other` is within `radius` meters.
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct ProximityRule {
+    pub id: i32,
+    pub client: String,
+    pub other: String,
+    pub radius: f64,
+    /// Whether the clients are currently within `radius` of each other.
+    pub inside: bool,
+}
+
+/// Sent when two clients come closer than the radius of a proximity rule ("enter"), or move
+/// apart again ("leave").
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct ProximityEvent {
+    pub rule: i32,
+    pub client: String,
+    pub other: String,
+    pub event: String,
+    /// Distance in meters.
+    pub distance: f64,
+    pub time: chrono::DateTime<chrono::Utc>,
+}
+
+impl ProximityEvent {
+    pub fn new(
+        rule: &ProximityRule,
+        inside: bool,
+        distance: f64,
+        time: chrono::DateTime<chrono::Utc>,
+    ) -> ProximityEvent {
+        ProximityEvent {
+            rule: rule.id,
+            client: rule.client.clone(),
+            other: rule.other.clone(),
+            event: if inside { "enter" } else { "leave" }.into(),
+            distance: distance,
+            time: time,
+        }
+    }
+}
+
+/// Returned by the retrieve/at endpoint.
+#[derive(serde::Serialize, Debug)]
+pub struct PositionAt {
+    #[serde(rename = "type")]
+    typ: String, // always "GeoHubPosition"
+    client: String,
+    time: chrono::DateTime<chrono::Utc>,
+    /// Interpolated position, or null if unknown at `time`.
+    geo: Option<GeoFeature>,
+    /// Time distance to the nearest real point, in seconds.
+    nearest_fix: Option<f64>,
+    error: Option<String>,
+}
+
+impl PositionAt {
+    pub fn new(
+        client: String,
+        time: chrono::DateTime<chrono::Utc>,
+        point: Option<GeoPoint>,
+        nearest_fix: Option<chrono::Duration>,
+        err: Option<String>,
+    ) -> PositionAt {
+        PositionAt {
+            typ: "GeoHubPosition".into(),
+            client: client,
+            time: time,
+            geo: point.map(geofeature_from_point),
+            nearest_fix: nearest_fix.map(|d| d.num_milliseconds() as f64 / 1000.),
+            error: err,
+        }
+    }
+}
+
+/// Returned by the retrieve/profile endpoint. The series all have the same length.
+#[derive(serde::Serialize, Debug)]
+pub struct Profile {
+    #[serde(rename = "type")]
+    typ: String, // always "GeoHubProfile"
+    client: String,
+    /// Distance between samples, in meters.
+    step: f64,
+    ascent: f64,
+    descent: f64,
+    /// Cumulative distance in meters.
+    distance: Vec<f64>,
+    /// Smoothed elevation in meters.
+    elevation: Vec<Option<f64>>,
+    speed: Vec<Option<f64>>,
+}
+
+impl Profile {
+    pub fn new(client: String, p: crate::profile::Resampled) -> Profile {
+        Profile {
+            typ: "GeoHubProfile".into(),
+            client: client,
+            step: p.step,
+            ascent: p.ascent,
+            descent: p.descent,
+            distance: p.distance,
+            elevation: p.elevation,
+            speed: p.speed,
+        }
+    }
+}
+
+/// Body of a geotag request for photos that aren't uploaded.
+#[derive(serde::Deserialize, Debug)]
+pub struct GeotagRequest {
+    /// Photo timestamps, usually EXIF DateTimeOriginal values.
+    pub timestamps: Vec<String>,
+}
+
+/// Position of one photo.
+#[derive(serde::Serialize, Debug)]
+pub struct PhotoPosition {
+    /// Timestamp as supplied or read from the photo.
+    photo_time: Option<String>,
+    /// Time of the photo in UTC, after applying time zone and clock offset.
+    time: Option<chrono::DateTime<chrono::Utc>>,
+    geo: Option<GeoFeature>,
+    nearest_fix: Option<f64>,
+    error: Option<String>,
+}
+
+impl PhotoPosition {
+    pub fn new(
+        photo_time: Option<String>,
+        time: Option<chrono::DateTime<chrono::Utc>>,
+        point: Option<GeoPoint>,
+        nearest_fix: Option<chrono::Duration>,
+        err: Option<String>,
+    ) -> PhotoPosition {
+        PhotoPosition {
+            photo_time: photo_time,
+            time: time,
+            geo: point.map(geofeature_from_point),
+            nearest_fix: nearest_fix.map(|d| d.num_milliseconds() as f64 / 1000.),
+            error: err,
+        }
+    }
+}
+
+/// Returned by the geotag endpoint.
+#[derive(serde::Serialize, Debug)]
+pub struct GeotagResult {
+    #[serde(rename = "type")]
+    typ: String, // always "GeoHubGeotag"
+    client: String,
+    photos: Vec<PhotoPosition>,
+}
+
+impl GeotagResult {
+    pub fn new(client: String, photos: Vec<PhotoPosition>) -> GeotagResult {
+        GeotagResult {
+            typ: "GeoHubGeotag".into(),
+            client: client,
+            photos: photos,
+        }
+    }
+}
+
+/// A client and the secret of one of its sessions.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct ClientSession {
+    pub client: String,
+    pub secret: Option<String>,
+}
+
+/// Body of an encounter detection request.
+#[derive(serde::Deserialize, Debug)]
+pub struct EncounterRequest {
+    pub clients: Vec<ClientSession>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    /// Maximum distance in meters.
+    pub distance: f64,
+    /// Sampling interval in seconds.
+    pub step: Option<i64>,
+}
+
+/// An interval during which two clients were close to each other.
+#[derive(serde::Serialize, Debug)]
+pub struct EncounterInterval {
+    clients: (String, String),
+    from: chrono::DateTime<chrono::Utc>,
+    to: chrono::DateTime<chrono::Utc>,
+    /// Smallest distance in meters.
+    min_distance: f64,
+}
+
+/// Returned by the encounters endpoint.
+#[derive(serde::Serialize, Debug)]
+pub struct Encounters {
+    #[serde(rename = "type")]
+    typ: String, // always "GeoHubEncounters"
+    encounters: Vec<EncounterInterval>,
+}
+
+impl Encounters {
+    pub fn new() -> Encounters {
+        Encounters {
+            typ: "GeoHubEncounters".into(),
+            encounters: vec![],
+        }
+    }
+    pub fn push(
+        &mut self,
+        a: String,
+        b: String,
+        from: chrono::DateTime<chrono::Utc>,
+        to: chrono::DateTime<chrono::Utc>,
+        min_distance: f64,
+    ) {
+        self.encounters.push(EncounterInterval {
+            clients: (a, b),
+            from: from,
+            to: to,
+            min_distance: min_distance,
+        });
+    }
+}
+
+/// Body of a replay request.
+#[derive(serde::Deserialize, Debug)]
+pub struct ReplayRequest {
+    /// Client and secret whose points are replayed.
+    pub source: String,
+    pub source_secret: Option<String>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    /// Playback speed factor, default 1.
+    pub speed: Option<f64>,
+    /// Don't store the points, only send them to live subscribers.
+    #[serde(default)]
+    pub live_only: bool,
+}
+
+/// Returned when a replay has been started.
+#[derive(serde::Serialize, Debug)]
+pub struct ReplayStarted {
+    #[serde(rename = "type")]
+    typ: String, // always "GeoHubReplay"
+    client: String,
+    points: usize,
+    /// Expected end of the replay.
+    until: chrono::DateTime<chrono::Utc>,
+    live_only: bool,
+}
+
+impl ReplayStarted {
+    pub fn new(
+        client: String,
+        points: usize,
+        until: chrono::DateTime<chrono::Utc>,
+        live_only: bool,
+    ) -> ReplayStarted {
+        ReplayStarted {
+            typ: "GeoHubReplay".into(),
+            client: client,
+            points: points,
+            until: until,
+            live_only: live_only,
+        }
+    }
+}
+
+/// Returned when points have been moved to another client or session.
+#[derive(serde::Serialize, Debug)]
+pub struct PointsMoved {
+    #[serde(rename = "type")]
+    typ: String, // always "GeoHubMoved"
+    from: String,
+    to: String,
+    points: u64,
+}
+
+impl PointsMoved {
+    pub fn new(from: String, to: String, points: u64) -> PointsMoved {
+        PointsMoved {
+            typ: "GeoHubMoved".into(),
+            from: from,
+            to: to,
+            points: points,
+        }
+    }
+}
+
+/// Outcome for one point of a `logjson` request.
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct IngestResult {
+    /// Position of the point in the request.
+    pub index: usize,
+    /// ID of the stored point.
+    pub id: Option<i32>,
+    /// Whether the point was not stored because it had been stored before.
+    pub duplicate: bool,
+    /// Why the point was not stored.
+    pub error: Option<String>,
+}
+
+/// Returned by the logjson endpoint.
+#[derive(serde::Serialize, Debug)]
+pub struct IngestReport {
+    #[serde(rename = "type")]
+    typ: String, // always "GeoHubIngest"
+    atomic: bool,
+    stored: usize,
+    duplicates: usize,
+    rejected: usize,
+    results: Vec<IngestResult>,
+}
+
+impl IngestReport {
+    pub fn new(atomic: bool, results: Vec<IngestResult>) -> IngestReport {
+        IngestReport {
+            typ: "GeoHubIngest".into(),
+            atomic: atomic,
+            stored: results.iter().filter(|r| r.id.is_some()).count(),
+            duplicates: results.iter().filter(|r| r.duplicate).count(),
+            rejected: results.iter().filter(|r| r.error.is_some()).count(),
+            results: results,
         }
     }
 }
@@ -74,6 +506,10 @@ pub struct GeoProperties {
     id: Option<i32>,
     /// An arbitrary note attached by the logging client.
     note: Option<String>,
+    /// Unique key chosen by the logging client, so that points sent again aren't stored twice.
+    /// Not returned.
+    #[serde(default, skip_serializing)]
+    key: Option<String>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
@@ -91,6 +527,13 @@ pub struct GeoFeature {
     geometry: GeoGeometry,
 }
 
+impl GeoFeature {
+    /// Key of the point, if set by the logging client.
+    pub fn point_key(&self) -> Option<String> {
+        self.properties.key.clone()
+    }
+}
+
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct GeoJSON {
     #[serde(rename = "type")]
@@ -113,6 +556,64 @@ impl GeoJSON {
     }
 }
 
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct PolygonGeometry {
+    #[serde(rename = "type")]
+    typ: String, // always "Polygon"
+    coordinates: Vec<Vec<(f64, f64)>>, // [long, lat]
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct HeatmapProperties {
+    /// Cell identifier: "x,y" grid indices or geohash.
+    cell: String,
+    count: i64,
+    /// Dwell time in seconds.
+    dwell: f64,
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct HeatmapFeature {
+    #[serde(rename = "type")]
+    typ: String, // always "Feature"
+    properties: HeatmapProperties,
+    geometry: PolygonGeometry,
+}
+
+/// Returned by the retrieve/heatmap endpoint.
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct Heatmap {
+    #[serde(rename = "type")]
+    typ: String, // always "FeatureCollection"
+    features: Vec<HeatmapFeature>,
+}
+
+/// Build a heatmap from cells given as (identifier, (minlon, minlat, maxlon, maxlat), count,
+/// dwell time).
+pub fn heatmap_from_cells<I: IntoIterator<Item = (String, (f64, f64, f64, f64), i64, f64)>>(
+    cells: I,
+) -> Heatmap {
+    let features = cells
+        .into_iter()
+        .map(|(cell, (x0, y0, x1, y1), count, dwell)| HeatmapFeature {
+            typ: "Feature".into(),
+            properties: HeatmapProperties {
+                cell: cell,
+                count: count,
+                dwell: dwell,
+            },
+            geometry: PolygonGeometry {
+                typ: "Polygon".into(),
+                coordinates: vec![vec![(x0, y0), (x1, y0), (x1, y1), (x0, y1), (x0, y0)]],
+            },
+        })
+        .collect();
+    Heatmap {
+        typ: "FeatureCollection".into(),
+        features: features,
+    }
+}
+
 pub fn geojson_from_points(points: Vec<GeoPoint>) -> GeoJSON {
     let mut gj = GeoJSON::new();
     gj.features = points.into_iter().map(geofeature_from_point).collect();
@@ -142,6 +643,7 @@ pub fn geofeature_from_point(point: GeoPoint) -> GeoFeature {
             speed: point.spd,
             note: point.note,
             accuracy: point.accuracy,
+            key: None,
         },
         geometry: GeoGeometry {
             typ: "Point".into(),
@@ -164,3 +666,73 @@ pub fn geopoint_from_feature(feat: GeoFeature) -> GeoPoint {
         time: prop.time,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point() -> GeoPoint {
+        GeoPoint {
+            id: None,
+            lat: 48.1,
+            long: 11.5,
+            spd: Some(12.),
+            ele: None,
+            accuracy: None,
+            time: chrono::Utc::now(),
+            note: None,
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(point().validate().is_ok());
+        let edges = GeoPoint {
+            lat: -90.,
+            long: 180.,
+            ..point()
+        };
+        assert!(edges.validate().is_ok());
+
+        let invalid = vec![
+            GeoPoint {
+                lat: 90.5,
+                ..point()
+            },
+            GeoPoint {
+                lat: std::f64::NAN,
+                ..point()
+            },
+            GeoPoint {
+                long: -180.5,
+                ..point()
+            },
+            GeoPoint {
+                spd: Some(std::f64::INFINITY),
+                ..point()
+            },
+            GeoPoint {
+                ele: Some(std::f64::NAN),
+                ..point()
+            },
+            GeoPoint {
+                accuracy: Some(std::f64::NEG_INFINITY),
+                ..point()
+            },
+            GeoPoint {
+                time: chrono::Utc::now() + chrono::Duration::days(2),
+                ..point()
+            },
+            GeoPoint {
+                time: chrono::DateTime::<chrono::Utc>::from_utc(
+                    chrono::NaiveDate::from_ymd(1970, 1, 1).and_hms(0, 0, 0),
+                    chrono::Utc,
+                ),
+                ..point()
+            },
+        ];
+        for p in invalid {
+            assert!(p.validate().is_err(), "{:?}", p);
+        }
+    }
+}
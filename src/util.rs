@@ -2,6 +2,7 @@ use chrono;
 
 use chrono::TimeZone;
 
+use crate::db;
 use crate::http;
 
 /// Parse timestamps flexibly. Without any zone information, UTC is assumed.
@@ -36,3 +37,131 @@ pub fn to_kph(unit: &str, num: f64) -> Result<f64, http::GeoHubResponder> {
         _ => Err(http::bad_request(format!("Unknown unit '{}'", unit))),
     }
 }
+
+fn parse_coordinates(s: &str, n: usize) -> Option<Vec<f64>> {
+    let coords = s
+        .split(',')
+        .map(|c| c.trim().parse::<f64>().ok().filter(|f| f.is_finite()))
+        .collect::<Option<Vec<f64>>>()?;
+    if coords.len() == n {
+        Some(coords)
+    } else {
+        None
+    }
+}
+
+/// Build a spatial filter from the `bbox`, `near`/`radius`, and `within` query parameters. At
+/// most one of them may be given.
+pub fn parse_spatial_filter(
+    bbox: Option<String>,
+    near: Option<String>,
+    radius: Option<f64>,
+    within: Option<String>,
+) -> Result<Option<db::SpatialFilter>, http::GeoHubResponder> {
+    match (bbox, near, within) {
+        (None, None, None) => Ok(None),
+        (Some(bbox), None, None) => match parse_coordinates(&bbox, 4).as_deref() {
+            Some(&[minlon, minlat, maxlon, maxlat]) if minlon <= maxlon && minlat <= maxlat => Ok(
+                Some(db::SpatialFilter::BBox(minlon, minlat, maxlon, maxlat)),
+            ),
+            _ => Err(http::bad_request(
+                "bbox must be minlon,minlat,maxlon,maxlat".into(),
+            )),
+        },
+        (None, Some(near), None) => match (parse_coordinates(&near, 2).as_deref(), radius) {
+            (Some(&[lat, lon]), Some(radius)) if radius >= 0. => {
+                Ok(Some(db::SpatialFilter::Near(lat, lon, radius)))
+            }
+            _ => Err(http::bad_request(
+                "near must be lat,lon and radius a distance in meters".into(),
+            )),
+        },
+        (None, None, Some(within)) => {
+            let geometry: serde_json::Value = serde_json::from_str(&within)
+                .map_err(|e| http::bad_request(format!("within is not valid JSON: {}", e)))?;
+            match geometry.get("type").and_then(|t| t.as_str()) {
+                Some("Polygon") | Some("MultiPolygon") => {
+                    Ok(Some(db::SpatialFilter::Within(within)))
+                }
+                _ => Err(http::bad_request(
+                    "within must be a GeoJSON Polygon or MultiPolygon geometry".into(),
+                )),
+            }
+        }
+        _ => Err(http::bad_request(
+            "Only one of bbox, near, within may be given".into(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spatial(
+        bbox: Option<&str>,
+        near: Option<&str>,
+        radius: Option<f64>,
+        within: Option<&str>,
+    ) -> Result<Option<db::SpatialFilter>, http::GeoHubResponder> {
+        parse_spatial_filter(
+            bbox.map(String::from),
+            near.map(String::from),
+            radius,
+            within.map(String::from),
+        )
+    }
+
+    #[test]
+    fn test_parse_spatial_filter_none() {
+        assert!(matches!(spatial(None, None, None, None), Ok(None)));
+        // A radius alone doesn't select anything.
+        assert!(matches!(spatial(None, None, Some(10.), None), Ok(None)));
+    }
+
+    #[test]
+    fn test_parse_spatial_filter_bbox() {
+        match spatial(Some("13.3, 52.4,13.5,52.6"), None, None, None) {
+            Ok(Some(db::SpatialFilter::BBox(minlon, minlat, maxlon, maxlat))) => {
+                assert_eq!((minlon, minlat, maxlon, maxlat), (13.3, 52.4, 13.5, 52.6))
+            }
+            _ => panic!("expected a bounding box"),
+        }
+        // Wrong order, number of coordinates, or non-finite values.
+        assert!(spatial(Some("13.5,52.4,13.3,52.6"), None, None, None).is_err());
+        assert!(spatial(Some("13.3,52.4,13.5"), None, None, None).is_err());
+        assert!(spatial(Some("13.3,52.4,13.5,inf"), None, None, None).is_err());
+        assert!(spatial(Some("a,b,c,d"), None, None, None).is_err());
+    }
+
+    #[test]
+    fn test_parse_spatial_filter_near() {
+        match spatial(None, Some("52.5,13.4"), Some(100.), None) {
+            Ok(Some(db::SpatialFilter::Near(lat, lon, radius))) => {
+                assert_eq!((lat, lon, radius), (52.5, 13.4, 100.))
+            }
+            _ => panic!("expected a circle"),
+        }
+        assert!(spatial(None, Some("52.5,13.4"), None, None).is_err());
+        assert!(spatial(None, Some("52.5,13.4"), Some(-1.), None).is_err());
+        assert!(spatial(None, Some("52.5"), Some(100.), None).is_err());
+    }
+
+    #[test]
+    fn test_parse_spatial_filter_within() {
+        let polygon = r#"{"type":"Polygon","coordinates":[[[0,0],[1,0],[1,1],[0,0]]]}"#;
+        match spatial(None, None, None, Some(polygon)) {
+            Ok(Some(db::SpatialFilter::Within(geojson))) => assert_eq!(geojson, polygon),
+            _ => panic!("expected a polygon"),
+        }
+        let point = r#"{"type":"Point","coordinates":[0,0]}"#;
+        assert!(spatial(None, None, None, Some(point)).is_err());
+        assert!(spatial(None, None, None, Some("not json")).is_err());
+    }
+
+    #[test]
+    fn test_parse_spatial_filter_exclusive() {
+        assert!(spatial(Some("0,0,1,1"), Some("0,0"), Some(1.), None).is_err());
+        assert!(spatial(Some("0,0,1,1"), None, None, Some("{}")).is_err());
+    }
+}